@@ -32,9 +32,28 @@ class MarkdownResult:
     clean_markdown: str = ""
     markdown_references: str = ""
     markdown_plain: str = ""
+    toc_markdown: str = ""
+    comments_markdown: str = ""
     links: List[Dict[str, Any]] = None
     images: List[Dict[str, Any]] = None
     urls: List[str] = None
+    media: List[Dict[str, Any]] = None
+    contacts: List[Dict[str, str]] = None
+    headings: List[Dict[str, Any]] = None
+    chunks: List[Dict[str, Any]] = None
+    stats: Dict[str, Any] = None
+    diagnostics: Dict[str, Any] = None
+    truncated: bool = False
+    title: str = ""
+    language: str = ""
+    metadata: Dict[str, str] = None
+    open_graph: Dict[str, Any] = None
+    json_ld: List[str] = None
+    html_comments: List[str] = None
+    canonical_url: str = ""
+    feeds: List[Dict[str, str]] = None
+    next_page: str = ""
+    prev_page: str = ""
     
     def __post_init__(self):
         if self.links is None:
@@ -43,6 +62,28 @@ class MarkdownResult:
             self.images = []
         if self.urls is None:
             self.urls = []
+        if self.media is None:
+            self.media = []
+        if self.contacts is None:
+            self.contacts = []
+        if self.headings is None:
+            self.headings = []
+        if self.chunks is None:
+            self.chunks = []
+        if self.stats is None:
+            self.stats = {}
+        if self.diagnostics is None:
+            self.diagnostics = {}
+        if self.metadata is None:
+            self.metadata = {}
+        if self.open_graph is None:
+            self.open_graph = {}
+        if self.json_ld is None:
+            self.json_ld = []
+        if self.html_comments is None:
+            self.html_comments = []
+        if self.feeds is None:
+            self.feeds = []
     
     def __str__(self):
         return self.clean_markdown or self.raw_markdown
@@ -448,7 +489,7 @@ class MarkdownGenerator:
         # Fast path: use Rust native extension if available
         if _HAS_RUST:
             try:
-                d = _rust_generate(html, base_url, dedupe_tables)
+                d = _rust_generate(html, base_url, dedupe_tables=dedupe_tables)
                 return MarkdownResult(**d)
             except Exception as e:
                 logger.warning(f"Rust grub_md failed, falling back to Python: {e}")
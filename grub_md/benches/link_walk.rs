@@ -0,0 +1,80 @@
+// Link extraction time on a large article page, against the full
+// conversion it replaces when only the outbound URLs are wanted. Run with
+//
+//     cargo bench --no-default-features --bench link_walk
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use grub_md::{run_link_pipeline, run_pipeline, Options};
+use scraper::Html;
+
+/// A news-style page: a header menu and footer of site links around an
+/// article of `paragraphs` paragraphs, each with inline links, emphasis and
+/// an occasional list.
+fn article_page(paragraphs: usize) -> String {
+    let mut html = String::from("<html><head><title>Article</title></head><body>");
+    html.push_str("<header><nav><ul>");
+    for i in 0..200 {
+        html.push_str(&format!(
+            "<li><a href=\"/section/{}\">Section {}</a></li>",
+            i, i
+        ));
+    }
+    html.push_str("</ul></nav></header><article><h1>Article</h1>");
+    for p in 0..paragraphs {
+        html.push_str(&format!(
+            "<p>Paragraph {} cites <a href=\"https://example.org/paper/{}\">a paper</a> and \
+             <a href=\"/topic/{}\" title=\"Topic\">a <em>related</em> topic</a>, with some \
+             <strong>bold claims</strong> and enough words to read as content.</p>",
+            p,
+            p,
+            p % 50
+        ));
+        if p % 10 == 0 {
+            html.push_str("<ul><li><a href=\"/more\">More</a></li><li>Plain item</li></ul>");
+        }
+    }
+    html.push_str("</article><footer>");
+    for i in 0..100 {
+        html.push_str(&format!("<a href=\"/footer/{}\">Footer link {}</a> ", i, i));
+    }
+    html.push_str("</footer></body></html>");
+    html
+}
+
+/// Median of `runs` timings of `f`.
+fn median(runs: usize, mut f: impl FnMut()) -> Duration {
+    let mut times: Vec<Duration> = (0..runs)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times[runs / 2]
+}
+
+fn main() {
+    let html = article_page(5_000);
+    let opts = Options::default();
+    let parse = median(15, || {
+        black_box(Html::parse_document(&html));
+    });
+    let links = median(15, || {
+        black_box(run_link_pipeline(
+            &html,
+            "https://example.com/",
+            &opts,
+            false,
+        ));
+    });
+    let full = median(15, || {
+        black_box(run_pipeline(&html, "https://example.com/", &opts));
+    });
+    println!("article page: {} KB of HTML", html.len() / 1024);
+    println!("  parse only          {:>8.1?}", parse);
+    println!("  run_link_pipeline   {:>8.1?}", links);
+    println!("  run_pipeline        {:>8.1?}", full);
+}
@@ -0,0 +1,71 @@
+// Conversion time on a large page whose navigation dwarfs its article, the
+// case where building the skip set dominates. Run with
+//
+//     cargo bench --no-default-features --bench skip_set
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use grub_md::{run_pipeline, run_text_pipeline, Options};
+use scraper::Html;
+
+/// A mega-menu of `sections` nested link lists, `depth` levels deep, in a
+/// sidebar that also holds a share widget and a newsletter overlay, beside
+/// a modest article.
+fn deep_nav_page(sections: usize, depth: usize) -> String {
+    let mut html = String::from("<html><head><title>Deep nav</title></head><body>");
+    html.push_str("<div class=\"sidebar\"><nav><ul>");
+    for s in 0..sections {
+        for d in 0..depth {
+            html.push_str(&format!(
+                "<li class=\"menu-item\"><a href=\"/s{}/d{}\">Section {} level {}</a><ul>",
+                s, d, s, d
+            ));
+        }
+        html.push_str(&"</ul></li>".repeat(depth));
+    }
+    html.push_str("</ul></nav>");
+    html.push_str("<div class=\"share\"><a href=\"/share\">Share</a></div>");
+    html.push_str("<div class=\"newsletter-modal\"><p>Subscribe</p></div></div>");
+    html.push_str("<article><h1>Deep nav</h1>");
+    for p in 0..200 {
+        html.push_str(&format!(
+            "<p>Paragraph {} of the article, with <a href=\"/ref/{}\">a link</a>, \
+             some commas, and enough words to score as content.</p>",
+            p, p
+        ));
+    }
+    html.push_str("</article></body></html>");
+    html
+}
+
+/// Median of `runs` timings of `f`.
+fn median(runs: usize, mut f: impl FnMut()) -> Duration {
+    let mut times: Vec<Duration> = (0..runs)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times[runs / 2]
+}
+
+fn main() {
+    let html = deep_nav_page(2_000, 12);
+    let opts = Options::default();
+    let parse = median(9, || {
+        black_box(Html::parse_document(&html));
+    });
+    let text = median(9, || {
+        black_box(run_text_pipeline(&html, "", &opts));
+    });
+    let full = median(9, || {
+        black_box(run_pipeline(&html, "", &opts));
+    });
+    println!("deep nav page: {} KB of HTML", html.len() / 1024);
+    println!("  parse only          {:>8.1?}", parse);
+    println!("  run_text_pipeline   {:>8.1?}", text);
+    println!("  run_pipeline        {:>8.1?}", full);
+}
@@ -0,0 +1,82 @@
+// Plain-text extraction time on a large, link-heavy article page, against
+// the full conversion. Run with
+//
+//     cargo bench --no-default-features --bench text_walk
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use grub_md::{run_pipeline, run_text_pipeline, Options};
+use scraper::Html;
+
+/// An encyclopedia-style page: a sidebar of site links beside an article of
+/// `sections` sections, each with linked and emphasized text, a figure and
+/// a list of references.
+fn article_page(sections: usize) -> String {
+    let mut html = String::from("<html><head><title>Article</title></head><body>");
+    html.push_str("<div class=\"sidebar\"><ul>");
+    for i in 0..300 {
+        html.push_str(&format!(
+            "<li><a href=\"/wiki/Portal_{}\">Portal {}</a></li>",
+            i, i
+        ));
+    }
+    html.push_str("</ul></div><main><h1>Article</h1>");
+    for s in 0..sections {
+        html.push_str(&format!("<h2>Section {}</h2>", s));
+        for p in 0..5 {
+            html.push_str(&format!(
+                "<p>The <a href=\"/wiki/Term_{}\">term {}</a> is <em>often</em> linked to \
+                 <a href=\"/wiki/Other_{}\" title=\"Other\">another <b>entry</b></a>, as \
+                 <a href=\"https://example.org/cite/{}\">a source</a> notes.</p>",
+                p, p, s, s
+            ));
+        }
+        html.push_str(&format!(
+            "<figure><a href=\"/file/{}.jpg\"><img src=\"/thumb/{}.jpg\" alt=\"Figure {}\">\
+             </a><figcaption>Figure {}</figcaption></figure>",
+            s, s, s, s
+        ));
+        html.push_str("<ol>");
+        for r in 0..4 {
+            html.push_str(&format!(
+                "<li><a href=\"https://example.org/ref/{}/{}\">Reference {}</a>, p. {}</li>",
+                s, r, r, r
+            ));
+        }
+        html.push_str("</ol>");
+    }
+    html.push_str("</main></body></html>");
+    html
+}
+
+/// Median of `runs` timings of `f`.
+fn median(runs: usize, mut f: impl FnMut()) -> Duration {
+    let mut times: Vec<Duration> = (0..runs)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times[runs / 2]
+}
+
+fn main() {
+    let html = article_page(600);
+    let opts = Options::default();
+    let parse = median(15, || {
+        black_box(Html::parse_document(&html));
+    });
+    let text = median(15, || {
+        black_box(run_text_pipeline(&html, "https://example.com/", &opts));
+    });
+    let full = median(15, || {
+        black_box(run_pipeline(&html, "https://example.com/", &opts));
+    });
+    println!("article page: {} KB of HTML", html.len() / 1024);
+    println!("  parse only          {:>8.1?}", parse);
+    println!("  run_text_pipeline   {:>8.1?}", text);
+    println!("  run_pipeline        {:>8.1?}", full);
+}
@@ -0,0 +1,137 @@
+// ---------------------------------------------------------------------------
+// grub-md: convert one HTML file from the command line
+// ---------------------------------------------------------------------------
+//
+// For debugging extractions without Python. Prints one markdown variant, or
+// with `--json` the whole result under the same keys as the Python dict.
+// Exits 1 when the chosen output is empty and 2 on usage or I/O errors.
+
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+
+use grub_md::{run_pipeline, Options};
+
+const USAGE: &str = "\
+usage: grub-md [--base-url URL] [--variant raw|clean|citations|plain] [--json] [FILE]
+
+Reads HTML from FILE, or from stdin when FILE is missing or `-`.
+
+  --base-url URL   resolve relative links and images against URL
+  --variant NAME   markdown to print (default: clean)
+                     raw        the markdown as walked
+                     clean      raw, tidied for reading
+                     citations  numbered citations plus the reference list
+                     plain      text with link syntax removed
+  --json           print the full result as JSON instead
+  -h, --help       print this help and exit";
+
+#[derive(Clone, Copy)]
+enum Variant {
+    Raw,
+    Clean,
+    Citations,
+    Plain,
+}
+
+struct Args {
+    base_url: String,
+    variant: Variant,
+    json: bool,
+    help: bool,
+    path: Option<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        base_url: String::new(),
+        variant: Variant::Clean,
+        json: false,
+        help: false,
+        path: None,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--base-url" => {
+                parsed.base_url = args.next().ok_or("--base-url needs a value")?;
+            }
+            "--variant" => {
+                let name = args.next().ok_or("--variant needs a value")?;
+                parsed.variant = match name.as_str() {
+                    "raw" => Variant::Raw,
+                    "clean" => Variant::Clean,
+                    "citations" => Variant::Citations,
+                    "plain" => Variant::Plain,
+                    _ => return Err(format!("unknown variant {:?}", name)),
+                };
+            }
+            "--json" => parsed.json = true,
+            "-h" | "--help" => {
+                parsed.help = true;
+                return Ok(parsed);
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if parsed.path.is_some() => return Err("only one input file is read".into()),
+            _ => parsed.path = Some(arg),
+        }
+    }
+    Ok(parsed)
+}
+
+fn read_input(path: Option<&str>) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    match path {
+        None | Some("-") => {
+            io::stdin().read_to_end(&mut bytes)?;
+        }
+        Some(path) => bytes = std::fs::read(path)?,
+    }
+    // Pages in legacy encodings still convert, with the odd replacement char
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("grub-md: {}", message);
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    if args.help {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+    let html = match read_input(args.path.as_deref()) {
+        Ok(html) => html,
+        Err(err) => {
+            let source = args.path.as_deref().unwrap_or("stdin");
+            eprintln!("grub-md: reading {}: {}", source, err);
+            return ExitCode::from(2);
+        }
+    };
+
+    let result = run_pipeline(&html, &args.base_url, &Options::default());
+    let markdown = match args.variant {
+        Variant::Raw => &result.raw_markdown,
+        Variant::Clean => &result.clean_markdown,
+        Variant::Citations => &result.markdown_references,
+        Variant::Plain => &result.markdown_plain,
+    };
+    let empty = markdown.trim().is_empty();
+    let output = if args.json {
+        serde_json::to_string(&result).expect("results always serialize")
+    } else {
+        markdown.clone()
+    };
+
+    let mut stdout = io::stdout().lock();
+    if writeln!(stdout, "{}", output).is_err() {
+        return ExitCode::from(2);
+    }
+    if empty {
+        eprintln!("grub-md: no content extracted");
+        return ExitCode::from(1);
+    }
+    ExitCode::SUCCESS
+}
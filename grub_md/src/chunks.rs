@@ -0,0 +1,225 @@
+// ---------------------------------------------------------------------------
+// Section chunking for retrieval
+// ---------------------------------------------------------------------------
+//
+// Splits the readable markdown at headings, merges sections too small to
+// stand alone, and splits oversized ones between paragraphs. Fenced code
+// blocks are never split.
+
+use crate::{fence_marker, strip_links};
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Chunk {
+    pub text: String,
+    /// Texts of the enclosing headings, outermost first.
+    pub heading_path: Vec<String>,
+    /// Character (not byte) offsets of `text` in the chunked markdown.
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A byte range of the markdown with the headings it sits under.
+struct Section {
+    start: usize,
+    end: usize,
+    path: Vec<String>,
+}
+
+pub(crate) fn chunk_markdown(md: &str, max_chars: usize, min_chars: usize) -> Vec<Chunk> {
+    let lines = scan_lines(md);
+    let sections = merge_small(split_sections(md, &lines), md, max_chars, min_chars);
+
+    let mut chunks = Vec::new();
+    // Chunks come in document order, so each offset is counted on from the
+    // one before rather than from the start of the markdown
+    let mut cursor = (0, 0);
+    for section in sections {
+        for (start, end) in split_oversized(md, &lines, &section, max_chars) {
+            let piece = &md[start..end];
+            let text = piece.trim();
+            if text.is_empty() {
+                continue;
+            }
+            let start = start + (piece.len() - piece.trim_start().len());
+            let end = start + text.len();
+            chunks.push(Chunk {
+                text: text.to_string(),
+                heading_path: section.path.clone(),
+                start: char_offset(md, &mut cursor, start),
+                end: char_offset(md, &mut cursor, end),
+            });
+        }
+    }
+    chunks
+}
+
+/// One line of the markdown: its byte offset, the line itself and whether it
+/// belongs to a fenced code block.
+struct Line<'a> {
+    start: usize,
+    text: &'a str,
+    in_fence: bool,
+}
+
+fn scan_lines(md: &str) -> Vec<Line<'_>> {
+    let mut lines = Vec::new();
+    let mut open: Option<(char, usize)> = None;
+    let mut start = 0;
+    for text in md.split_inclusive('\n') {
+        let marker = fence_marker(text);
+        let in_fence = match open {
+            None => {
+                open = marker;
+                marker.is_some()
+            }
+            Some((ch, len)) => {
+                let closes = marker.is_some_and(|(c, n)| c == ch && n >= len)
+                    && text.trim().chars().all(|c| c == ch);
+                if closes {
+                    open = None;
+                }
+                true
+            }
+        };
+        lines.push(Line {
+            start,
+            text,
+            in_fence,
+        });
+        start += text.len();
+    }
+    lines
+}
+
+/// Level and text of a heading starting at line `i`: ATX (`## Title`,
+/// optionally closed) or setext (a line underlined with `=` or `-`).
+fn heading_at(lines: &[Line], i: usize) -> Option<(u8, String)> {
+    let line = &lines[i];
+    if line.in_fence {
+        return None;
+    }
+    let text = line.text.trim_end();
+    let hashes = text.chars().take_while(|c| *c == '#').count();
+    if (1..=6).contains(&hashes) && text[hashes..].starts_with(' ') {
+        let title = text[hashes..].trim().trim_end_matches('#').trim_end();
+        return Some((hashes as u8, plain_heading(title)));
+    }
+    let next = lines.get(i + 1).filter(|l| !l.in_fence)?;
+    let underline = next.text.trim_end();
+    if text.trim().is_empty() || underline.len() < 3 {
+        return None;
+    }
+    let level = if underline.chars().all(|c| c == '=') {
+        1
+    } else if underline.chars().all(|c| c == '-') {
+        2
+    } else {
+        return None;
+    };
+    Some((level, plain_heading(text.trim())))
+}
+
+fn plain_heading(md: &str) -> String {
+    strip_links(md)
+        .replace(['*', '`'], "")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn split_sections(md: &str, lines: &[Line]) -> Vec<Section> {
+    let mut sections = Vec::new();
+    let mut path: Vec<(u8, String)> = Vec::new();
+    let mut start = 0;
+    for i in 0..lines.len() {
+        let Some((level, title)) = heading_at(lines, i) else {
+            continue;
+        };
+        let at = lines[i].start;
+        if at > start {
+            sections.push(Section {
+                start,
+                end: at,
+                path: path.iter().map(|(_, t)| t.clone()).collect(),
+            });
+        }
+        while path.last().is_some_and(|(l, _)| *l >= level) {
+            path.pop();
+        }
+        path.push((level, title));
+        start = at;
+    }
+    sections.push(Section {
+        start,
+        end: md.len(),
+        path: path.into_iter().map(|(_, t)| t).collect(),
+    });
+    sections
+}
+
+/// Fold each section shorter than `min_chars` into the one after it, as long
+/// as the result still fits in `max_chars`.
+fn merge_small(
+    sections: Vec<Section>,
+    md: &str,
+    max_chars: usize,
+    min_chars: usize,
+) -> Vec<Section> {
+    let mut merged: Vec<Section> = Vec::new();
+    for section in sections {
+        if let Some(last) = merged.last_mut() {
+            let last_len = md[last.start..last.end].chars().count();
+            let combined = md[last.start..section.end].chars().count();
+            if last_len < min_chars && combined <= max_chars {
+                last.end = section.end;
+                continue;
+            }
+        }
+        merged.push(section);
+    }
+    merged
+}
+
+/// Byte ranges covering `section`, each at most `max_chars` unless a single
+/// paragraph or code block is longer. Breaks fall only on blank lines
+/// outside code fences.
+fn split_oversized(
+    md: &str,
+    lines: &[Line],
+    section: &Section,
+    max_chars: usize,
+) -> Vec<(usize, usize)> {
+    if md[section.start..section.end].chars().count() <= max_chars {
+        return vec![(section.start, section.end)];
+    }
+    let first = lines.partition_point(|l| l.start <= section.start);
+    let last = lines.partition_point(|l| l.start < section.end);
+    let breaks = lines[first..last]
+        .iter()
+        .filter(|l| !l.in_fence && l.text.trim().is_empty())
+        .map(|l| l.start + l.text.len());
+
+    let mut ranges = Vec::new();
+    let mut start = section.start;
+    let mut last_break = None;
+    for at in breaks.chain(std::iter::once(section.end)) {
+        if md[start..at].chars().count() > max_chars {
+            if let Some(prev) = last_break.filter(|&p| p > start) {
+                ranges.push((start, prev));
+                start = prev;
+            }
+        }
+        last_break = Some(at);
+    }
+    ranges.push((start, section.end));
+    ranges
+}
+
+/// Character offset of `byte`, counted on from `cursor`: a byte offset at
+/// or before it, with its character offset. The cursor moves to `byte`.
+fn char_offset(md: &str, cursor: &mut (usize, usize), byte: usize) -> usize {
+    cursor.1 += md[cursor.0..byte].chars().count();
+    cursor.0 = byte;
+    cursor.1
+}
@@ -1,10 +1,17 @@
-use ego_tree::NodeId;
+//! HTML → markdown conversion with link citations, metadata and content
+//! detection. `convert` and `run_pipeline` are the Rust entry points;
+//! the `python` feature (on by default) builds the `grub_md` Python module.
+
+use ego_tree::{iter::Edge, NodeId};
 use once_cell::sync::Lazy;
-use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyList};
+use rayon::prelude::*;
 use regex::Regex;
 use scraper::{ElementRef, Html, Node, Selector};
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::str::FromStr;
 use url::Url;
 
 // ---------------------------------------------------------------------------
@@ -17,25 +24,67 @@ macro_rules! sel {
     };
 }
 
+/// Enter a `tracing` debug span until the end of the enclosing block; nothing
+/// without the `tracing` feature.
+macro_rules! span {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!($($arg)+).entered();
+    };
+}
+
+/// A `tracing` debug event; nothing (not even its fields are evaluated)
+/// without the `tracing` feature.
+macro_rules! event {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)+);
+    };
+}
+
+// Declared after the macros so the submodules can use them.
+mod chunks;
+mod mathml;
+mod metadata;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use chunks::Chunk;
+pub use metadata::{FeedLink, LinkRelations, PageMetadata};
+
+/// Candidates for the main content root, most specific first.
+const MAIN_SELECTORS: &[&str] = &[
+    "main",
+    "[role=\"main\"]",
+    "article",
+    ".content",
+    ".main-content",
+    ".post-content",
+    ".entry-content",
+    "#content",
+    "#main",
+    "body",
+];
+
 static SEL_MAIN: Lazy<Vec<Selector>> = Lazy::new(|| {
-    vec![
-        sel!("main"),
-        sel!("article"),
-        sel!(".content"),
-        sel!(".main-content"),
-        sel!(".post-content"),
-        sel!(".entry-content"),
-        sel!("#content"),
-        sel!("#main"),
-        sel!("body"),
-    ]
+    MAIN_SELECTORS
+        .iter()
+        .map(|s| Selector::parse(s).unwrap_or_else(|_| panic!("bad selector: {}", s)))
+        .collect()
 });
 
 static SEL_TABLE: Lazy<Selector> = Lazy::new(|| sel!("table"));
 static SEL_TR: Lazy<Selector> = Lazy::new(|| sel!("tr"));
-static SEL_THEAD_TBODY_TFOOT: Lazy<Selector> = Lazy::new(|| sel!("thead, tbody, tfoot"));
 static SEL_TD_TH: Lazy<Selector> = Lazy::new(|| sel!("td, th"));
+static SEL_CAPTION: Lazy<Selector> = Lazy::new(|| sel!("caption"));
+static SEL_BASE: Lazy<Selector> = Lazy::new(|| sel!("base[href]"));
 static SEL_LI: Lazy<Selector> = Lazy::new(|| sel!("li"));
+static SEL_SVG: Lazy<Selector> = Lazy::new(|| sel!("svg"));
+static SEL_IMG: Lazy<Selector> = Lazy::new(|| sel!("img"));
+static SEL_P: Lazy<Selector> = Lazy::new(|| sel!("p"));
+static SEL_FIGURE_MEDIA: Lazy<Selector> = Lazy::new(|| sel!("img, video, audio"));
 
 /// Tags whose entire subtree we skip.
 const SKIP_TAGS: &[&str] = &[
@@ -62,6 +111,34 @@ const NAV_CLASSES: &[&str] = &[
     "modal",
 ];
 
+/// Class / id words marking cookie banners, newsletter modals and paywalls.
+const OVERLAY_WORDS: &[&str] = &[
+    "cookie",
+    "cookies",
+    "consent",
+    "gdpr",
+    "newsletter",
+    "subscribe",
+    "paywall",
+    "overlay",
+];
+
+/// Class / id values marking a comment thread for `extract_comments`.
+const COMMENT_SECTION_NAMES: &[&str] = &["comments", "comment-list", "disqus_thread"];
+
+/// An overlay candidate is only removed while its text is under this share
+/// of the page's text, so an article that happens to be about cookies stays.
+const OVERLAY_MAX_TEXT_SHARE: f64 = 0.3;
+
+/// ARIA landmark roles for page chrome.
+const NAV_ROLES: &[&str] = &[
+    "navigation",
+    "banner",
+    "contentinfo",
+    "complementary",
+    "search",
+];
+
 /// Hidden / a11y-only CSS classes to remove.
 const HIDDEN_CLASSES: &[&str] = &[
     "sr-only",
@@ -75,38 +152,837 @@ const HIDDEN_CLASSES: &[&str] = &[
     "a11y_only",
 ];
 
+/// Attributes lazy-loading scripts use to hold the real image URL while
+/// `src` is a placeholder. Names ending in `srcset` are parsed as srcsets.
+const LAZY_IMAGE_ATTRS: &[&str] = &[
+    "data-src",
+    "data-lazy-src",
+    "data-original",
+    "data-srcset",
+    "data-lazy-srcset",
+];
+
+/// File names (without extension) lazy-loading scripts put in `src` until
+/// the real image loads.
+const PLACEHOLDER_IMAGE_NAMES: &[&str] = &[
+    "spacer",
+    "blank",
+    "pixel",
+    "transparent",
+    "clear",
+    "1x1",
+    "placeholder",
+    "lazy",
+    "loading",
+];
+
+/// Tracking parameters removed by URL normalization. A trailing `*` matches
+/// any parameter with that prefix.
+const TRACKING_PARAMS: &[&str] = &[
+    "utm_*", "gclid", "dclid", "gbraid", "wbraid", "fbclid", "msclkid", "yclid", "mc_cid",
+    "mc_eid", "igshid", "_ga", "_hsenc", "_hsmi",
+];
+
+/// Built-in `site_rules` as (host, required substring).
+const DEFAULT_SITE_RULES: &[(&str, &str)] = &[("news.ycombinator.com", "item?id=")];
+
+/// Lines removed by `strip_boilerplate`, compared case-insensitively once
+/// markdown syntax and trailing punctuation are stripped.
+const BOILERPLATE_PHRASES: &[&str] = &[
+    "advertisement",
+    "sponsored content",
+    "share this article",
+    "share this story",
+    "share this",
+    "read more",
+    "continue reading",
+    "sign up for our newsletter",
+    "subscribe to our newsletter",
+    "accept all cookies",
+    "skip to content",
+    "skip to main content",
+    "back to top",
+];
+
+/// Whole-line patterns removed by `strip_boilerplate`, matched against the
+/// same normalized text as `BOILERPLATE_PHRASES`.
+const BOILERPLATE_PATTERNS: &[&str] = &[
+    r"^share (on|to|via) (facebook|twitter|x|linkedin|reddit|email|whatsapp)$",
+    r"^advertisement \d+$",
+];
+
 /// Block-level tags that signal a table cell is used for layout.
 const BLOCK_LIKE_TAGS: &[&str] = &[
     "div", "p", "ul", "ol", "table", "article", "section", "header", "footer", "nav", "aside",
 ];
 
+/// Block elements (nested tables included) that collapse to space-separated
+/// inline content when rendered inside a table cell.
+const FLATTENED_BLOCK_TAGS: &[&str] = &[
+    "div", "p", "ul", "ol", "li", "dl", "dt", "dd", "h1", "h2", "h3", "h4", "h5", "h6",
+    "blockquote", "pre", "hr", "figure", "article", "section", "header", "footer", "aside",
+    "table", "caption", "thead", "tbody", "tfoot", "tr", "td", "th",
+];
+
 // ---------------------------------------------------------------------------
 // Link / image regex for the citation pass (matches Python's LINK_PATTERN)
 // ---------------------------------------------------------------------------
 
-static RE_LINK: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r#"!?\[([^\]]+)\]\(([^)]+?)(?:\s+"([^"]*)")?\)"#).unwrap());
+// Text and title may contain backslash escapes; the URL is either `<...>` (when it has
+// parentheses or spaces) or bare. Read captures through `link_parts`.
+// Link text may itself be an image (`[![alt](thumb)](full)`).
+static RE_LINK: Lazy<Regex> = Lazy::new(|| {
+    let image = r#"!\[(?:\\.|[^\]\\])*\]\((?:<[^>\n]*>|[^)\s]+)(?:\s+"(?:\\.|[^"\\])*")?\)"#;
+    Regex::new(&format!(
+        r#"!?\[((?:{}|\\.|[^\]\\])+)\]\((?:<([^>\n]*)>|([^)\s]+))(?:\s+"((?:\\.|[^"\\])*)")?\)"#,
+        image
+    ))
+    .unwrap()
+});
+
+/// `url(...)` in a CSS value, with double, single or no quotes.
+static RE_CSS_URL: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"url\(\s*(?:"([^"]*)"|'([^']*)'|([^)'"\s]+))\s*\)"#).unwrap());
+
+/// What a markdown reader decodes as a character reference: `&amp;`,
+/// `&#8217;`, `&#x27;`.
+static RE_CHAR_REF: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"&(?:#[0-9]{1,7}|#[xX][0-9a-fA-F]{1,6}|[A-Za-z][A-Za-z0-9]{0,31});").unwrap()
+});
+
+static RE_FOOTNOTE_LABEL: Lazy<Regex> = Lazy::new(|| Regex::new(r"\[\^([^\]\s]+)\]").unwrap());
 
-static RE_IMAGE: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r#"!\[([^\]]*)\]\(([^)]+?)(?:\s+"([^"]*)")?\)"#).unwrap());
+static RE_IMAGE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"!\[((?:\\.|[^\]\\])*)\]\((?:<([^>\n]*)>|([^)\s]+))(?:\s+"((?:\\.|[^"\\])*)")?\)"#)
+        .unwrap()
+});
 
 // ---------------------------------------------------------------------------
 // Collected link / image structs
 // ---------------------------------------------------------------------------
 
 #[derive(Debug, Clone)]
-struct LinkInfo {
-    text: String,
-    url: String,
-    title: String,
-    citation_number: usize,
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct LinkInfo {
+    pub text: String,
+    pub url: String,
+    pub title: String,
+    pub citation_number: usize,
+    /// Whether the link stays on the page's site (see `is_same_site`).
+    pub is_internal: bool,
+    /// Host of the resolved URL; empty without a base URL or when the URL
+    /// doesn't parse.
+    pub host: String,
+    /// Lowercased `rel` tokens, e.g. `["nofollow", "sponsored"]`.
+    pub rel: Vec<String>,
+    pub target: String,
+    pub aria_label: String,
+}
+
+/// A link recorded during the walk, with the label markdown the citation
+/// pass keeps in place of the link.
+#[derive(Debug, Clone)]
+struct WalkedLink {
+    info: LinkInfo,
+    label: String,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ImageInfo {
+    pub alt: String,
+    pub url: String,
+    pub title: String,
+    /// Text of the enclosing `<figcaption>`, if any.
+    pub caption: String,
+    /// Every resolved `srcset` candidate considered for this image.
+    pub candidates: Vec<String>,
+    /// Pixel size from the `width`/`height` attributes or inline style.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Declared no larger than 32px (favicons, UI icons, spacers).
+    pub is_probably_icon: bool,
+    /// Lowercase file type from the URL extension or data URI, e.g. `"png"`.
+    pub format: String,
+}
+
+/// DOM-only details about an emitted image that the markdown can't carry.
+#[derive(Debug, Clone, Default)]
+struct ImageMeta {
+    caption: String,
+    candidates: Vec<String>,
+    width: Option<u32>,
+    height: Option<u32>,
 }
 
+/// One `srcset` entry: a URL plus its width (`800w`) or density (`2x`)
+/// descriptor.
 #[derive(Debug, Clone)]
-struct ImageInfo {
-    alt: String,
+struct SrcsetCandidate {
     url: String,
-    title: String,
+    width: Option<u32>,
+    density: f32,
+}
+
+/// A heading as emitted, for outlines and section splitting.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HeadingInfo {
+    pub level: u8,
+    /// Plain text, without markdown or permalink symbols.
+    pub text: String,
+    /// Position among the emitted headings.
+    pub index: usize,
+    /// The heading's `id`, or a slug of its text made unique in the document.
+    pub anchor: String,
+}
+
+/// A data table as emitted, with the plain text of each cell laid out on
+/// the same grid as the markdown table.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TableInfo {
+    /// Position among the emitted data tables.
+    pub index: usize,
+    pub caption: Option<String>,
+    /// The first row, when it holds `<th>` cells.
+    pub header: Option<Vec<String>>,
+    pub rows: Vec<Vec<String>>,
+}
+
+/// Size and make-up of the converted content.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ContentStats {
+    /// Words outside code blocks, not counting URLs.
+    pub word_count: usize,
+    /// Characters of `markdown_plain`.
+    pub char_count: usize,
+    pub link_count: usize,
+    pub image_count: usize,
+    pub table_count: usize,
+    pub code_block_count: usize,
+    /// Emitted headings per level, `h1` first.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "serde_impls::headings_by_level")
+    )]
+    pub headings_by_level: [usize; 6],
+    /// At 200 words a minute, rounded up.
+    pub reading_time_minutes: usize,
+    /// Markdown length over HTML length, as the fallback check judges it.
+    pub content_ratio: f64,
+}
+
+/// How the content was found, for debugging bad extractions.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+    /// Whether `base_url` parsed as an absolute URL. When it didn't, links
+    /// resolve against the document's `<base href>` only, if it has one.
+    pub base_url_usable: bool,
+    /// The `MAIN_SELECTORS` entry that matched the content root,
+    /// `"readability"` when the scorer picked it, or empty when nothing did.
+    pub main_selector: String,
+    /// The content root's tag and classes, e.g. `div.story.body`.
+    pub main_node: String,
+    /// Why the full-document re-walk ran, if it did.
+    pub fallback_reason: Option<FallbackReason>,
+    /// Which walk produced the final markdown.
+    pub stage: WalkStage,
+    /// The site rule behind a `SiteSpecific` fallback.
+    pub site_rule: Option<SiteRule>,
+    /// Elements left out as navigation / page chrome.
+    pub clutter_nodes: usize,
+    /// Elements left out as hidden or non-content (`script`, `[hidden]`, …).
+    pub hidden_nodes: usize,
+    /// Elements left out as cookie / newsletter / paywall overlays.
+    pub overlay_nodes: usize,
+}
+
+/// A `mailto:` or `tel:` link.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ContactInfo {
+    /// `"email"` or `"phone"`.
+    pub kind: String,
+    /// The address or number, without any `?subject=`-style query.
+    pub value: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MediaInfo {
+    /// `"video"` or `"audio"`.
+    pub kind: String,
+    pub url: String,
+    pub poster: String,
+}
+
+// ---------------------------------------------------------------------------
+// Conversion options
+// ---------------------------------------------------------------------------
+
+/// Implement `FromStr` for a string-valued option enum so it can be passed
+/// from Python by name, and `Serialize` (with `serde`) under the same name.
+macro_rules! option_enum {
+    ($ty:ident, $name:literal, { $($s:literal => $v:ident),+ $(,)? }) => {
+        impl FromStr for $ty {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    $($s => Ok($ty::$v),)+
+                    _ => Err(format!(
+                        "invalid {} {:?} (expected one of: {})",
+                        $name,
+                        s,
+                        [$($s),+].join(", ")
+                    )),
+                }
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl serde::Serialize for $ty {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(match self {
+                    $($ty::$v => $s,)+
+                })
+            }
+        }
+    };
+}
+
+/// How `<dl>` definition lists are rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefinitionListStyle {
+    /// `**Term**` on its own line with the definition indented beneath it.
+    Bold,
+    /// Extended-markdown `Term` / `: definition` syntax.
+    Extended,
+}
+
+option_enum!(DefinitionListStyle, "definition_list_style", {
+    "bold" => Bold,
+    "extended" => Extended,
+});
+
+/// How `<sup>` / `<sub>` are rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptStyle {
+    /// Extended-markdown `^2^` / `~2~`.
+    Extended,
+    /// Plain-text `^(2)` / `_(2)` for renderers without the extension.
+    Parens,
+}
+
+option_enum!(ScriptStyle, "script_style", {
+    "extended" => Extended,
+    "parens" => Parens,
+});
+
+/// How `<mark>` highlights are rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkStyle {
+    /// `==text==` highlight extension.
+    Highlight,
+    /// `**text**` for renderers without the extension.
+    Bold,
+}
+
+option_enum!(MarkStyle, "mark_style", {
+    "highlight" => Highlight,
+    "bold" => Bold,
+});
+
+/// When `<abbr title>` expansions are appended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbbrExpansion {
+    /// Only the first occurrence of each abbreviation.
+    First,
+    /// Every occurrence.
+    Always,
+    /// Never; emit the abbreviation as-is.
+    Never,
+}
+
+option_enum!(AbbrExpansion, "abbr_expansion", {
+    "first" => First,
+    "always" => Always,
+    "never" => Never,
+});
+
+/// How `<table>` elements are classified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableMode {
+    /// Decide per table from its structure.
+    Auto,
+    /// Always emit a markdown table.
+    AlwaysData,
+    /// Always flatten to prose.
+    AlwaysLayout,
+}
+
+option_enum!(TableMode, "table_mode", {
+    "auto" => Auto,
+    "always_data" => AlwaysData,
+    "always_layout" => AlwaysLayout,
+});
+
+/// How headings are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeadingStyle {
+    /// `## Title`
+    Atx,
+    /// `## Title ##`
+    AtxClosed,
+    /// `Title` underlined with `=` / `-` for levels 1–2, ATX below that.
+    Setext,
+}
+
+option_enum!(HeadingStyle, "heading_style", {
+    "atx" => Atx,
+    "atx_closed" => AtxClosed,
+    "setext" => Setext,
+});
+
+/// How citations are marked in `markdown_with_citations`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CitationStyle {
+    /// `text[3]` plus a `## References` block.
+    Bracket,
+    /// `text[^3]` plus `[^3]: url` footnote definitions.
+    Footnote,
+}
+
+option_enum!(CitationStyle, "citation_style", {
+    "bracket" => Bracket,
+    "footnote" => Footnote,
+});
+
+/// Whether links to subdomains of the page's site count as internal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubdomainPolicy {
+    /// `blog.example.com` is internal to `example.com`.
+    Internal,
+    /// Only the page's own host (with or without `www.`) is internal.
+    External,
+}
+
+option_enum!(SubdomainPolicy, "subdomain_policy", {
+    "internal" => Internal,
+    "external" => External,
+});
+
+/// What to do with `data:` URI images longer than `data_uri_limit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataUriImages {
+    /// Leave the image out.
+    Drop,
+    /// Keep the first `data_uri_limit` bytes followed by `…`.
+    Truncate,
+    /// Emit the URI verbatim.
+    Keep,
+}
+
+option_enum!(DataUriImages, "data_uri_images", {
+    "drop" => Drop,
+    "truncate" => Truncate,
+    "keep" => Keep,
+});
+
+/// A caller-supplied CSS selector, kept with its source text for error
+/// messages and diagnostics.
+#[derive(Debug, Clone)]
+pub struct NamedSelector {
+    pub source: String,
+    pub selector: Selector,
+}
+
+/// Parse the selectors given for the option `name`, naming the first one
+/// that fails.
+pub fn parse_selectors(name: &str, sources: &[String]) -> Result<Vec<NamedSelector>, String> {
+    sources
+        .iter()
+        .map(|source| {
+            Selector::parse(source)
+                .map(|selector| NamedSelector {
+                    source: source.clone(),
+                    selector,
+                })
+                .map_err(|e| format!("invalid {} selector {:?}: {}", name, source, e))
+        })
+        .collect()
+}
+
+/// Marker characters for lists and emphasis, for renderers and linters that
+/// prefer a particular flavour.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct MarkdownStyle {
+    pub bullet_marker: &'static str,
+    pub emphasis_marker: &'static str,
+    pub strong_marker: &'static str,
+    pub ordered_marker_suffix: &'static str,
+}
+
+impl Default for MarkdownStyle {
+    fn default() -> Self {
+        MarkdownStyle {
+            bullet_marker: "-",
+            emphasis_marker: "*",
+            strong_marker: "**",
+            ordered_marker_suffix: ".",
+        }
+    }
+}
+
+/// Match `value` against the allowed markers for the option `name`.
+fn parse_marker(name: &str, value: &str, allowed: &[&'static str]) -> Result<&'static str, String> {
+    allowed
+        .iter()
+        .copied()
+        .find(|m| *m == value)
+        .ok_or_else(|| {
+            format!(
+                "invalid {} {:?} (expected one of: {})",
+                name,
+                value,
+                allowed.join(", ")
+            )
+        })
+}
+
+/// Settings that control how the pipeline renders markdown.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Options {
+    pub dedupe_tables: bool,
+    pub table_mode: TableMode,
+    /// Emit the `| --- |` separator after the first row even when the table
+    /// has no `<th>` header, so GFM renderers still see a table.
+    pub table_header_separator: bool,
+    /// Repeat a spanned cell's content across the columns and rows it covers
+    /// instead of padding them with empty cells.
+    pub repeat_spanned_cells: bool,
+    pub definition_list_style: DefinitionListStyle,
+    pub script_style: ScriptStyle,
+    /// Lazy-load attributes checked (in order) for the real image URL when
+    /// `src` is missing or a placeholder.
+    pub lazy_image_attrs: Vec<String>,
+    /// Emit labelled inline `<svg>` as `![label]()` instead of dropping it.
+    pub svg_placeholders: bool,
+    /// Drop 1×1 images, which are almost always tracking pixels.
+    pub skip_tracking_pixels: bool,
+    pub data_uri_images: DataUriImages,
+    /// Longest `data:` image URI emitted as is, in bytes.
+    pub data_uri_limit: usize,
+    /// Add `background-image` / `data-bg` images to `images` (not to the
+    /// markdown).
+    pub background_images: bool,
+    /// Append a `<q cite>` URL as a link after the quotation.
+    pub include_cite_urls: bool,
+    /// Append a `<dfn title>` expansion in parentheses.
+    pub expand_dfn_titles: bool,
+    /// Drop a trailing " | Site Name" segment from the extracted title.
+    pub strip_title_suffix: bool,
+    /// Give every link to the same URL one shared citation number. URLs are
+    /// compared normalized: no fragment or trailing slash, any host case.
+    pub dedupe_citations: bool,
+    pub citation_style: CitationStyle,
+    /// Keep `#fragment` links, resolved against the page URL, instead of
+    /// reducing them to their text.
+    pub resolve_fragment_links: bool,
+    /// Cite `mailto:` / `tel:` links and list them in `urls`; when off they
+    /// only appear in `contacts`.
+    pub cite_contact_links: bool,
+    /// Treat hrefs like `www.example.com/page` as absolute `https://` URLs
+    /// rather than paths relative to the page.
+    pub fix_schemeless: bool,
+    /// Normalize link URLs in `links` / `urls` (see `normalize_url`) and
+    /// drop duplicates from `urls`.
+    pub normalize_urls: bool,
+    /// Also write the normalized URLs into the markdown links.
+    pub normalize_markdown_urls: bool,
+    /// Query parameters removed by normalization.
+    pub strip_query_params: Vec<String>,
+    pub subdomain_policy: SubdomainPolicy,
+    /// Nesting levels included in `toc_markdown`, counted from the
+    /// shallowest heading.
+    pub toc_max_depth: usize,
+    /// Longest chunk in `chunks`, in characters, unless one paragraph or
+    /// code block is longer on its own.
+    pub max_chunk_chars: usize,
+    /// Sections shorter than this are merged into the next chunk.
+    pub min_chunk_chars: usize,
+    /// Tried in order before the built-in `MAIN_SELECTORS` when looking for
+    /// the content root.
+    pub content_selectors: Vec<NamedSelector>,
+    /// Fall back to `MAIN_SELECTORS` when no `content_selectors` match.
+    pub default_content_selectors: bool,
+    /// Elements that are never dropped as clutter or hidden, along with
+    /// their descendants, even inside a dropped ancestor.
+    pub keep_selectors: Vec<NamedSelector>,
+    /// Drop cookie-consent, newsletter and paywall overlays, in both the
+    /// content-root and the full-document walks.
+    pub remove_overlays: bool,
+    /// Score blocks by paragraph text and link density to find the content
+    /// root when no selector short of `body` matches.
+    pub use_readability_fallback: bool,
+    /// Pages under this many bytes of HTML only fall back when the content
+    /// root is empty.
+    pub min_html_len: usize,
+    /// Fall back when the content root yields fewer markdown bytes.
+    pub min_md_len: usize,
+    /// Fall back when markdown bytes per HTML byte are below this.
+    pub min_ratio: f64,
+    /// Sites whose content root must contain a marker, or the whole document
+    /// is walked instead.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serde_impls::site_rules"))]
+    pub site_rules: Vec<SiteRule>,
+    /// Drop repeats of a block (text between blank lines) already emitted,
+    /// such as a menu rendered for both desktop and mobile.
+    pub dedupe_blocks: bool,
+    /// Remove lines that consist solely of a boilerplate phrase ("Read
+    /// more", "Advertisement", …).
+    pub strip_boilerplate: bool,
+    /// Render comment threads into `comments_markdown` instead of dropping
+    /// them; they stay out of the main markdown either way.
+    pub extract_comments: bool,
+    /// Gather the text of `<!-- -->` comments into `html_comments`.
+    pub collect_comments: bool,
+    /// Refuse a `base_url` that isn't an absolute URL (see `try_run_pipeline`)
+    /// instead of converting without one.
+    pub strict: bool,
+    /// Elements nested deeper than this inside the walked root are left out,
+    /// so pathological nesting can't exhaust the stack.
+    pub max_depth: usize,
+    /// Stop walking after this many elements.
+    pub max_nodes: usize,
+    /// Stop emitting once the markdown reaches this many bytes.
+    pub max_output_bytes: usize,
+    /// Lowercase phrases and whole-line patterns for `strip_boilerplate`.
+    pub boilerplate_phrases: Vec<String>,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serde_impls::patterns"))]
+    pub boilerplate_patterns: Vec<Regex>,
+    /// Collect `application/ld+json` script contents into `json_ld`.
+    pub extract_json_ld: bool,
+    pub abbr_expansion: AbbrExpansion,
+    pub mark_style: MarkStyle,
+    pub heading_style: HeadingStyle,
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub style: MarkdownStyle,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            dedupe_tables: true,
+            table_mode: TableMode::Auto,
+            table_header_separator: true,
+            repeat_spanned_cells: false,
+            definition_list_style: DefinitionListStyle::Bold,
+            script_style: ScriptStyle::Extended,
+            lazy_image_attrs: LAZY_IMAGE_ATTRS.iter().map(|a| a.to_string()).collect(),
+            svg_placeholders: false,
+            skip_tracking_pixels: true,
+            data_uri_images: DataUriImages::Drop,
+            data_uri_limit: 256,
+            background_images: false,
+            include_cite_urls: false,
+            expand_dfn_titles: false,
+            strip_title_suffix: false,
+            extract_json_ld: false,
+            dedupe_citations: true,
+            citation_style: CitationStyle::Bracket,
+            resolve_fragment_links: false,
+            cite_contact_links: true,
+            fix_schemeless: false,
+            normalize_urls: false,
+            normalize_markdown_urls: false,
+            strip_query_params: TRACKING_PARAMS.iter().map(|p| p.to_string()).collect(),
+            subdomain_policy: SubdomainPolicy::Internal,
+            toc_max_depth: 3,
+            max_chunk_chars: 2000,
+            min_chunk_chars: 200,
+            content_selectors: Vec::new(),
+            default_content_selectors: true,
+            keep_selectors: Vec::new(),
+            remove_overlays: true,
+            use_readability_fallback: true,
+            min_html_len: 5000,
+            min_md_len: 400,
+            min_ratio: 0.01,
+            dedupe_blocks: false,
+            strip_boilerplate: false,
+            extract_comments: false,
+            collect_comments: false,
+            strict: false,
+            max_depth: 256,
+            max_nodes: 1_000_000,
+            max_output_bytes: 16 << 20,
+            boilerplate_phrases: BOILERPLATE_PHRASES.iter().map(|p| p.to_string()).collect(),
+            boilerplate_patterns: BOILERPLATE_PATTERNS
+                .iter()
+                .map(|p| Regex::new(p).expect("bad boilerplate pattern"))
+                .collect(),
+            site_rules: DEFAULT_SITE_RULES
+                .iter()
+                .map(|(host, required)| SiteRule {
+                    host: host.to_string(),
+                    required: required.to_string(),
+                })
+                .collect(),
+            abbr_expansion: AbbrExpansion::First,
+            mark_style: MarkStyle::Highlight,
+            heading_style: HeadingStyle::Atx,
+            style: MarkdownStyle::default(),
+        }
+    }
+}
+
+impl Options {
+    /// Options from a JSON object of settings, named and valued as the Python
+    /// keyword arguments: `{"citation_style": "footnote", "min_md_len": 200}`.
+    ///
+    /// ```
+    /// let opts = grub_md::Options::from_json(r#"{"bullet_marker": "*"}"#).unwrap();
+    /// assert_eq!(opts.style.bullet_marker, "*");
+    /// assert!(grub_md::Options::from_json(r##"{"bullet_marker": "#"}"##).is_err());
+    /// ```
+    pub fn from_json(json: &str) -> Result<Options, String> {
+        let value: serde_json::Value =
+            serde_json::from_str(json).map_err(|e| format!("invalid options JSON: {}", e))?;
+        let serde_json::Value::Object(settings) = value else {
+            return Err("options JSON must be an object".to_string());
+        };
+        let mut opts = Options::default();
+        for (name, value) in &settings {
+            opts.set(name, value)?;
+        }
+        Ok(opts)
+    }
+
+    /// Change the setting `name` (a Python keyword argument name) to `value`.
+    /// `null` leaves it as it is. As in Python, boilerplate phrases and
+    /// patterns add to the built-in ones while other lists replace theirs.
+    pub fn set(&mut self, name: &str, value: &serde_json::Value) -> Result<(), String> {
+        if value.is_null() {
+            return Ok(());
+        }
+        let invalid =
+            |expected: &str| format!("invalid {} {} (expected {})", name, value, expected);
+        let flag = || value.as_bool().ok_or_else(|| invalid("a boolean"));
+        let count = || {
+            value
+                .as_u64()
+                .map(|n| n as usize)
+                .ok_or_else(|| invalid("a non-negative integer"))
+        };
+        let text = || value.as_str().ok_or_else(|| invalid("a string"));
+        let texts = || -> Result<Vec<String>, String> {
+            value
+                .as_array()
+                .and_then(|items| {
+                    items
+                        .iter()
+                        .map(|item| item.as_str().map(String::from))
+                        .collect()
+                })
+                .ok_or_else(|| invalid("a list of strings"))
+        };
+        match name {
+            "dedupe_tables" => self.dedupe_tables = flag()?,
+            "definition_list_style" => self.definition_list_style = text()?.parse()?,
+            "script_style" => self.script_style = text()?.parse()?,
+            "lazy_image_attrs" => self.lazy_image_attrs = texts()?,
+            "svg_placeholders" => self.svg_placeholders = flag()?,
+            "skip_tracking_pixels" => self.skip_tracking_pixels = flag()?,
+            "data_uri_images" => self.data_uri_images = text()?.parse()?,
+            "data_uri_limit" => self.data_uri_limit = count()?,
+            "background_images" => self.background_images = flag()?,
+            "include_cite_urls" => self.include_cite_urls = flag()?,
+            "expand_dfn_titles" => self.expand_dfn_titles = flag()?,
+            "abbr_expansion" => self.abbr_expansion = text()?.parse()?,
+            "mark_style" => self.mark_style = text()?.parse()?,
+            "strip_title_suffix" => self.strip_title_suffix = flag()?,
+            "extract_json_ld" => self.extract_json_ld = flag()?,
+            "table_header_separator" => self.table_header_separator = flag()?,
+            "repeat_spanned_cells" => self.repeat_spanned_cells = flag()?,
+            "table_mode" => self.table_mode = text()?.parse()?,
+            "bullet_marker" => {
+                self.style.bullet_marker = parse_marker(name, text()?, &["-", "*", "+"])?
+            }
+            "emphasis_marker" => {
+                self.style.emphasis_marker = parse_marker(name, text()?, &["*", "_"])?
+            }
+            "strong_marker" => {
+                self.style.strong_marker = parse_marker(name, text()?, &["**", "__"])?
+            }
+            "ordered_marker_suffix" => {
+                self.style.ordered_marker_suffix = parse_marker(name, text()?, &[".", ")"])?
+            }
+            "heading_style" => self.heading_style = text()?.parse()?,
+            "dedupe_citations" => self.dedupe_citations = flag()?,
+            "citation_style" => self.citation_style = text()?.parse()?,
+            "resolve_fragment_links" => self.resolve_fragment_links = flag()?,
+            "cite_contact_links" => self.cite_contact_links = flag()?,
+            "fix_schemeless" => self.fix_schemeless = flag()?,
+            "normalize_urls" => self.normalize_urls = flag()?,
+            "normalize_markdown_urls" => self.normalize_markdown_urls = flag()?,
+            "strip_query_params" => self.strip_query_params = texts()?,
+            "subdomain_policy" => self.subdomain_policy = text()?.parse()?,
+            "toc_max_depth" => self.toc_max_depth = count()?,
+            "max_chunk_chars" => self.max_chunk_chars = count()?,
+            "min_chunk_chars" => self.min_chunk_chars = count()?,
+            "content_selectors" => self.content_selectors = parse_selectors(name, &texts()?)?,
+            "default_content_selectors" => self.default_content_selectors = flag()?,
+            "keep_selectors" => self.keep_selectors = parse_selectors(name, &texts()?)?,
+            "remove_overlays" => self.remove_overlays = flag()?,
+            "use_readability_fallback" => self.use_readability_fallback = flag()?,
+            "min_html_len" => self.min_html_len = count()?,
+            "min_md_len" => self.min_md_len = count()?,
+            "min_ratio" => self.min_ratio = value.as_f64().ok_or_else(|| invalid("a number"))?,
+            "site_rules" => {
+                self.site_rules = value
+                    .as_array()
+                    .and_then(|rules| {
+                        rules
+                            .iter()
+                            .map(|rule| match rule.as_array()?.as_slice() {
+                                [host, required] => Some(SiteRule {
+                                    host: host.as_str()?.to_string(),
+                                    required: required.as_str()?.to_string(),
+                                }),
+                                _ => None,
+                            })
+                            .collect()
+                    })
+                    .ok_or_else(|| invalid("a list of [host, required] pairs"))?
+            }
+            "dedupe_blocks" => self.dedupe_blocks = flag()?,
+            "strip_boilerplate" => self.strip_boilerplate = flag()?,
+            "boilerplate_phrases" => self
+                .boilerplate_phrases
+                .extend(texts()?.iter().map(|p| p.trim().to_lowercase())),
+            "boilerplate_patterns" => {
+                for pattern in texts()? {
+                    let re = Regex::new(&format!("(?i){}", pattern))
+                        .map_err(|e| format!("invalid boilerplate pattern {:?}: {}", pattern, e))?;
+                    self.boilerplate_patterns.push(re);
+                }
+            }
+            "extract_comments" => self.extract_comments = flag()?,
+            "collect_comments" => self.collect_comments = flag()?,
+            "strict" => self.strict = flag()?,
+            "max_depth" => self.max_depth = count()?,
+            "max_nodes" => self.max_nodes = count()?,
+            "max_output_bytes" => self.max_output_bytes = count()?,
+            _ => return Err(format!("unknown option {:?}", name)),
+        }
+        Ok(())
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -125,6 +1001,16 @@ fn should_skip(el: &ElementRef) -> bool {
     if el.value().attr("hidden").is_some() {
         return true;
     }
+    if el
+        .value()
+        .attr("aria-hidden")
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("true"))
+    {
+        return true;
+    }
+    if el.value().attr("style").is_some_and(is_hidden_style) {
+        return true;
+    }
 
     // Hidden / a11y-only classes
     if let Some(cls_attr) = el.value().attr("class") {
@@ -138,13 +1024,49 @@ fn should_skip(el: &ElementRef) -> bool {
     false
 }
 
+/// Whether an inline `style` declares `display: none` or
+/// `visibility: hidden`.
+fn is_hidden_style(style: &str) -> bool {
+    style.split(';').any(|decl| {
+        let Some((prop, value)) = decl.split_once(':') else {
+            return false;
+        };
+        let value = value.trim().to_ascii_lowercase();
+        let value = value.trim_end_matches("!important").trim_end();
+        match prop.trim().to_ascii_lowercase().as_str() {
+            "display" => value == "none",
+            "visibility" => value == "hidden",
+            _ => false,
+        }
+    })
+}
+
 /// Check if an element is nav/clutter that should be removed during content
 /// filtering (before main-content detection).
 fn is_nav_clutter(el: &ElementRef) -> bool {
     let tag = el.value().name();
-    if NAV_TAGS.contains(&tag) {
+    let roles: Vec<String> = el
+        .value()
+        .attr("role")
+        .unwrap_or("")
+        .split_whitespace()
+        .map(str::to_ascii_lowercase)
+        .collect();
+    if roles.iter().any(|r| r == "main") {
+        return false;
+    }
+    if roles.iter().any(|r| NAV_ROLES.contains(&r.as_str())) {
         return true;
     }
+    if NAV_TAGS.contains(&tag) {
+        // A footer inside a blockquote is the quote's attribution, not page chrome
+        let is_attribution = tag == "footer"
+            && el
+                .ancestors()
+                .filter_map(ElementRef::wrap)
+                .any(|a| a.value().name() == "blockquote");
+        return !is_attribution;
+    }
     if let Some(cls_attr) = el.value().attr("class") {
         for cls in cls_attr.split_whitespace() {
             if NAV_CLASSES.contains(&cls) {
@@ -152,7 +1074,32 @@ fn is_nav_clutter(el: &ElementRef) -> bool {
             }
         }
     }
-    false
+    if let Some(id) = el.value().attr("id") {
+        if NAV_CLASSES.contains(&id.trim()) {
+            return true;
+        }
+    }
+    tag == "a" && is_heading_permalink(el)
+}
+
+/// The `¶` / `#` self-link that documentation generators append to headings.
+fn is_heading_permalink(a: &ElementRef) -> bool {
+    let href = a.value().attr("href").unwrap_or("");
+    if !href.starts_with('#') {
+        return false;
+    }
+    let in_heading = a
+        .ancestors()
+        .filter_map(ElementRef::wrap)
+        .any(|p| matches!(p.value().name(), "h1" | "h2" | "h3" | "h4" | "h5" | "h6"));
+    if !in_heading {
+        return false;
+    }
+    let text = get_text_content(a);
+    matches!(text.as_str(), "" | "¶" | "#" | "§" | "🔗")
+        || a.value()
+            .classes()
+            .any(|c| matches!(c, "headerlink" | "anchor" | "anchor-link" | "permalink"))
 }
 
 // ---------------------------------------------------------------------------
@@ -163,52 +1110,396 @@ fn resolve_url(href: &str, base: &Option<Url>) -> String {
     if href.is_empty() {
         return String::new();
     }
+    // `Url::join` would re-encode these; keep them exactly as written
+    if contact_kind(href).is_some() {
+        return href.to_string();
+    }
     if let Some(base_url) = base {
         match base_url.join(href) {
             Ok(u) => u.to_string(),
             Err(_) => href.to_string(),
         }
+    } else if href.starts_with("//") {
+        // Protocol-relative with nothing to inherit from
+        format!("https:{}", href)
     } else {
         href.to_string()
     }
 }
 
+/// `www.example.com/page` → `https://www.example.com/page`; anything else is
+/// returned unchanged.
+fn add_missing_scheme(href: &str) -> Cow<'_, str> {
+    let is_bare_www = href
+        .get(..4)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("www."));
+    if is_bare_www {
+        Cow::Owned(format!("https://{}", href))
+    } else {
+        Cow::Borrowed(href)
+    }
+}
+
+/// Hrefs that lead nowhere a crawler can fetch: `javascript:` always, and
+/// same-page `#fragment` links unless `resolve_fragment_links` is set.
+fn is_unfollowable_href(href: &str, opts: &Options) -> bool {
+    let href = href.trim();
+    href.get(..11)
+        .is_some_and(|scheme| scheme.eq_ignore_ascii_case("javascript:"))
+        || (href.starts_with('#') && !opts.resolve_fragment_links)
+}
+
+/// `"email"` for `mailto:` hrefs, `"phone"` for `tel:`.
+fn contact_kind(href: &str) -> Option<&'static str> {
+    let href = href.trim();
+    let has_scheme = |scheme: &str| {
+        href.get(..scheme.len())
+            .is_some_and(|s| s.eq_ignore_ascii_case(scheme))
+    };
+    if has_scheme("mailto:") {
+        Some("email")
+    } else if has_scheme("tel:") {
+        Some("phone")
+    } else {
+        None
+    }
+}
+
+/// Canonical form of an absolute URL for deduplication: lowercase scheme and
+/// host, no default port, no fragment, no trailing slash on the path, and
+/// without the query parameters in `strip_params`. Relative or unparseable
+/// URLs are returned unchanged.
+fn normalize_url(url: &str, strip_params: &[String]) -> String {
+    // Parsing lowercases the scheme and host and drops default ports
+    let Ok(mut parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+    if parsed.cannot_be_a_base() {
+        return url.to_string();
+    }
+    parsed.set_fragment(None);
+    if let Some(query) = parsed.query() {
+        // Filter the raw pairs so the kept ones keep their encoding
+        let kept: Vec<&str> = query
+            .split('&')
+            .filter(|pair| {
+                let key = pair.split('=').next().unwrap_or("");
+                !key.is_empty() && !strip_params.iter().any(|p| param_matches(p, key))
+            })
+            .collect();
+        let query = kept.join("&");
+        parsed.set_query(if query.is_empty() { None } else { Some(&query) });
+    }
+    let path = parsed.path();
+    if path.len() > 1 && path.ends_with('/') {
+        let trimmed = path.trim_end_matches('/').to_string();
+        parsed.set_path(&trimmed);
+    }
+    parsed.to_string()
+}
+
+/// Whether a query key matches a `strip_query_params` entry (`utm_*` style
+/// entries match by prefix).
+fn param_matches(pattern: &str, key: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => key == pattern,
+    }
+}
+
+/// Second-level labels under which registrations happen one level deeper,
+/// as in `example.co.uk`.
+const SECOND_LEVEL_LABELS: &[&str] = &["co", "com", "net", "org", "gov", "edu", "ac"];
+
+/// The registrable part of a host: the last two labels, or three under a
+/// country-code second level like `co.uk`. An approximation of the public
+/// suffix list that covers the common cases. IP addresses (IPv6 in URL
+/// brackets or not) are returned whole.
+fn registrable_domain(host: &str) -> &str {
+    let bare = host.trim_start_matches('[').trim_end_matches(']');
+    if bare.parse::<std::net::IpAddr>().is_ok() {
+        return host;
+    }
+    let labels: Vec<&str> = host.rsplit('.').collect();
+    let keep = match labels.as_slice() {
+        [tld, second, _, ..] if tld.len() == 2 && SECOND_LEVEL_LABELS.contains(second) => 3,
+        _ => 2,
+    };
+    if labels.len() <= keep {
+        return host;
+    }
+    let cut: usize = labels[..keep].iter().map(|l| l.len() + 1).sum();
+    &host[host.len() - cut + 1..]
+}
+
+/// Whether `host` belongs to the same site as `base_host`. A leading `www.`
+/// is ignored on both; subdomains match according to `policy`.
+fn is_same_site(host: &str, base_host: &str, policy: SubdomainPolicy) -> bool {
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    let base_host = base_host.strip_prefix("www.").unwrap_or(base_host);
+    if host.eq_ignore_ascii_case(base_host) {
+        return true;
+    }
+    match policy {
+        SubdomainPolicy::Internal => {
+            registrable_domain(host).eq_ignore_ascii_case(registrable_domain(base_host))
+        }
+        SubdomainPolicy::External => false,
+    }
+}
+
+/// Private-use characters wrapping each link the walker emits, as
+/// `LINK_OPEN index LINK_SEP [text](url) LINK_CLOSE`. The citation pass finds
+/// links by these instead of re-parsing the markdown; every output has them
+/// stripped.
+const LINK_OPEN: char = '\u{e000}';
+const LINK_SEP: char = '\u{e001}';
+const LINK_CLOSE: char = '\u{e002}';
+
+/// A piece of marked markdown: plain text, or an emitted link.
+enum Segment<'a> {
+    Text(&'a str),
+    Link { index: usize, markup: &'a str },
+}
+
+/// Split markdown carrying link markers into text and link segments.
+/// A malformed marker is dropped whole (markers, index and all), keeping
+/// the link markup it wraps as text.
+fn link_segments(md: &str) -> Vec<Segment<'_>> {
+    let mut segments = Vec::new();
+    let mut rest = md;
+    while let Some(open) = rest.find(LINK_OPEN) {
+        segments.push(Segment::Text(&rest[..open]));
+        let after = &rest[open + LINK_OPEN.len_utf8()..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let (index, after) = after.split_at(digits);
+        let (index, after) = match after.strip_prefix(LINK_SEP) {
+            Some(after) => (index.parse().ok(), after),
+            None => (None, after),
+        };
+        let close = after.find(LINK_CLOSE);
+        match (index, close) {
+            (Some(index), Some(close)) => {
+                let markup = &after[..close];
+                segments.push(Segment::Link { index, markup });
+                rest = &after[close + LINK_CLOSE.len_utf8()..];
+            }
+            // Only this marker's close, not a later link's
+            (None, Some(close)) if !after[..close].contains(LINK_OPEN) => {
+                segments.push(Segment::Text(&after[..close]));
+                rest = &after[close + LINK_CLOSE.len_utf8()..];
+            }
+            _ => rest = after,
+        }
+    }
+    segments.push(Segment::Text(rest));
+    segments
+}
+
+/// The markdown with the link markers removed.
+fn strip_link_markers(md: &str) -> String {
+    if !md.contains(LINK_OPEN) {
+        return md.to_string();
+    }
+    link_segments(md)
+        .into_iter()
+        .map(|segment| match segment {
+            Segment::Text(text) => text,
+            Segment::Link { markup, .. } => markup,
+        })
+        .collect()
+}
+
 // ---------------------------------------------------------------------------
 // Core tree-walk: emit markdown into a buffer
 // ---------------------------------------------------------------------------
 
 struct Walker<'a> {
     base_url: Option<Url>,
-    dedupe_tables: bool,
+    opts: &'a Options,
     layout_table_depth: usize,
-    /// Set of node IDs that belong to nav/clutter elements (pre-computed).
-    skip_ids: &'a HashSet<NodeId>,
+    /// Nav/clutter subtrees (pre-computed).
+    skip_ids: &'a SkipSet,
+    /// Node IDs under a `keep_selectors` match, exempt from skipping.
+    keep_ids: &'a HashSet<NodeId>,
+    /// Whether `should_skip` applies; when off only `SKIP_TAGS` are skipped.
+    skip_hidden: bool,
+    /// Write links as their plain text, without markers or a record (the
+    /// text-only walk).
+    links_as_text: bool,
+    /// Number of enclosing `<q>` elements, to alternate quote marks.
+    quote_depth: usize,
+    /// Abbreviations already expanded in this document.
+    expanded_abbrs: HashSet<String>,
+    /// Caption of the `<figure>` currently being walked.
+    figure_caption: Option<String>,
+    /// Captions and srcset candidates keyed by resolved image URL.
+    image_meta: HashMap<String, ImageMeta>,
+    /// `<video>` / `<audio>` sources, in document order.
+    media: Vec<MediaInfo>,
+    /// Images that only appear as attributes (e.g. video posters), so the
+    /// markdown scan in `extract_images` can't find them.
+    extra_images: Vec<ImageInfo>,
+    /// Greater than zero while rendering table cells, where block elements
+    /// are flattened to inline content.
+    inline_depth: usize,
+    /// Links as emitted, indexed by their markers, with the attributes the
+    /// markdown can't carry.
+    links: Vec<WalkedLink>,
+    /// Headings as emitted, in document order.
+    headings: Vec<HeadingInfo>,
+    /// Data tables as emitted, in document order.
+    tables: Vec<TableInfo>,
+    /// Anchors handed out so far, with how many times each base was used.
+    anchors: HashMap<String, usize>,
+    /// Data tables and code blocks emitted, for the stats.
+    table_count: usize,
+    code_block_count: usize,
+    /// Nesting of `walk` calls, checked against `max_depth`.
+    depth: usize,
+    /// Elements walked, checked against `max_nodes`.
+    nodes: usize,
+    /// Bytes of text emitted, checked against `max_output_bytes`.
+    text_bytes: usize,
+    /// Whether a limit cut the walk short.
+    truncated: bool,
 }
 
 impl<'a> Walker<'a> {
+    fn new(
+        base_url: Option<Url>,
+        opts: &'a Options,
+        skip_ids: &'a SkipSet,
+        keep_ids: &'a HashSet<NodeId>,
+    ) -> Self {
+        Walker {
+            base_url,
+            opts,
+            layout_table_depth: 0,
+            skip_ids,
+            keep_ids,
+            skip_hidden: true,
+            links_as_text: false,
+            quote_depth: 0,
+            expanded_abbrs: HashSet::new(),
+            figure_caption: None,
+            image_meta: HashMap::new(),
+            media: Vec::new(),
+            extra_images: Vec::new(),
+            inline_depth: 0,
+            links: Vec::new(),
+            headings: Vec::new(),
+            tables: Vec::new(),
+            anchors: HashMap::new(),
+            table_count: 0,
+            code_block_count: 0,
+            depth: 0,
+            nodes: 0,
+            text_bytes: 0,
+            truncated: false,
+        }
+    }
+
+    /// Walk the outermost kept elements inside a skipped one.
+    fn walk_kept(&mut self, el: ElementRef, buf: &mut String) {
+        if self.keep_ids.is_empty() {
+            return;
+        }
+        let mut stack: Vec<_> = el.children().filter_map(ElementRef::wrap).collect();
+        stack.reverse();
+        while let Some(child) = stack.pop() {
+            if self.keep_ids.contains(&child.id()) {
+                self.walk(child, buf);
+                buf.push_str("\n\n");
+            } else {
+                let start = stack.len();
+                stack.extend(child.children().filter_map(ElementRef::wrap));
+                stack[start..].reverse();
+            }
+        }
+    }
+
+    /// Whether `max_nodes` or `max_output_bytes` has been reached, marking
+    /// the walk truncated when it has.
+    fn exhausted(&mut self) -> bool {
+        let exhausted =
+            self.nodes >= self.opts.max_nodes || self.text_bytes >= self.opts.max_output_bytes;
+        self.truncated |= exhausted;
+        exhausted
+    }
+
+    /// Resolve an href or src from the document against the base URL.
+    fn resolve(&self, href: &str) -> String {
+        if self.opts.fix_schemeless {
+            resolve_url(&add_missing_scheme(href), &self.base_url)
+        } else {
+            resolve_url(href, &self.base_url)
+        }
+    }
+
+    /// Resolve a link href, normalized when the markdown should carry
+    /// normalized URLs.
+    fn link_url(&self, href: &str) -> String {
+        let resolved = self.resolve(href);
+        if self.opts.normalize_markdown_urls {
+            normalize_url(&resolved, &self.opts.strip_query_params)
+        } else {
+            resolved
+        }
+    }
+
+    /// Emit `el`, unless a limit has been reached or it sits `max_depth`
+    /// deep, in which case it and its subtree are left out.
     fn walk(&mut self, el: ElementRef, buf: &mut String) {
-        // Skip entirely?
-        if should_skip(&el) {
+        if self.exhausted() {
             return;
         }
-        if self.skip_ids.contains(&el.id()) {
+        if self.depth >= self.opts.max_depth {
+            self.truncated = true;
             return;
         }
+        self.nodes += 1;
+        self.depth += 1;
+        self.walk_element(el, buf);
+        self.depth -= 1;
+    }
 
-        let tag = el.value().name();
-
-        match tag {
+    fn walk_element(&mut self, el: ElementRef, buf: &mut String) {
+        // Skip entirely? Kept elements only give way to `SKIP_TAGS`.
+        let kept = self.keep_ids.contains(&el.id()) && !SKIP_TAGS.contains(&el.value().name());
+        let hidden = if self.skip_hidden {
+            should_skip(&el)
+        } else {
+            SKIP_TAGS.contains(&el.value().name())
+        };
+        if !kept && (hidden || self.skip_ids.is_root(&el)) {
+            self.walk_kept(el, buf);
+            return;
+        }
+
+        if self.opts.background_images {
+            self.collect_background_images(&el);
+        }
+
+        let tag = el.value().name();
+
+        if self.inline_depth > 0 && FLATTENED_BLOCK_TAGS.contains(&tag) {
+            self.walk_children(&el, buf);
+            buf.push(' ');
+            return;
+        }
+
+        match tag {
             "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
                 let level = tag.as_bytes()[1] - b'0';
-                let text = get_text_content(&el);
+                // Constrained inline walk keeps links, code and emphasis
+                self.inline_depth += 1;
+                let content = self.children_to_string(&el);
+                self.inline_depth -= 1;
+                let text = collapse_whitespace(&content);
                 if !text.is_empty() {
-                    buf.push('\n');
-                    for _ in 0..level {
-                        buf.push('#');
-                    }
-                    buf.push(' ');
-                    buf.push_str(&text);
-                    buf.push_str("\n\n");
+                    self.push_heading(level, &text, buf);
+                    self.record_heading(&el, level);
                 }
             }
             "p" => {
@@ -221,89 +1512,149 @@ impl<'a> Walker<'a> {
             "br" => {
                 buf.push('\n');
             }
+            "hr" => {
+                // Blank line before so preceding text isn't read as a setext heading
+                buf.push_str("\n\n---\n\n");
+            }
             "strong" | "b" => {
-                let content = self.children_to_string(&el);
-                if !content.is_empty() {
-                    buf.push_str("**");
-                    buf.push_str(&content);
-                    buf.push_str("**");
+                let strong = self.opts.style.strong_marker;
+                self.wrap_children(&el, strong, strong, buf);
+            }
+            "em" | "i" | "cite" => {
+                let em = self.opts.style.emphasis_marker;
+                self.wrap_children(&el, em, em, buf);
+            }
+            "dfn" => {
+                let em = self.opts.style.emphasis_marker;
+                self.wrap_children(&el, em, em, buf);
+                if self.opts.expand_dfn_titles {
+                    let title = el.value().attr("title").unwrap_or("").trim();
+                    if !title.is_empty() {
+                        buf.push_str(" (");
+                        buf.push_str(title);
+                        buf.push(')');
+                    }
                 }
             }
-            "em" | "i" => {
-                let content = self.children_to_string(&el);
-                if !content.is_empty() {
-                    buf.push('*');
-                    buf.push_str(&content);
-                    buf.push('*');
+            "del" | "s" | "strike" => {
+                self.wrap_children(&el, "~~", "~~", buf);
+            }
+            "mark" => match self.opts.mark_style {
+                MarkStyle::Highlight => self.wrap_children(&el, "==", "==", buf),
+                MarkStyle::Bold => {
+                    let strong = self.opts.style.strong_marker;
+                    self.wrap_children(&el, strong, strong, buf)
                 }
+            },
+            "q" => {
+                self.handle_quote(&el, buf);
             }
+            "abbr" => {
+                self.handle_abbr(&el, buf);
+            }
+            "sup" => match self.opts.script_style {
+                ScriptStyle::Extended => self.wrap_children(&el, "^", "^", buf),
+                ScriptStyle::Parens => self.wrap_children(&el, "^(", ")", buf),
+            },
+            "sub" => match self.opts.script_style {
+                ScriptStyle::Extended => self.wrap_children(&el, "~", "~", buf),
+                ScriptStyle::Parens => self.wrap_children(&el, "_(", ")", buf),
+            },
             "a" => {
                 self.handle_link(&el, buf);
             }
             "img" => {
                 self.handle_image(&el, buf);
             }
+            "video" | "audio" => {
+                self.handle_media(&el, buf);
+            }
+            "math" => {
+                self.handle_math(&el, buf);
+            }
+            "svg" => {
+                // Icon labels and <text> nodes would leak as stray words
+                if self.opts.svg_placeholders {
+                    if let Some(label) = svg_label(&el) {
+                        buf.push_str("![");
+                        buf.push_str(&label);
+                        buf.push_str("]()");
+                    }
+                }
+            }
             "ul" => {
                 self.handle_list(&el, false, buf);
             }
             "ol" => {
                 self.handle_list(&el, true, buf);
             }
+            "dl" => {
+                self.handle_definition_list(&el, buf);
+            }
+            "figure" => {
+                self.handle_figure(&el, buf);
+            }
             "li" => {
                 // Only reached if <li> appears outside <ul>/<ol>
                 let content = self.children_to_string(&el);
                 let trimmed = content.trim();
                 if !trimmed.is_empty() {
-                    buf.push_str("- ");
+                    buf.push_str(self.opts.style.bullet_marker);
+                    buf.push(' ');
                     buf.push_str(trimmed);
                     buf.push('\n');
                 }
             }
             "blockquote" => {
-                let content = self.children_to_string(&el);
-                for line in content.lines() {
-                    let trimmed = line.trim();
-                    if !trimmed.is_empty() {
-                        buf.push_str("> ");
-                        buf.push_str(trimmed);
-                        buf.push('\n');
-                    }
-                }
-                buf.push('\n');
+                self.handle_blockquote(&el, buf);
             }
-            "code" | "tt" => {
+            "code" | "tt" | "kbd" | "samp" | "var" => {
                 // If inside <pre>, don't add backticks (pre handles it)
                 if el
                     .parent()
                     .and_then(|p| p.value().as_element())
-                    .map_or(false, |p| p.name() == "pre")
+                    .is_some_and(|p| p.name() == "pre")
                 {
                     let text = get_text_content(&el);
                     buf.push_str(&text);
                 } else {
                     let text = get_text_content(&el);
                     if !text.is_empty() {
-                        buf.push('`');
-                        buf.push_str(&text);
-                        buf.push('`');
+                        push_code_span(buf, &text);
                     }
                 }
             }
             "pre" => {
                 let text = get_raw_text(&el);
-                let trimmed = text.trim();
+                let trimmed = trim_blank_lines(&text);
                 if !trimmed.is_empty() {
-                    buf.push_str("```\n");
+                    // The fence must be longer than any backtick run inside
+                    let fence = "`".repeat(longest_backtick_run(trimmed).max(2) + 1);
+                    buf.push_str(&fence);
+                    buf.push_str(&code_language(&el).unwrap_or_default());
+                    buf.push('\n');
                     buf.push_str(trimmed);
-                    buf.push_str("\n```\n\n");
+                    buf.push('\n');
+                    buf.push_str(&fence);
+                    buf.push_str("\n\n");
+                    self.code_block_count += 1;
                 }
             }
             "table" | "thead" | "tbody" | "tfoot" => {
                 self.handle_table(&el, buf);
             }
+            "caption" => {
+                // Emitted by handle_table ahead of data tables, dropped for layout tables
+            }
             "tr" => {
-                if self.dedupe_tables && self.layout_table_depth > 0 {
+                if self.opts.dedupe_tables && self.layout_table_depth > 0 {
+                    // Layout rows become lines of space-separated cell text
                     self.walk_children(&el, buf);
+                    let trimmed = buf.trim_end_matches(' ').len();
+                    buf.truncate(trimmed);
+                    if !buf.is_empty() && !buf.ends_with('\n') {
+                        buf.push('\n');
+                    }
                 } else {
                     let cells = direct_children_by_sel(&el, &SEL_TD_TH);
                     if !cells.is_empty() {
@@ -312,12 +1663,19 @@ impl<'a> Walker<'a> {
                             if i > 0 {
                                 buf.push_str(" | ");
                             }
-                            buf.push_str(&get_text_content(cell));
+                            buf.push_str(&self.table_cell(cell));
                         }
                         buf.push_str(" |\n");
                     }
                 }
             }
+            "td" | "th" => {
+                // Only reached for layout tables; data cells go through table_cell
+                self.walk_children(&el, buf);
+                if !buf.is_empty() && !buf.ends_with(char::is_whitespace) {
+                    buf.push(' ');
+                }
+            }
             // Container elements — just recurse
             _ => {
                 self.walk_children(&el, buf);
@@ -334,16 +1692,136 @@ impl<'a> Walker<'a> {
                     }
                 }
                 Node::Text(t) => {
-                    let s = t.text.trim();
-                    if !s.is_empty() {
-                        buf.push_str(s);
+                    if self.exhausted() {
+                        return;
+                    }
+                    // Collapse interior whitespace but keep a single space at
+                    // the boundaries, so inline siblings stay separated
+                    let text = normalize_text(&t.text);
+                    let mut collapsed = collapse_whitespace(&text);
+                    let budget = self.opts.max_output_bytes - self.text_bytes;
+                    if collapsed.len() > budget {
+                        let mut end = budget;
+                        while !collapsed.is_char_boundary(end) {
+                            end -= 1;
+                        }
+                        collapsed.truncate(end);
+                        self.truncated = true;
+                    }
+                    self.text_bytes += collapsed.len();
+                    if text.starts_with(char::is_whitespace) {
+                        push_space(buf);
+                    }
+                    buf.push_str(&escape_char_refs(&collapsed));
+                    if !collapsed.is_empty() && text.ends_with(char::is_whitespace) {
+                        buf.push(' ');
                     }
                 }
+                // Comments, including conditional comments and the bogus
+                // comments html5ever makes of CDATA sections, never render
+                Node::Comment(_) => {}
                 _ => {}
             }
         }
     }
 
+    /// Render a table cell through a constrained inline walk, keeping links,
+    /// images and inline formatting, then escape it for a markdown row.
+    fn table_cell(&mut self, cell: &ElementRef) -> String {
+        self.inline_depth += 1;
+        let content = self.children_to_string(cell);
+        self.inline_depth -= 1;
+        escape_table_cell(&content)
+    }
+
+    /// Add an emitted heading to the outline.
+    fn record_heading(&mut self, el: &ElementRef, level: u8) {
+        let text = self.heading_text(el);
+        let anchor = match el.value().attr("id").map(str::trim) {
+            Some(id) if !id.is_empty() => {
+                self.anchors.entry(id.to_string()).or_insert(0);
+                id.to_string()
+            }
+            _ => self.unique_anchor(slugify(&text)),
+        };
+        self.headings.push(HeadingInfo {
+            level,
+            text,
+            index: self.headings.len(),
+            anchor,
+        });
+    }
+
+    /// `slug`, or `slug-1`, `slug-2`, … when it is already taken.
+    fn unique_anchor(&mut self, slug: String) -> String {
+        let mut candidate = slug.clone();
+        while let Some(count) = self.anchors.get_mut(&slug) {
+            *count += 1;
+            candidate = format!("{}-{}", slug, count);
+            if !self.anchors.contains_key(&candidate) {
+                break;
+            }
+        }
+        self.anchors.entry(candidate.clone()).or_insert(0);
+        candidate
+    }
+
+    /// Text of a heading, leaving out what the walk drops (permalink
+    /// anchors, hidden and clutter elements).
+    fn heading_text(&self, el: &ElementRef) -> String {
+        let text: String = el
+            .descendants()
+            .filter(|node| {
+                !node
+                    .ancestors()
+                    .take_while(|a| a.id() != el.id())
+                    .filter_map(ElementRef::wrap)
+                    .any(|a| {
+                        self.skip_ids.contains(&a, self.keep_ids)
+                            || should_skip(&a)
+                            || (a.value().name() == "a" && is_heading_permalink(&a))
+                    })
+            })
+            .filter_map(|node| match node.value() {
+                Node::Text(t) => Some(normalize_text(&t.text).into_owned()),
+                _ => None,
+            })
+            .collect();
+        collapse_whitespace(&text)
+    }
+
+    fn push_heading(&self, level: u8, text: &str, buf: &mut String) {
+        let hashes = "#".repeat(level as usize);
+        match self.opts.heading_style {
+            HeadingStyle::Setext if level <= 2 => {
+                // The underline would also claim a preceding paragraph line
+                if !buf.is_empty() && !buf.ends_with("\n\n") {
+                    buf.push_str(if buf.ends_with('\n') { "\n" } else { "\n\n" });
+                }
+                let underline = if level == 1 { "=" } else { "-" };
+                buf.push_str(text);
+                buf.push('\n');
+                let width = strip_link_markers(text).chars().count();
+                buf.push_str(&underline.repeat(width.max(3)));
+            }
+            HeadingStyle::AtxClosed => {
+                buf.push('\n');
+                buf.push_str(&hashes);
+                buf.push(' ');
+                buf.push_str(text);
+                buf.push(' ');
+                buf.push_str(&hashes);
+            }
+            _ => {
+                buf.push('\n');
+                buf.push_str(&hashes);
+                buf.push(' ');
+                buf.push_str(text);
+            }
+        }
+        buf.push_str("\n\n");
+    }
+
     /// Walk children into a temporary String (used for inline contexts).
     fn children_to_string(&mut self, el: &ElementRef) -> String {
         let mut tmp = String::new();
@@ -351,66 +1829,564 @@ impl<'a> Walker<'a> {
         tmp
     }
 
+    /// Emit the element's inline content between `open` and `close`
+    /// delimiters, or nothing when the content is empty.
+    fn wrap_children(&mut self, el: &ElementRef, open: &str, close: &str, buf: &mut String) {
+        let start = buf.len();
+        self.walk_children(el, buf);
+        delimit_tail(buf, start, open, close);
+    }
+
     fn handle_link(&mut self, el: &ElementRef, buf: &mut String) {
-        let text = get_text_content(el);
-        let href = el.value().attr("href").unwrap_or("");
+        let mut text = get_text_content(el);
+        if text.is_empty() {
+            // Icon-only link: fall back to its accessible name
+            let aria_label = collapse_whitespace(el.value().attr("aria-label").unwrap_or(""));
+            if !aria_label.is_empty() {
+                text = aria_label;
+            } else if let Some(label) = el.select(&SEL_SVG).find_map(|svg| svg_label(&svg)) {
+                text = label;
+            }
+        }
+        let mut href = el.value().attr("href").unwrap_or("");
+        if is_unfollowable_href(href, self.opts) {
+            href = "";
+        }
+        if !href.is_empty() && el.select(&SEL_IMG).next().is_some() {
+            self.handle_image_link(el, href, buf);
+            return;
+        }
+        if href.is_empty() && el.select(&SEL_IMG).next().is_some() {
+            // An unlinked image keeps rendering as an image
+            self.walk_children(el, buf);
+            return;
+        }
         if text.is_empty() && href.is_empty() {
             return;
         }
         if text.is_empty() || href.is_empty() {
             // Just emit the text (or nothing)
+            buf.push_str(&escape_char_refs(&text));
+            return;
+        }
+        let resolved = self.link_url(href);
+        let title = collapse_whitespace(el.value().attr("title").unwrap_or(""));
+        let label = escape_link_text(&text).into_owned();
+        self.push_recorded_link(Some(el), buf, label, text, resolved, title);
+    }
+
+    /// Emit a link wrapped in its markers and remember it, with the `<a>`
+    /// element's attributes, under the URL the citation pass will list it as.
+    /// The title is recorded for the references, not written into the link.
+    fn push_recorded_link(
+        &mut self,
+        anchor: Option<&ElementRef>,
+        buf: &mut String,
+        label: String,
+        text: String,
+        resolved: String,
+        title: String,
+    ) {
+        if self.links_as_text {
+            // What `strip_links` would leave of the link
             buf.push_str(&text);
             return;
         }
-        let resolved = resolve_url(href, &self.base_url);
-        buf.push('[');
-        buf.push_str(&text);
-        buf.push_str("](");
-        buf.push_str(&resolved);
-        buf.push(')');
+        buf.push(LINK_OPEN);
+        buf.push_str(&self.links.len().to_string());
+        buf.push(LINK_SEP);
+        push_link_label(buf, &label, &resolved, "");
+        buf.push(LINK_CLOSE);
+
+        let url = if self.opts.normalize_urls {
+            normalize_url(&resolved, &self.opts.strip_query_params)
+        } else {
+            resolved
+        };
+        let attr = |name| anchor.and_then(|a| a.value().attr(name)).unwrap_or("");
+        let info = LinkInfo {
+            text,
+            url,
+            title,
+            citation_number: 0,
+            is_internal: false,
+            host: String::new(),
+            rel: attr("rel")
+                .split_ascii_whitespace()
+                .map(|t| t.to_ascii_lowercase())
+                .collect(),
+            target: attr("target").trim().to_string(),
+            aria_label: collapse_whitespace(attr("aria-label")),
+        };
+        self.links.push(WalkedLink { info, label });
+    }
+
+    /// A link wrapping an image (`<a href=full><img src=thumb></a>`): the
+    /// label is the rendered image plus any text, e.g. `[![Photo](thumb)](full)`.
+    fn handle_image_link(&mut self, el: &ElementRef, href: &str, buf: &mut String) {
+        self.inline_depth += 1;
+        let label = self.children_to_string(el);
+        self.inline_depth -= 1;
+        let label = collapse_whitespace(&label);
+        if label.is_empty() {
+            return;
+        }
+        let resolved = self.link_url(href);
+        let title = collapse_whitespace(el.value().attr("title").unwrap_or(""));
+        let text = plain_link_text(&label);
+        self.push_recorded_link(Some(el), buf, label, text, resolved, title);
     }
 
     fn handle_image(&mut self, el: &ElementRef, buf: &mut String) {
         let src = el.value().attr("src").unwrap_or("");
-        if src.is_empty() {
+
+        // Responsive candidates: <picture> sources first, then the img's own srcset
+        let mut candidates: Vec<SrcsetCandidate> = Vec::new();
+        if let Some(picture) = el
+            .parent()
+            .and_then(ElementRef::wrap)
+            .filter(|p| p.value().name() == "picture")
+        {
+            for source in picture
+                .children()
+                .filter_map(ElementRef::wrap)
+                .filter(|c| c.value().name() == "source")
+            {
+                candidates.extend(parse_srcset(source.value().attr("srcset").unwrap_or("")));
+            }
+        }
+        candidates.extend(parse_srcset(el.value().attr("srcset").unwrap_or("")));
+
+        // Lazy-loaded images keep the real URL in a data-* attribute, with a
+        // placeholder in src; a real src wins
+        let mut lazy_src = None;
+        let lazy_attrs: &[String] = if is_placeholder_image(src) {
+            &self.opts.lazy_image_attrs
+        } else {
+            &[]
+        };
+        for attr in lazy_attrs {
+            let value = el.value().attr(attr).unwrap_or("").trim();
+            if value.is_empty() {
+                continue;
+            }
+            if attr.ends_with("srcset") {
+                candidates.extend(parse_srcset(value));
+            } else if lazy_src.is_none() && !value.starts_with("data:") {
+                lazy_src = Some(value);
+            }
+        }
+
+        let chosen = largest_candidate(&candidates)
+            .map(|c| c.url.as_str())
+            .or(lazy_src)
+            .unwrap_or(src);
+        if chosen.is_empty() {
+            return;
+        }
+        let (width, height) = image_dimensions(el);
+        if self.opts.skip_tracking_pixels && width == Some(1) && height == Some(1) {
             return;
         }
         let alt = el.value().attr("alt").unwrap_or("Image");
         let title = el.value().attr("title").unwrap_or("");
-        let resolved = resolve_url(src, &self.base_url);
-        buf.push_str("![");
-        buf.push_str(alt);
-        buf.push_str("](");
-        buf.push_str(&resolved);
-        if !title.is_empty() {
-            buf.push_str(" \"");
-            buf.push_str(title);
-            buf.push('"');
+        // Candidates and lazy attributes already skip data URIs, so one here
+        // is the only source there is
+        let resolved = if chosen.starts_with("data:") {
+            match self.data_uri(chosen) {
+                Some(uri) => uri,
+                None => return,
+            }
+        } else {
+            self.resolve(chosen)
+        };
+        let has_size = width.is_some() || height.is_some();
+        if self.figure_caption.is_some() || !candidates.is_empty() || has_size {
+            let resolved_candidates: Vec<String> =
+                candidates.iter().map(|c| self.resolve(&c.url)).collect();
+            let meta = self.image_meta.entry(resolved.clone()).or_default();
+            if meta.caption.is_empty() {
+                meta.caption = self.figure_caption.clone().unwrap_or_default();
+            }
+            if meta.candidates.is_empty() {
+                meta.candidates = resolved_candidates;
+            }
+            if meta.width.is_none() && meta.height.is_none() {
+                meta.width = width;
+                meta.height = height;
+            }
+        }
+        push_link(buf, true, alt, &resolved, title);
+    }
+
+    /// Record images set through `background-image` in the inline style or a
+    /// lazy-loader's `data-bg` attribute.
+    fn collect_background_images(&mut self, el: &ElementRef) {
+        let mut urls: Vec<&str> = Vec::new();
+        if let Some(style) = el.value().attr("style") {
+            for decl in style.split(';') {
+                let Some((prop, value)) = decl.split_once(':') else {
+                    continue;
+                };
+                if matches!(
+                    prop.trim().to_ascii_lowercase().as_str(),
+                    "background" | "background-image"
+                ) {
+                    urls.extend(css_urls(value));
+                }
+            }
+        }
+        if let Some(bg) = el.value().attr("data-bg").map(str::trim) {
+            if bg.starts_with("url(") {
+                urls.extend(css_urls(bg));
+            } else if !bg.is_empty() {
+                urls.push(bg);
+            }
+        }
+        for url in urls {
+            if url.starts_with("data:") {
+                continue;
+            }
+            let resolved = self.resolve(url);
+            self.extra_images.push(ImageInfo {
+                alt: collapse_whitespace(el.value().attr("aria-label").unwrap_or("")),
+                format: image_format(&resolved),
+                url: resolved,
+                title: String::new(),
+                caption: String::new(),
+                candidates: Vec::new(),
+                width: None,
+                height: None,
+                is_probably_icon: false,
+            });
+        }
+    }
+
+    /// A `data:` image URI as it should be emitted, or `None` to drop it.
+    fn data_uri(&self, uri: &str) -> Option<String> {
+        let limit = self.opts.data_uri_limit;
+        if uri.len() <= limit {
+            return Some(uri.to_string());
+        }
+        match self.opts.data_uri_images {
+            DataUriImages::Drop => None,
+            DataUriImages::Keep => Some(uri.to_string()),
+            DataUriImages::Truncate => {
+                let mut end = limit;
+                while !uri.is_char_boundary(end) {
+                    end -= 1;
+                }
+                Some(format!("{}…", &uri[..end]))
+            }
+        }
+    }
+
+    /// Emit a `<video>`/`<audio>` element as a link to its source and record
+    /// it (and any poster image) for the structured output.
+    fn handle_media(&mut self, el: &ElementRef, buf: &mut String) {
+        let src = el
+            .value()
+            .attr("src")
+            .filter(|s| !s.is_empty())
+            .or_else(|| {
+                el.children()
+                    .filter_map(ElementRef::wrap)
+                    .filter(|c| c.value().name() == "source")
+                    .find_map(|c| c.value().attr("src").filter(|s| !s.is_empty()))
+            });
+        let src = match src {
+            Some(s) => s,
+            None => return,
+        };
+        let kind = el.value().name();
+        let url = self.resolve(src);
+        let poster = el
+            .value()
+            .attr("poster")
+            .map_or(String::new(), |p| self.resolve(p));
+
+        let text = ["title", "aria-label"]
+            .iter()
+            .filter_map(|a| el.value().attr(a))
+            .map(str::trim)
+            .find(|t| !t.is_empty())
+            .map(str::to_string)
+            .or_else(|| self.figure_caption.clone())
+            .unwrap_or_else(|| if kind == "video" { "Video" } else { "Audio" }.to_string());
+
+        let label = escape_link_text(&text).into_owned();
+        self.push_recorded_link(None, buf, label, text.clone(), url.clone(), String::new());
+
+        if !poster.is_empty() {
+            self.extra_images.push(ImageInfo {
+                alt: text,
+                url: poster.clone(),
+                title: String::new(),
+                caption: self.figure_caption.clone().unwrap_or_default(),
+                candidates: Vec::new(),
+                width: None,
+                height: None,
+                is_probably_icon: false,
+                format: image_format(&poster),
+            });
+        }
+        self.media.push(MediaInfo {
+            kind: kind.to_string(),
+            url,
+            poster,
+        });
+    }
+
+    /// Render the quote's children as full markdown, then prefix every
+    /// line with `>` (blank lines become a bare `>`). Nested quotes pick up
+    /// an extra `> ` from each enclosing level.
+    fn handle_blockquote(&mut self, el: &ElementRef, buf: &mut String) {
+        let content = self.children_to_string(el);
+        let content = content.trim();
+        if content.is_empty() {
+            return;
+        }
+        if !buf.is_empty() && !buf.ends_with('\n') {
+            buf.push('\n');
+        }
+        let mut in_fence = false;
+        let mut prev_blank = false;
+        for line in content.lines() {
+            // Code keeps its trailing whitespace; only prose is trimmed
+            let is_fence = fence_marker(line).is_some();
+            let line = if in_fence && !is_fence {
+                line
+            } else {
+                line.trim_end()
+            };
+            if line.is_empty() {
+                // Runs of blank lines collapse to one outside code blocks
+                if in_fence || !prev_blank {
+                    buf.push_str(">\n");
+                }
+                prev_blank = true;
+                continue;
+            }
+            if is_fence {
+                in_fence = !in_fence;
+            }
+            prev_blank = false;
+            buf.push_str("> ");
+            buf.push_str(line);
+            buf.push('\n');
+        }
+        buf.push('\n');
+    }
+
+    /// Inline quotation: double quotes, alternating to single quotes when
+    /// nested.
+    fn handle_quote(&mut self, el: &ElementRef, buf: &mut String) {
+        let mark = if self.quote_depth.is_multiple_of(2) {
+            '"'
+        } else {
+            '\''
+        };
+        let start = buf.len();
+        self.quote_depth += 1;
+        self.walk_children(el, buf);
+        self.quote_depth -= 1;
+        let mark = mark.to_string();
+        if !delimit_tail(buf, start, &mark, &mark) {
+            return;
+        }
+        if self.opts.include_cite_urls {
+            let cite = el.value().attr("cite").unwrap_or("").trim();
+            if !cite.is_empty() {
+                buf.push_str(" (");
+                let url = self.resolve(cite);
+                let source = "source".to_string();
+                self.push_recorded_link(None, buf, source.clone(), source, url, String::new());
+                buf.push(')');
+            }
+        }
+    }
+
+    /// `HTML (HyperText Markup Language)` — by default only on the first
+    /// occurrence of each abbreviation.
+    fn handle_abbr(&mut self, el: &ElementRef, buf: &mut String) {
+        let content = self.children_to_string(el);
+        buf.push_str(content.trim_end());
+        let title = collapse_whitespace(el.value().attr("title").unwrap_or(""));
+        if !content.trim().is_empty() && !title.is_empty() {
+            let expand = match self.opts.abbr_expansion {
+                AbbrExpansion::Always => true,
+                AbbrExpansion::Never => false,
+                AbbrExpansion::First => self.expanded_abbrs.insert(get_text_content(el)),
+            };
+            if expand {
+                buf.push_str(" (");
+                buf.push_str(&title);
+                buf.push(')');
+            }
+        }
+        if content.ends_with(char::is_whitespace) {
+            buf.push(' ');
+        }
+    }
+
+    /// Emit MathML as `$...$` (or `$$...$$` for display math), falling back
+    /// to the `alttext` attribute for constructs we can't convert.
+    fn handle_math(&mut self, el: &ElementRef, buf: &mut String) {
+        let latex = mathml::mathml_to_latex(el)
+            .or_else(|| {
+                el.value()
+                    .attr("alttext")
+                    .map(|a| a.trim().to_string())
+                    .filter(|a| !a.is_empty())
+            })
+            .or_else(|| mathml::tex_annotation(el));
+        let latex = match latex {
+            Some(l) => l,
+            None => {
+                buf.push_str(&get_text_content(el));
+                return;
+            }
+        };
+        if el.value().attr("display") == Some("block") {
+            buf.push_str("\n\n$$");
+            buf.push_str(&latex);
+            buf.push_str("$$\n\n");
+        } else {
+            buf.push('$');
+            buf.push_str(&latex);
+            buf.push('$');
+        }
+    }
+
+    /// A figure with a single image (or video/audio) emits it followed by its
+    /// caption as an italic line; anything else is walked as a plain container.
+    fn handle_figure(&mut self, el: &ElementRef, buf: &mut String) {
+        let caption_el = el
+            .children()
+            .filter_map(ElementRef::wrap)
+            .find(|c| c.value().name() == "figcaption");
+        let caption_el = match caption_el {
+            Some(c) if el.select(&SEL_FIGURE_MEDIA).count() == 1 => c,
+            _ => {
+                self.walk_children(el, buf);
+                return;
+            }
+        };
+
+        let caption_text = get_text_content(&caption_el);
+        let saved = self.figure_caption.replace(caption_text);
+        for child in el.children() {
+            if child.id() == caption_el.id() {
+                continue;
+            }
+            if let Some(child_el) = ElementRef::wrap(child) {
+                self.walk(child_el, buf);
+            }
+        }
+        self.figure_caption = saved;
+
+        let caption = self.children_to_string(&caption_el);
+        let caption = caption.trim();
+        if !caption.is_empty() {
+            let em = self.opts.style.emphasis_marker;
+            buf.push_str("\n\n");
+            buf.push_str(em);
+            buf.push_str(caption);
+            buf.push_str(em);
+            buf.push_str("\n\n");
         }
-        buf.push(')');
     }
 
     fn handle_list(&mut self, el: &ElementRef, ordered: bool, buf: &mut String) {
+        // A list nested inside an item's text must start on its own line
+        if !buf.is_empty() && !buf.ends_with('\n') {
+            buf.push('\n');
+        }
         let items = direct_children_by_sel(el, &SEL_LI);
-        let mut counter = 1usize;
+        let number_attr = |e: &ElementRef, name: &str| {
+            e.value().attr(name).and_then(|v| v.trim().parse::<i64>().ok())
+        };
+        // <ol reversed> counts down from the item count unless `start` is given
+        let reversed = ordered && el.value().attr("reversed").is_some();
+        let step = if reversed { -1 } else { 1 };
+        let mut counter = number_attr(el, "start").unwrap_or(if reversed {
+            items.len() as i64
+        } else {
+            1
+        });
         for li in &items {
+            if let Some(value) = number_attr(li, "value") {
+                counter = value;
+            }
             let content = self.children_to_string(li);
             let trimmed = content.trim();
             if !trimmed.is_empty() {
-                if ordered {
-                    buf.push_str(&counter.to_string());
-                    buf.push_str(". ");
-                    counter += 1;
+                let style = &self.opts.style;
+                let marker = if ordered {
+                    format!("{}{} ", counter, style.ordered_marker_suffix)
                 } else {
-                    buf.push_str("- ");
+                    format!("{} ", style.bullet_marker)
+                };
+                match task_state(li) {
+                    Some(checked) => {
+                        let check = if checked { "[x] " } else { "[ ] " };
+                        push_list_item(buf, &marker, &format!("{}{}", check, trimmed));
+                    }
+                    None => push_list_item(buf, &marker, trimmed),
                 }
-                buf.push_str(trimmed);
-                buf.push('\n');
+                counter += step;
             }
         }
         buf.push('\n');
     }
 
+    fn handle_definition_list(&mut self, el: &ElementRef, buf: &mut String) {
+        let mut emitted = false;
+        for item in definition_list_items(el) {
+            let content = self.children_to_string(&item);
+            let trimmed = content.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+            if item.value().name() == "dt" {
+                // Blank line between term groups
+                if emitted {
+                    buf.push('\n');
+                }
+                match self.opts.definition_list_style {
+                    DefinitionListStyle::Bold => {
+                        buf.push_str(self.opts.style.strong_marker);
+                        buf.push_str(trimmed);
+                        buf.push_str(self.opts.style.strong_marker);
+                        buf.push('\n');
+                    }
+                    DefinitionListStyle::Extended => {
+                        buf.push_str(trimmed);
+                        buf.push('\n');
+                    }
+                }
+            } else {
+                let first_prefix = match self.opts.definition_list_style {
+                    DefinitionListStyle::Bold => "  ",
+                    DefinitionListStyle::Extended => ": ",
+                };
+                for (i, line) in trimmed.lines().enumerate() {
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    buf.push_str(if i == 0 { first_prefix } else { "  " });
+                    buf.push_str(line);
+                    buf.push('\n');
+                }
+            }
+            emitted = true;
+        }
+        if emitted {
+            buf.push('\n');
+        }
+    }
+
     fn handle_table(&mut self, el: &ElementRef, buf: &mut String) {
         let tag = el.value().name();
 
@@ -422,15 +2398,7 @@ impl<'a> Walker<'a> {
 
         // Gather rows
         let has_nested_table = el.select(&SEL_TABLE).next().is_some();
-        let mut rows: Vec<ElementRef> = direct_children_by_sel(el, &SEL_TR);
-
-        if rows.is_empty() {
-            // Look inside thead/tbody/tfoot
-            let sections = direct_children_by_sel(el, &SEL_THEAD_TBODY_TFOOT);
-            for sec in &sections {
-                rows.extend(direct_children_by_sel(sec, &SEL_TR));
-            }
-        }
+        let rows = table_rows(el);
         if rows.is_empty() {
             if has_nested_table {
                 self.walk_children(el, buf);
@@ -442,21 +2410,34 @@ impl<'a> Walker<'a> {
         let first_row = &rows[0];
         let first_row_cells = direct_children_by_sel(first_row, &SEL_TD_TH);
 
-        let has_block_children = first_row_cells.iter().any(|cell| {
-            cell.children().any(|c| {
-                if let Some(ce) = ElementRef::wrap(c) {
-                    BLOCK_LIKE_TAGS.contains(&ce.value().name())
-                } else {
-                    false
-                }
-            })
-        });
+        let has_th_header = first_row_cells.iter().any(|c| c.value().name() == "th");
 
-        let looks_like_layout =
-            !first_row_cells.is_empty() && first_row_cells.len() <= 2 && rows.len() >= 15;
+        // A table nested in a headed table's cell is a positioning hack; its
+        // text is flattened into the cell instead of demoting the outer table
+        let has_nested_table = has_nested_table && !has_th_header;
 
-        if has_nested_table || has_block_children || looks_like_layout {
-            if self.dedupe_tables {
+        let has_block_children = first_row_cells
+            .iter()
+            .any(|cell| has_block_layout(cell, has_th_header));
+
+        // Header cells, a caption or an explicit table role mark real data,
+        // however long and narrow the table is
+        let has_data_markers = has_th_header
+            || !direct_children_by_sel(el, &SEL_CAPTION).is_empty()
+            || el.value().attr("role") == Some("table")
+            || el.value().attr("summary").is_some();
+        let looks_like_layout = !has_data_markers
+            && !first_row_cells.is_empty()
+            && first_row_cells.len() <= 2
+            && rows.len() >= 15;
+
+        let is_layout = match self.opts.table_mode {
+            TableMode::Auto => has_nested_table || has_block_children || looks_like_layout,
+            TableMode::AlwaysData => false,
+            TableMode::AlwaysLayout => true,
+        };
+        if is_layout {
+            if self.opts.dedupe_tables {
                 self.layout_table_depth += 1;
                 self.walk_children(el, buf);
                 self.layout_table_depth -= 1;
@@ -466,41 +2447,143 @@ impl<'a> Walker<'a> {
             return;
         }
 
-        // Data table — emit markdown table
-        let mut md_rows: Vec<String> = Vec::new();
+        // Data table — lay cells out on a grid so spans keep columns aligned
+        let mut grid: Vec<Vec<String>> = Vec::new();
+        // The same grid with each cell's plain text, for `extract_tables`
+        let mut plain_grid: Vec<Vec<String>> = Vec::new();
         let mut first_has_th = false;
-        let mut first_cell_count = 0;
+        // Alignment of the cells starting in each column, for the header
+        // row and the first body row
+        let mut row_aligns: Vec<Vec<Option<Alignment>>> = Vec::new();
+        // Per column: rows still covered by a rowspan, and their filler in
+        // markdown and in plain text
+        let mut carry: Vec<(usize, String, String)> = Vec::new();
 
-        for (i, row) in rows.iter().enumerate() {
+        for row in &rows {
             let cells = direct_children_by_sel(row, &SEL_TD_TH);
             if cells.is_empty() {
                 continue;
             }
-            let mut parts: Vec<String> = Vec::new();
-            for cell in &cells {
-                parts.push(get_text_content(cell));
-                if i == 0 {
-                    if cell.value().name() == "th" {
-                        first_has_th = true;
+            if grid.is_empty() {
+                first_has_th = cells.iter().any(|c| c.value().name() == "th");
+            }
+            let mut out: Vec<String> = Vec::new();
+            let mut plain: Vec<String> = Vec::new();
+            let mut aligns: Vec<Option<Alignment>> = Vec::new();
+            let mut cells = cells.iter();
+            loop {
+                let col = out.len();
+                if let Some((remaining, filler, plain_filler)) =
+                    carry.get_mut(col).filter(|(n, _, _)| *n > 0)
+                {
+                    *remaining -= 1;
+                    out.push(filler.clone());
+                    plain.push(plain_filler.clone());
+                    continue;
+                }
+                let Some(cell) = cells.next() else {
+                    // Rowspans may still cover columns past the last cell
+                    if carry[col.min(carry.len())..].iter().any(|(n, _, _)| *n > 0) {
+                        out.push(String::new());
+                        plain.push(String::new());
+                        continue;
+                    }
+                    break;
+                };
+                let content = self.table_cell(cell);
+                let text = get_text_content(cell);
+                let colspan = span_attr(cell, "colspan");
+                let rowspan = span_attr(cell, "rowspan");
+                let (filler, plain_filler) = if self.opts.repeat_spanned_cells {
+                    (content.clone(), text.clone())
+                } else {
+                    (String::new(), String::new())
+                };
+                if carry.len() < col + colspan {
+                    carry.resize(col + colspan, (0, String::new(), String::new()));
+                }
+                if aligns.len() <= col {
+                    aligns.resize(col + 1, None);
+                }
+                aligns[col] = alignment_of(cell);
+                for (i, slot) in carry[col..col + colspan].iter_mut().enumerate() {
+                    *slot = (rowspan - 1, filler.clone(), plain_filler.clone());
+                    if i == 0 {
+                        out.push(content.clone());
+                        plain.push(text.clone());
+                    } else {
+                        out.push(filler.clone());
+                        plain.push(plain_filler.clone());
                     }
                 }
             }
-            if i == 0 {
-                first_cell_count = parts.len();
+            if row_aligns.len() < 2 {
+                row_aligns.push(aligns);
             }
-            let row_str = format!("| {} |", parts.join(" | "));
-            md_rows.push(row_str);
+            grid.push(out);
+            plain_grid.push(plain);
         }
 
-        if md_rows.is_empty() {
+        let width = grid.iter().map(Vec::len).max().unwrap_or(0);
+        if width == 0 {
             return;
         }
+        self.table_count += 1;
+        let mut md_rows: Vec<String> = grid
+            .into_iter()
+            .map(|mut row| {
+                row.resize(width, String::new());
+                format!("| {} |", row.join(" | "))
+            })
+            .collect();
 
-        if first_has_th && first_cell_count > 0 {
-            let sep = format!(
-                "| {} |",
-                vec!["---"; first_cell_count].join(" | ")
-            );
+        let caption = direct_children_by_sel(el, &SEL_CAPTION)
+            .first()
+            .map(get_text_content)
+            .unwrap_or_default();
+
+        for row in &mut plain_grid {
+            row.resize(width, String::new());
+        }
+        let header = if first_has_th && !plain_grid.is_empty() {
+            Some(plain_grid.remove(0))
+        } else {
+            None
+        };
+        self.tables.push(TableInfo {
+            index: self.tables.len(),
+            caption: Some(caption.clone()).filter(|c| !c.is_empty()),
+            header,
+            rows: plain_grid,
+        });
+
+        if !caption.is_empty() {
+            buf.push_str(self.opts.style.strong_marker);
+            buf.push_str(&caption);
+            buf.push_str(self.opts.style.strong_marker);
+            buf.push_str("\n\n");
+        }
+
+        // GFM only recognises a table with a separator after its first row
+        if first_has_th || self.opts.table_header_separator {
+            let col_aligns = column_alignments(el);
+            let sep = (0..width)
+                .map(|col| {
+                    let mut found = std::iter::once(col_aligns.get(col))
+                        .chain(row_aligns.iter().map(|r| r.get(col)))
+                        .filter_map(|a| a.copied().flatten());
+                    let first = found.next();
+                    // Conflicting hints fall back to the default (left)
+                    match first.filter(|a| found.all(|b| b == *a)) {
+                        Some(Alignment::Left) => ":---",
+                        Some(Alignment::Center) => ":---:",
+                        Some(Alignment::Right) => "---:",
+                        None => "---",
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" | ");
+            let sep = format!("| {} |", sep);
             md_rows.insert(1, sep);
         }
 
@@ -525,516 +2608,5758 @@ fn direct_children_by_sel<'a>(parent: &ElementRef<'a>, _sel: &Selector) -> Vec<E
         .collect()
 }
 
-/// Recursively extract text content (normalised whitespace).
-fn get_text_content(el: &ElementRef) -> String {
-    let mut parts: Vec<String> = Vec::new();
-    collect_text(el, &mut parts);
-    let joined = parts.join("");
-    // Normalise whitespace
-    joined.split_whitespace().collect::<Vec<_>>().join(" ")
-}
-
-fn collect_text(el: &ElementRef, parts: &mut Vec<String>) {
-    for child in el.children() {
-        match child.value() {
-            Node::Text(t) => {
-                parts.push(t.text.to_string());
-            }
-            Node::Element(_) => {
-                if let Some(child_el) = ElementRef::wrap(child) {
-                    collect_text(&child_el, parts);
-                }
-            }
-            _ => {}
+/// Parse a `srcset` attribute. URLs may themselves contain commas, so a
+/// candidate ends at whitespace and only the descriptor runs to the next comma.
+fn parse_srcset(srcset: &str) -> Vec<SrcsetCandidate> {
+    let mut out = Vec::new();
+    let mut rest = srcset;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() {
+            break;
+        }
+        let url_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let mut url = &rest[..url_end];
+        rest = &rest[url_end..];
+        let mut descriptor = "";
+        if url.ends_with(',') {
+            url = url.trim_end_matches(',');
+        } else {
+            let desc_end = rest.find(',').unwrap_or(rest.len());
+            descriptor = rest[..desc_end].split_whitespace().next().unwrap_or("");
+            rest = &rest[desc_end..];
+        }
+        if url.is_empty() {
+            continue;
+        }
+        let mut cand = SrcsetCandidate {
+            url: url.to_string(),
+            width: None,
+            density: 1.0,
+        };
+        if let Some(w) = descriptor.strip_suffix('w') {
+            cand.width = w.parse().ok();
+        } else if let Some(x) = descriptor.strip_suffix('x') {
+            cand.density = x.parse().unwrap_or(1.0);
         }
+        out.push(cand);
     }
+    out
 }
 
-/// Get raw text preserving whitespace (for <pre> blocks).
-fn get_raw_text(el: &ElementRef) -> String {
-    let mut parts: Vec<String> = Vec::new();
-    collect_text(el, &mut parts);
-    parts.join("")
+/// Whether an `img` src is missing or a stand-in for a lazy-loaded image:
+/// a `data:` URI or a known spacer file.
+fn is_placeholder_image(src: &str) -> bool {
+    let src = src.trim();
+    if src.is_empty() || src.starts_with("data:") {
+        return true;
+    }
+    let path = src.split(['?', '#']).next().unwrap_or("");
+    let name = path.rsplit('/').next().unwrap_or("");
+    let stem = name.split('.').next().unwrap_or("");
+    PLACEHOLDER_IMAGE_NAMES
+        .iter()
+        .any(|p| stem.eq_ignore_ascii_case(p))
 }
 
-// ---------------------------------------------------------------------------
-// Clean markdown (same rules as Python _clean_markdown)
-// ---------------------------------------------------------------------------
-
-static RE_MULTI_NL: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n{3,}").unwrap());
-static RE_MULTI_SP: Lazy<Regex> = Lazy::new(|| Regex::new(r" {2,}").unwrap());
-static RE_EMPTY_LI: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n- \n").unwrap());
-static RE_EMPTY_OL: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n\d+\. \n").unwrap());
-static RE_HEADER_BEFORE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n+(#{1,6})").unwrap());
-static RE_HEADER_AFTER: Lazy<Regex> = Lazy::new(|| Regex::new(r"(#{1,6}.*)\n+").unwrap());
-
-fn clean_markdown(md: &str) -> String {
-    let s = RE_MULTI_NL.replace_all(md, "\n\n");
-    let s = RE_MULTI_SP.replace_all(&s, " ");
-    let s = RE_EMPTY_LI.replace_all(&s, "\n");
-    let s = RE_EMPTY_OL.replace_all(&s, "\n");
-    s.trim().to_string()
+/// The largest non-`data:` candidate: by width when any width descriptors
+/// are present, otherwise by density. Ties keep the earlier candidate.
+fn largest_candidate(cands: &[SrcsetCandidate]) -> Option<&SrcsetCandidate> {
+    let usable = cands.iter().filter(|c| !c.url.starts_with("data:"));
+    if cands.iter().any(|c| c.width.is_some()) {
+        usable
+            .filter(|c| c.width.is_some())
+            .fold(None, |best, c| match best {
+                Some(b) if b.width >= c.width => Some(b),
+                _ => Some(c),
+            })
+    } else {
+        usable.fold(None, |best, c| match best {
+            Some(b) if b.density >= c.density => Some(b),
+            _ => Some(c),
+        })
+    }
 }
 
-fn clean_markdown_readable(md: &str) -> String {
-    let s = RE_MULTI_NL.replace_all(md, "\n\n");
-    let s = RE_EMPTY_LI.replace_all(&s, "\n");
-    let s = RE_HEADER_BEFORE.replace_all(&s, "\n\n$1");
-    let s = RE_HEADER_AFTER.replace_all(&s, "$1\n\n");
-    s.trim().to_string()
+/// The URLs of every `url(...)` in a CSS value.
+fn css_urls(value: &str) -> impl Iterator<Item = &str> {
+    RE_CSS_URL.captures_iter(value).filter_map(|caps| {
+        caps.get(1)
+            .or_else(|| caps.get(2))
+            .or_else(|| caps.get(3))
+            .map(|m| m.as_str().trim())
+            .filter(|url| !url.is_empty())
+    })
 }
 
-// ---------------------------------------------------------------------------
-// Post-processing: citations, references, plain, images
-// ---------------------------------------------------------------------------
-
-fn extract_links_and_citations(md: &str, base_url: &Option<Url>) -> (Vec<LinkInfo>, String) {
-    let mut links: Vec<LinkInfo> = Vec::new();
-    let mut citation_counter = 1usize;
-    let mut result = md.to_string();
-
-    // We need to collect matches first to avoid borrow issues
-    let matches: Vec<(String, String, String, String)> = RE_LINK
-        .find_iter(md)
-        .filter_map(|m| {
-            let full = m.as_str().to_string();
-            // Skip image links (start with !)
-            if full.starts_with('!') {
-                return None;
-            }
-            RE_LINK.captures(m.as_str()).map(|caps| {
-                let text = caps.get(1).map_or("", |c| c.as_str()).to_string();
-                let url = caps.get(2).map_or("", |c| c.as_str()).to_string();
-                let title = caps.get(3).map_or("", |c| c.as_str()).to_string();
-                (full, text, url, title)
+/// Declared pixel width and height of an image: the `width`/`height`
+/// attributes, falling back to simple `width: 32px` declarations in `style`.
+fn image_dimensions(el: &ElementRef) -> (Option<u32>, Option<u32>) {
+    let style = el.value().attr("style").unwrap_or("");
+    let dimension = |name: &str| {
+        el.value().attr(name).and_then(parse_pixels).or_else(|| {
+            style.split(';').find_map(|decl| {
+                let (prop, value) = decl.split_once(':')?;
+                if prop.trim().eq_ignore_ascii_case(name) {
+                    parse_pixels(value)
+                } else {
+                    None
+                }
             })
         })
-        .collect();
-
-    for (full, text, url, title) in matches {
-        let resolved = if let Some(base) = base_url {
-            match base.join(&url) {
-                Ok(u) => u.to_string(),
-                Err(_) => url.clone(),
-            }
-        } else {
-            url.clone()
-        };
+    };
+    (dimension("width"), dimension("height"))
+}
 
-        links.push(LinkInfo {
-            text: text.clone(),
-            url: resolved,
-            title: title.clone(),
-            citation_number: citation_counter,
-        });
+/// `"32"`, `"32px"` or `"31.5px"` as whole pixels; anything relative (`%`,
+/// `em`, `auto`) is `None`.
+fn parse_pixels(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let number = value.strip_suffix("px").unwrap_or(value).trim();
+    let px: f64 = number.parse().ok()?;
+    (px.is_finite() && px >= 0.0).then(|| px.round() as u32)
+}
 
-        let citation = format!("{}[{}]", text, citation_counter);
-        result = result.replacen(&full, &citation, 1);
-        citation_counter += 1;
+/// File type hint from an image URL: the MIME subtype of a data URI, or the
+/// path's extension when it is a known image type.
+fn image_format(url: &str) -> String {
+    const FORMATS: &[&str] = &[
+        "png", "jpg", "jpeg", "gif", "webp", "avif", "svg", "ico", "bmp", "tif", "tiff", "heic",
+    ];
+    let format = if let Some(data) = url.strip_prefix("data:") {
+        data.strip_prefix("image/")
+            .and_then(|rest| rest.split([';', ',', '+']).next())
+            .unwrap_or("")
+            .to_ascii_lowercase()
+    } else {
+        let path = url.split(['?', '#']).next().unwrap_or("");
+        let file = path.rsplit('/').next().unwrap_or("");
+        file.rsplit_once('.')
+            .map_or(String::new(), |(_, ext)| ext.to_ascii_lowercase())
+    };
+    if FORMATS.contains(&format.as_str()) {
+        format
+    } else {
+        String::new()
     }
+}
 
-    (links, result)
+/// Emit one list item, indenting continuation lines (nested lists, further
+/// paragraphs, code blocks) by the marker width so they stay inside the
+/// item. A blank line between blocks is kept, which makes the item loose.
+fn push_list_item(buf: &mut String, marker: &str, content: &str) {
+    let mut in_fence = false;
+    let mut prev_blank = false;
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim_end();
+        if i == 0 {
+            buf.push_str(marker);
+        } else if line.is_empty() {
+            if in_fence || !prev_blank {
+                buf.push('\n');
+            }
+            prev_blank = true;
+            continue;
+        } else {
+            for _ in 0..marker.len() {
+                buf.push(' ');
+            }
+        }
+        if fence_marker(line).is_some() {
+            in_fence = !in_fence;
+        }
+        prev_blank = false;
+        buf.push_str(line);
+        buf.push('\n');
+    }
 }
 
-fn generate_references(links: &[LinkInfo]) -> String {
-    if links.is_empty() {
-        return String::new();
+/// Checkbox state of a task-list item: a checkbox `<input>` (or Notion's
+/// `checkbox-on`/`checkbox-off` marker) ahead of the item's text, else
+/// unchecked for a bare `task-list-item` class.
+fn task_state(li: &ElementRef) -> Option<bool> {
+    for node in li.descendants().skip(1) {
+        match node.value() {
+            Node::Text(t) if !t.trim().is_empty() => break,
+            Node::Element(e) => {
+                let name = e.name();
+                if name == "ul" || name == "ol" {
+                    break;
+                }
+                if name == "input"
+                    && e.attr("type")
+                        .is_some_and(|t| t.eq_ignore_ascii_case("checkbox"))
+                {
+                    return Some(e.attr("checked").is_some());
+                }
+                if let Some(class) = e.classes().find(|c| c.starts_with("checkbox-")) {
+                    match class {
+                        "checkbox-on" => return Some(true),
+                        "checkbox-off" => return Some(false),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
     }
-    let mut refs = String::from("## References\n");
-    for link in links {
-        refs.push_str(&format!("[{}]: {}", link.citation_number, link.url));
-        if !link.title.is_empty() {
-            refs.push_str(&format!(" \"{}\"", link.title));
+    li.value()
+        .classes()
+        .any(|c| c == "task-list-item")
+        .then_some(false)
+}
+
+/// `<dt>`/`<dd>` items of a definition list, looking through the `<div>`
+/// wrappers HTML allows around each term group.
+fn definition_list_items<'a>(dl: &ElementRef<'a>) -> Vec<ElementRef<'a>> {
+    let mut items = Vec::new();
+    for child in dl.children().filter_map(ElementRef::wrap) {
+        match child.value().name() {
+            "dt" | "dd" => items.push(child),
+            "div" => items.extend(
+                child
+                    .children()
+                    .filter_map(ElementRef::wrap)
+                    .filter(|c| matches!(c.value().name(), "dt" | "dd")),
+            ),
+            _ => {}
         }
-        refs.push('\n');
     }
-    refs
+    items
 }
 
-fn strip_links(md: &str) -> String {
-    // Replace [text](url) with text
-    let s = Regex::new(r"\[([^\]]+)\]\([^)]+\)")
-        .unwrap()
-        .replace_all(md, "$1");
-    // Replace ![alt](url) with alt
-    let s = Regex::new(r"!\[([^\]]*)\]\([^)]+\)")
-        .unwrap()
-        .replace_all(&s, "$1");
-    s.to_string()
+/// Recursively extract text content (normalised whitespace).
+fn get_text_content(el: &ElementRef) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    collect_text(el, " ", &mut parts);
+    collapse_whitespace(&parts.join(""))
 }
 
-fn extract_images(md: &str) -> Vec<ImageInfo> {
-    RE_IMAGE
-        .captures_iter(md)
-        .map(|caps| ImageInfo {
-            alt: caps.get(1).map_or("", |c| c.as_str()).to_string(),
-            url: caps.get(2).map_or("", |c| c.as_str()).to_string(),
-            title: caps.get(3).map_or("", |c| c.as_str()).to_string(),
-        })
-        .collect()
+/// Collect descendant text, emitting `br` for each `<br>` element.
+fn collect_text(el: &ElementRef, br: &str, parts: &mut Vec<String>) {
+    // The `<svg>` or `<br>` whose subtree is being passed over
+    let mut skipping = None;
+    for edge in el.traverse().skip(1) {
+        let node = match edge {
+            Edge::Open(node) if skipping.is_none() => node,
+            Edge::Close(node) if skipping == Some(node.id()) => {
+                skipping = None;
+                continue;
+            }
+            _ => continue,
+        };
+        match node.value() {
+            Node::Text(t) => {
+                parts.push(normalize_text(&t.text).into_owned());
+            }
+            Node::Element(e) => match e.name() {
+                "svg" => skipping = Some(node.id()),
+                "br" => {
+                    parts.push(br.to_string());
+                    skipping = Some(node.id());
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
 }
 
-// ---------------------------------------------------------------------------
-// Main content detection
-// ---------------------------------------------------------------------------
+/// Accessible name of an inline `<svg>`: its `aria-label`, else its `<title>`.
+fn svg_label(svg: &ElementRef) -> Option<String> {
+    if let Some(label) = svg.value().attr("aria-label") {
+        let label = collapse_whitespace(label);
+        if !label.is_empty() {
+            return Some(label);
+        }
+    }
+    svg.children()
+        .filter_map(ElementRef::wrap)
+        .find(|c| c.value().name() == "title")
+        .map(|t| collapse_whitespace(&t.text().collect::<String>()))
+        .filter(|t| !t.is_empty())
+}
 
-/// Pre-compute the set of node IDs that belong to nav/clutter subtrees so the
-/// walker can skip them.
-fn build_skip_set(doc: &Html) -> HashSet<NodeId> {
-    let mut set = HashSet::new();
+/// Emit `[text](url "title")` (or the `![...]` image form), escaping the text
+/// and wrapping the URL in `<...>` when it contains parentheses or spaces
+/// (`/wiki/Rust_(language)`), which would otherwise end the link early.
+fn push_link(buf: &mut String, image: bool, text: &str, url: &str, title: &str) {
+    if image {
+        buf.push('!');
+    }
+    push_link_label(buf, &escape_link_text(text), url, title);
+}
 
-    for el in doc.root_element().children().filter_map(ElementRef::wrap) {
-        collect_nav_ids(&el, &mut set);
+/// Emit a link whose label is already markdown (e.g. a linked image).
+fn push_link_label(buf: &mut String, label: &str, url: &str, title: &str) {
+    buf.push('[');
+    buf.push_str(label);
+    buf.push_str("](");
+    let url = escape_link_url(url);
+    if url.contains(['(', ')', ' ']) {
+        buf.push('<');
+        buf.push_str(&url.replace('<', "%3C").replace('>', "%3E"));
+        buf.push('>');
+    } else {
+        buf.push_str(&url);
+    }
+    if !title.is_empty() {
+        buf.push_str(" \"");
+        buf.push_str(&escape_link_title(title));
+        buf.push('"');
     }
+    buf.push(')');
+}
 
-    set
+/// Backslash-escape quotes (and backslashes) inside a `"..."` link title.
+fn escape_link_title(title: &str) -> String {
+    escape_char_refs(&title.replace('\\', "\\\\").replace('"', "\\\"")).into_owned()
 }
 
-fn collect_nav_ids(el: &ElementRef, set: &mut HashSet<NodeId>) {
-    if should_skip(el) || is_nav_clutter(el) {
-        add_subtree(el, set);
-        return;
+/// Backslash-escape the characters that would end link text early.
+fn escape_link_text(text: &str) -> Cow<'_, str> {
+    if !text.contains(['[', ']', '\\']) {
+        return escape_char_refs(text);
     }
-    for child in el.children().filter_map(ElementRef::wrap) {
-        collect_nav_ids(&child, set);
+    let mut out = String::with_capacity(text.len() + 4);
+    for c in text.chars() {
+        if matches!(c, '[' | ']' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
     }
+    Cow::Owned(escape_char_refs(&out).into_owned())
 }
 
-fn add_subtree(el: &ElementRef, set: &mut HashSet<NodeId>) {
-    set.insert(el.id());
-    for child in el.descendants().filter_map(ElementRef::wrap) {
-        set.insert(child.id());
+/// Backslash-escape backslashes and character references in a link
+/// destination, which markdown readers unescape and decode like text.
+fn escape_link_url(url: &str) -> Cow<'_, str> {
+    if !url.contains('\\') {
+        return escape_char_refs(url);
     }
+    Cow::Owned(escape_char_refs(&url.replace('\\', "\\\\")).into_owned())
 }
 
-fn find_main_content<'a>(doc: &'a Html, skip_ids: &HashSet<NodeId>) -> Option<ElementRef<'a>> {
-    for sel in SEL_MAIN.iter() {
-        for el in doc.select(sel) {
-            if !skip_ids.contains(&el.id()) {
-                return Some(el);
+/// Backslash-escape the `&` of anything a markdown reader would decode as a
+/// character reference. The HTML parser has already decoded entities, so
+/// text left looking like `&amp;` (from `&amp;amp;`) means it literally.
+fn escape_char_refs(text: &str) -> Cow<'_, str> {
+    RE_CHAR_REF.replace_all(text, |caps: &regex::Captures| format!("\\{}", &caps[0]))
+}
+
+/// Undo backslash escapes in link text, a link URL or a link title.
+fn unescape_link_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(next) = chars.next() {
+                out.push(next);
+                continue;
             }
         }
+        out.push(c);
     }
-    None
+    out
 }
 
-// ---------------------------------------------------------------------------
-// Fallback logic (same as Python _should_fallback)
-// ---------------------------------------------------------------------------
-
-fn should_fallback(html: &str, md: &str, base_url: &str) -> bool {
-    let html_len = html.len();
-    let md_len = md.len();
-    if md_len == 0 {
-        return true;
+/// Append a single space unless the buffer already ends with whitespace.
+fn push_space(buf: &mut String) {
+    if !buf.ends_with(char::is_whitespace) {
+        buf.push(' ');
     }
-    if html_len < 5000 {
+}
+
+/// Put the content written to `buf` from `start` on between inline
+/// delimiters, moving any boundary whitespace outside them (`** world**`
+/// isn't valid emphasis). Returns whether anything other than whitespace
+/// was emitted.
+fn delimit_tail(buf: &mut String, start: usize, open: &str, close: &str) -> bool {
+    let content = &buf[start..];
+    let lead = content.len() - content.trim_start().len();
+    if lead == content.len() {
+        buf.truncate(start);
+        if lead > 0 {
+            push_space(buf);
+        }
         return false;
     }
-    if md_len < 400 {
-        return true;
+    let end = start + content.trim_end().len();
+    let trail = end < buf.len();
+    buf.truncate(end);
+    buf.push_str(close);
+    if trail {
+        buf.push(' ');
     }
-    if (md_len as f64 / html_len.max(1) as f64) < 0.01 {
-        return true;
+    // As `push_space` would have before the content
+    let space = lead > 0 && !buf[..start].ends_with(char::is_whitespace);
+    buf.replace_range(start..start + lead, open);
+    if space {
+        buf.insert(start, ' ');
     }
-    if base_url.contains("news.ycombinator.com") && !md.contains("item?id=") {
-        return true;
+    true
+}
+
+/// Replace non-breaking spaces with plain ones and drop invisible characters
+/// (zero-width spaces, word joiners, byte order marks, soft hyphens) that
+/// break tokenizers. Zero-width (non-)joiners are kept: Persian, the Indic
+/// scripts and emoji sequences need them to render correctly.
+fn normalize_text(s: &str) -> Cow<'_, str> {
+    fn replacement(c: char) -> Option<Option<char>> {
+        match c {
+            '\u{a0}' | '\u{202f}' => Some(Some(' ')),
+            '\u{200b}' | '\u{2060}' | '\u{feff}' | '\u{ad}' => Some(None),
+            // Reserved for link markers
+            LINK_OPEN | LINK_SEP | LINK_CLOSE => Some(None),
+            _ => None,
+        }
     }
-    false
+    if !s.chars().any(|c| replacement(c).is_some()) {
+        return Cow::Borrowed(s);
+    }
+    Cow::Owned(
+        s.chars()
+            .filter_map(|c| replacement(c).unwrap_or(Some(c)))
+            .collect(),
+    )
 }
 
-// ---------------------------------------------------------------------------
-// Top-level pipeline
-// ---------------------------------------------------------------------------
+/// GitHub-style anchor for a heading: lowercase, spaces to hyphens, and
+/// punctuation other than `-` and `_` dropped.
+fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() || c == '-' || c == '_' {
+            slug.push(c);
+        } else if c.is_whitespace() {
+            slug.push('-');
+        }
+    }
+    slug
+}
 
-fn run_pipeline(html: &str, base_url: &str, dedupe_tables: bool) -> PipelineResult {
-    let parsed_base: Option<Url> = if base_url.is_empty() {
-        None
-    } else {
-        Url::parse(base_url).ok()
-    };
+/// Collapse runs of whitespace to single spaces and trim the ends.
+fn collapse_whitespace(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
 
-    let doc = Html::parse_document(html);
-    let skip_ids = build_skip_set(&doc);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Alignment {
+    Left,
+    Center,
+    Right,
+}
 
-    // Find main content node
-    let main_node = find_main_content(&doc, &skip_ids);
+/// Horizontal alignment from an `align` attribute or inline `text-align`.
+fn alignment_of(el: &ElementRef) -> Option<Alignment> {
+    let style_align = el.value().attr("style").and_then(|style| {
+        style.split(';').find_map(|decl| {
+            let (prop, value) = decl.split_once(':')?;
+            (prop.trim().eq_ignore_ascii_case("text-align")).then(|| value.trim())
+        })
+    });
+    let value = style_align.or_else(|| el.value().attr("align"))?;
+    match value.trim().to_ascii_lowercase().as_str() {
+        "left" | "start" => Some(Alignment::Left),
+        "center" => Some(Alignment::Center),
+        "right" | "end" => Some(Alignment::Right),
+        _ => None,
+    }
+}
 
-    let mut walker = Walker {
-        base_url: parsed_base.clone(),
-        dedupe_tables,
-        layout_table_depth: 0,
-        skip_ids: &skip_ids,
-    };
+/// Per-column alignment declared by `<colgroup>` / `<col>` elements.
+fn column_alignments(table: &ElementRef) -> Vec<Option<Alignment>> {
+    let mut aligns = Vec::new();
+    for group in table
+        .children()
+        .filter_map(ElementRef::wrap)
+        .filter(|c| c.value().name() == "colgroup")
+    {
+        let group_align = alignment_of(&group);
+        let cols: Vec<ElementRef> = group
+            .children()
+            .filter_map(ElementRef::wrap)
+            .filter(|c| c.value().name() == "col")
+            .collect();
+        if cols.is_empty() {
+            let span = span_attr(&group, "span");
+            aligns.extend(std::iter::repeat_n(group_align, span));
+        }
+        for col in &cols {
+            let align = alignment_of(col).or(group_align);
+            aligns.extend(std::iter::repeat_n(align, span_attr(col, "span")));
+        }
+    }
+    aligns
+}
+
+/// A cell's `colspan` / `rowspan`, clamped to 1..=1000 like browsers do.
+fn span_attr(cell: &ElementRef, name: &str) -> usize {
+    cell.value()
+        .attr(name)
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .unwrap_or(1)
+        .clamp(1, 1000)
+}
 
-    let mut raw = String::with_capacity(html.len() / 4);
-    if let Some(node) = main_node {
-        walker.walk(node, &mut raw);
+/// Whether a first-row cell holds block structure that marks its table as
+/// layout: several block children, or a list or table. A single wrapping
+/// `<p>` / `<div>` is looked through, since CMSes wrap every cell in one.
+/// Nested tables are tolerated in headed tables (see `handle_table`).
+fn has_block_layout(cell: &ElementRef, headed: bool) -> bool {
+    let mut el = *cell;
+    loop {
+        let blocks: Vec<ElementRef> = el
+            .children()
+            .filter_map(ElementRef::wrap)
+            .filter(|c| BLOCK_LIKE_TAGS.contains(&c.value().name()))
+            .collect();
+        match blocks.as_slice() {
+            [] => return false,
+            [only] => match only.value().name() {
+                "ul" | "ol" => return true,
+                "table" => return !headed,
+                _ => el = *only,
+            },
+            _ => return true,
+        }
     }
+}
 
-    let raw = clean_markdown(&raw);
+/// A table's rows with `<thead>` rows first and `<tfoot>` rows last,
+/// whatever their order in the source.
+fn table_rows<'a>(table: &ElementRef<'a>) -> Vec<ElementRef<'a>> {
+    let (mut head, mut body, mut foot) = (Vec::new(), Vec::new(), Vec::new());
+    for child in table.children().filter_map(ElementRef::wrap) {
+        match child.value().name() {
+            "tr" => body.push(child),
+            "thead" => head.extend(direct_children_by_sel(&child, &SEL_TR)),
+            "tbody" => body.extend(direct_children_by_sel(&child, &SEL_TR)),
+            "tfoot" => foot.extend(direct_children_by_sel(&child, &SEL_TR)),
+            _ => {}
+        }
+    }
+    head.extend(body);
+    head.extend(foot);
+    head
+}
 
-    // Fallback: if too sparse, re-walk the entire document
-    let raw = if should_fallback(html, &raw, base_url) {
-        let empty_skip = HashSet::new();
-        let mut walker2 = Walker {
-            base_url: parsed_base.clone(),
-            dedupe_tables,
-            layout_table_depth: 0,
-            skip_ids: &empty_skip,
-        };
-        let mut full_buf = String::with_capacity(html.len() / 4);
-        // Walk root element (usually <html>)
-        let root = doc.root_element();
-        walker2.walk(root, &mut full_buf);
-        clean_markdown(&full_buf)
-    } else {
-        raw
-    };
+/// Escape rendered cell content so it can't break the markdown row: `|`
+/// becomes `\|` and line breaks become literal `<br>`.
+fn escape_table_cell(content: &str) -> String {
+    content
+        .lines()
+        .map(collapse_whitespace)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join("<br>")
+        .replace('|', "\\|")
+}
 
-    // Post-processing
-    let (links, md_with_citations) = extract_links_and_citations(&raw, &parsed_base);
-    let references = generate_references(&links);
-    let clean = clean_markdown_readable(&raw);
-    let plain = strip_links(&raw);
-    let images = extract_images(&raw);
-    let urls: Vec<String> = links.iter().map(|l| l.url.clone()).collect();
+/// Trim surrounding blank lines, keeping the first line's indentation.
+fn trim_blank_lines(s: &str) -> &str {
+    let s = s.trim_end();
+    let start = s
+        .find(|c: char| !c.is_whitespace())
+        .map_or(s.len(), |i| s[..i].rfind('\n').map_or(0, |nl| nl + 1));
+    &s[start..]
+}
 
-    let md_references = if references.is_empty() {
-        md_with_citations.clone()
-    } else {
-        format!("{}\n\n{}", md_with_citations, references)
-    };
+/// Length of the longest run of consecutive backticks in `s`.
+fn longest_backtick_run(s: &str) -> usize {
+    s.split(|c| c != '`').map(str::len).max().unwrap_or(0)
+}
 
-    PipelineResult {
-        raw_markdown: raw,
-        clean_markdown: clean,
-        markdown_with_citations: md_with_citations,
-        references_markdown: references,
-        markdown_references: md_references,
-        markdown_plain: plain,
-        links,
-        images,
-        urls,
+/// Emit an inline code span, using a longer delimiter (padded with spaces)
+/// when the text itself contains backticks.
+fn push_code_span(buf: &mut String, text: &str) {
+    let run = longest_backtick_run(text);
+    if run == 0 {
+        buf.push('`');
+        buf.push_str(text);
+        buf.push('`');
+        return;
+    }
+    let delim = "`".repeat(run + 1);
+    buf.push_str(&delim);
+    buf.push(' ');
+    buf.push_str(text);
+    buf.push(' ');
+    buf.push_str(&delim);
+}
+
+/// Language of a `<pre>` block for the fence info string, from `data-lang`
+/// or the class names used by GitHub, Prism and highlight.js.
+fn code_language(pre: &ElementRef) -> Option<String> {
+    let code = pre
+        .children()
+        .filter_map(ElementRef::wrap)
+        .find(|c| c.value().name() == "code");
+    // GitHub puts `highlight-source-*` on the wrapping <div>
+    let wrapper = pre.parent().and_then(ElementRef::wrap);
+
+    // Not `lang`, which gives the natural language of the text
+    for el in [Some(*pre), code].iter().flatten() {
+        for attr in ["data-lang", "data-language"] {
+            if let Some(lang) = el.value().attr(attr).map(str::trim) {
+                if !lang.is_empty() {
+                    return Some(lang.to_string());
+                }
+            }
+        }
+    }
+    for el in [Some(*pre), code, wrapper].iter().flatten() {
+        let classes: Vec<&str> = el.value().classes().collect();
+        for class in &classes {
+            for prefix in ["language-", "lang-", "highlight-source-", "highlight-text-"] {
+                if let Some(lang) = class.strip_prefix(prefix).filter(|l| !l.is_empty()) {
+                    return Some(lang.to_string());
+                }
+            }
+        }
+        // highlight.js: `class="hljs python"`
+        if classes.contains(&"hljs") {
+            if let Some(lang) = classes.iter().find(|c| **c != "hljs" && **c != "nohighlight") {
+                return Some(lang.to_string());
+            }
+        }
     }
+    None
 }
 
-struct PipelineResult {
-    raw_markdown: String,
-    clean_markdown: String,
-    markdown_with_citations: String,
-    references_markdown: String,
-    markdown_references: String,
-    markdown_plain: String,
-    links: Vec<LinkInfo>,
-    images: Vec<ImageInfo>,
-    urls: Vec<String>,
+/// Get raw text preserving whitespace (for <pre> blocks).
+fn get_raw_text(el: &ElementRef) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    collect_text(el, "\n", &mut parts);
+    parts.join("")
 }
 
 // ---------------------------------------------------------------------------
-// PyO3 bindings
+// Clean markdown (same rules as Python _clean_markdown)
 // ---------------------------------------------------------------------------
 
-#[pyfunction]
-#[pyo3(signature = (html, base_url="", dedupe_tables=true))]
-fn generate_markdown(py: Python<'_>, html: &str, base_url: &str, dedupe_tables: bool) -> PyResult<PyObject> {
-    let result = run_pipeline(html, base_url, dedupe_tables);
-
-    let dict = PyDict::new_bound(py);
-    dict.set_item("raw_markdown", &result.raw_markdown)?;
-    dict.set_item("clean_markdown", &result.clean_markdown)?;
-    dict.set_item("markdown_with_citations", &result.markdown_with_citations)?;
-    dict.set_item("references_markdown", &result.references_markdown)?;
-    dict.set_item("markdown_references", &result.markdown_references)?;
-    dict.set_item("markdown_plain", &result.markdown_plain)?;
+static RE_MULTI_NL: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n{3,}").unwrap());
+/// Runs of spaces after non-space text; leading indentation is left intact.
+static RE_MULTI_SP: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\S)[ \u{a0}]{2,}").unwrap());
+static RE_TRAILING_SP: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?m)[ \t]+$").unwrap());
+static RE_EMPTY_LI: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n[-*+] \n").unwrap());
+static RE_EMPTY_OL: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n\d+[.)] \n").unwrap());
+static RE_HEADER_BEFORE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n+(#{1,6})").unwrap());
+static RE_HEADER_AFTER: Lazy<Regex> = Lazy::new(|| Regex::new(r"(#{1,6}.*)\n+").unwrap());
+static RE_SETEXT_BEFORE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\n+([^\n]+\n(?:=+|-+)\n)").unwrap());
+static RE_SETEXT_AFTER: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?m)^([^\n]+\n(?:=+|-+))\n+").unwrap());
 
-    // Links
-    let links_list = PyList::empty_bound(py);
-    for link in &result.links {
-        let d = PyDict::new_bound(py);
-        d.set_item("text", &link.text)?;
-        d.set_item("url", &link.url)?;
-        d.set_item("title", &link.title)?;
-        d.set_item("citation_number", link.citation_number)?;
-        links_list.append(d)?;
-    }
-    dict.set_item("links", links_list)?;
+fn clean_markdown(md: &str) -> String {
+    let s = map_outside_fences(md, |s| {
+        let s = RE_MULTI_NL.replace_all(s, "\n\n");
+        let s = RE_MULTI_SP.replace_all(&s, "$1 ");
+        let s = RE_EMPTY_LI.replace_all(&s, "\n");
+        let s = RE_EMPTY_OL.replace_all(&s, "\n");
+        RE_TRAILING_SP.replace_all(&s, "").into_owned()
+    });
+    s.trim().to_string()
+}
 
-    // Images
-    let images_list = PyList::empty_bound(py);
-    for img in &result.images {
-        let d = PyDict::new_bound(py);
-        d.set_item("alt", &img.alt)?;
-        d.set_item("url", &img.url)?;
-        d.set_item("title", &img.title)?;
-        images_list.append(d)?;
-    }
-    dict.set_item("images", images_list)?;
+fn clean_markdown_readable(md: &str) -> String {
+    let s = map_outside_fences(md, |s| {
+        let s = RE_MULTI_NL.replace_all(s, "\n\n");
+        let s = RE_EMPTY_LI.replace_all(&s, "\n");
+        let s = RE_HEADER_BEFORE.replace_all(&s, "\n\n$1");
+        let s = RE_HEADER_AFTER.replace_all(&s, "$1\n\n");
+        let s = RE_SETEXT_BEFORE.replace_all(&s, "\n\n$1");
+        RE_SETEXT_AFTER.replace_all(&s, "$1\n\n").into_owned()
+    });
+    s.trim().to_string()
+}
 
-    // URLs
-    let urls_list = PyList::new_bound(py, &result.urls);
-    dict.set_item("urls", &urls_list)?;
+/// Blocks with less normalized text than this are never treated as repeats,
+/// so short phrases like "Read more" can recur.
+const DEDUPE_MIN_CHARS: usize = 40;
 
-    Ok(dict.into())
+/// Remove blocks whose link-free, whitespace-collapsed text matches an
+/// earlier block. Fenced code is left alone.
+fn dedupe_blocks(md: &str) -> String {
+    let seen = RefCell::new(HashSet::new());
+    let out = map_outside_fences(md, |s| {
+        let mut seen = seen.borrow_mut();
+        s.split("\n\n")
+            .filter(|block| {
+                let key = strip_links(&strip_link_markers(block))
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+                    .to_lowercase();
+                key.chars().count() < DEDUPE_MIN_CHARS || seen.insert(key)
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    });
+    out.trim().to_string()
 }
 
-#[pymodule]
-fn grub_md(m: &Bound<'_, PyModule>) -> PyResult<()> {
-    m.add_function(wrap_pyfunction!(generate_markdown, m)?)?;
-    Ok(())
+/// The text of a markdown line as the boilerplate filter compares it:
+/// links reduced to their text, block and emphasis markers and trailing
+/// punctuation dropped, lowercased.
+fn boilerplate_key(line: &str) -> String {
+    let text = strip_links(&strip_link_markers(line));
+    text.trim_start_matches(|c: char| c.is_whitespace() || "#>*_-+".contains(c))
+        .trim_end_matches(|c: char| c.is_whitespace() || "*_.!:…»→".contains(c))
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
 }
 
-// ---------------------------------------------------------------------------
-// Tests
-// ---------------------------------------------------------------------------
-
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Remove lines that are nothing but a boilerplate phrase. Lines with any
+/// other text, and fenced code, are kept.
+fn strip_boilerplate_lines(md: &str, opts: &Options) -> String {
+    let out = map_outside_fences(md, |s| {
+        s.split_inclusive('\n')
+            .filter(|line| {
+                let key = boilerplate_key(line);
+                key.is_empty()
+                    || !(opts.boilerplate_phrases.contains(&key)
+                        || opts.boilerplate_patterns.iter().any(|re| re.is_match(&key)))
+            })
+            .collect()
+    });
+    clean_markdown(&out)
+}
 
-    #[test]
-    fn test_basic_heading() {
-        let r = run_pipeline("<h1>Hello</h1><p>World</p>", "", true);
-        assert!(r.raw_markdown.contains("# Hello"));
-        assert!(r.raw_markdown.contains("World"));
+/// Apply `f` to the markdown between fenced code blocks, copying the
+/// blocks themselves (fence lines included) through untouched.
+fn map_outside_fences(md: &str, f: impl Fn(&str) -> String) -> String {
+    let mut out = String::with_capacity(md.len());
+    let mut outside = String::new();
+    // Character and length of the fence we're inside, if any
+    let mut open: Option<(char, usize)> = None;
+    for line in md.split_inclusive('\n') {
+        let marker = fence_marker(line);
+        match open {
+            None => {
+                if marker.is_some() {
+                    out.push_str(&f(&outside));
+                    outside.clear();
+                    out.push_str(line);
+                    open = marker;
+                } else {
+                    outside.push_str(line);
+                }
+            }
+            Some(fence) => {
+                out.push_str(line);
+                if closes_fence(line, fence) {
+                    open = None;
+                }
+            }
+        }
     }
+    out.push_str(&f(&outside));
+    out
+}
 
-    #[test]
-    fn test_link_extraction() {
-        let r = run_pipeline(
-            r#"<p><a href="https://example.com">Example</a></p>"#,
-            "",
-            true,
+/// Byte ranges of the code in `md`, in order: fenced blocks (fence lines
+/// included) and inline code spans (backticks included).
+fn code_ranges(md: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut open: Option<(char, usize)> = None;
+    // Start of the current line, of the open fence and of the text after
+    // the last fence
+    let (mut start, mut fence_start, mut text_start) = (0, 0, 0);
+    for line in md.split_inclusive('\n') {
+        let end = start + line.len();
+        match open {
+            None => {
+                open = fence_marker(line);
+                if open.is_some() {
+                    push_code_spans(md, text_start..start, &mut ranges);
+                    fence_start = start;
+                }
+            }
+            Some(fence) => {
+                if closes_fence(line, fence) {
+                    ranges.push(fence_start..end);
+                    open = None;
+                    text_start = end;
+                }
+            }
+        }
+        start = end;
+    }
+    if open.is_some() {
+        ranges.push(fence_start..md.len());
+    } else {
+        push_code_spans(md, text_start..md.len(), &mut ranges);
+    }
+    ranges
+}
+
+/// Add the inline code spans in `md[range]` to `ranges`. A run of backticks
+/// opens a span that the next run of the same length closes, within the
+/// paragraph; an unclosed run is literal, as is an escaped backtick.
+fn push_code_spans(md: &str, range: Range<usize>, ranges: &mut Vec<Range<usize>>) {
+    let bytes = &md.as_bytes()[..range.end];
+    let run = |at: usize| bytes[at..].iter().take_while(|&&b| b == b'`').count();
+    let mut i = range.start;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'`' => {
+                let len = run(i);
+                let mut j = i + len;
+                let close = loop {
+                    match bytes.get(j) {
+                        None => break None,
+                        Some(b'`') if run(j) == len => break Some(j + len),
+                        Some(b'`') => j += run(j),
+                        Some(b'\n') if bytes.get(j + 1) == Some(&b'\n') => break None,
+                        Some(_) => j += 1,
+                    }
+                };
+                match close {
+                    Some(end) => {
+                        ranges.push(i..end);
+                        i = end;
+                    }
+                    None => i += len,
+                }
+            }
+            _ => i += 1,
+        }
+    }
+}
+
+/// The fence character and length if `line` opens or closes a fenced code
+/// block: three or more backticks or tildes, possibly indented to sit inside
+/// a list item or quoted with `>`.
+fn fence_marker(line: &str) -> Option<(char, usize)> {
+    let rest = strip_quote_markers(line).trim_start_matches(' ');
+    let ch = rest.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = rest.chars().take_while(|c| *c == ch).count();
+    (len >= 3).then_some((ch, len))
+}
+
+/// Whether `line` closes a fenced code block opened by `fence`.
+fn closes_fence(line: &str, (ch, len): (char, usize)) -> bool {
+    fence_marker(line).is_some_and(|(c, n)| c == ch && n >= len)
+        && strip_quote_markers(line).trim().chars().all(|c| c == ch)
+}
+
+/// `line` without its blockquote markers: each `>` and the single space
+/// after it, at any nesting depth.
+fn strip_quote_markers(line: &str) -> &str {
+    let mut rest = line;
+    while let Some(inner) = rest.trim_start_matches(' ').strip_prefix('>') {
+        rest = inner.strip_prefix(' ').unwrap_or(inner);
+    }
+    rest
+}
+
+// ---------------------------------------------------------------------------
+// Post-processing: citations, references, plain, images
+// ---------------------------------------------------------------------------
+
+/// Replace each marked link with `text[n]`, taking the link details from the
+/// walk. With `dedupe_citations` every link to the same (normalized) URL
+/// shares the first occurrence's number; otherwise each occurrence gets its
+/// own.
+fn extract_links_and_citations(
+    md: &str,
+    walked: &[WalkedLink],
+    base_url: &Option<Url>,
+    opts: &Options,
+    format: &CitationFormat,
+) -> (Vec<LinkInfo>, String) {
+    let mut links: Vec<LinkInfo> = Vec::new();
+    let mut result = String::with_capacity(md.len());
+    let mut citations = Citations::new(base_url, opts);
+
+    for segment in link_segments(md) {
+        let (index, markup) = match segment {
+            Segment::Text(text) => {
+                result.push_str(text);
+                continue;
+            }
+            Segment::Link { index, markup } => (index, markup),
+        };
+        let Some(link) = walked.get(index) else {
+            result.push_str(markup);
+            continue;
+        };
+        match citations.cite(link) {
+            Some(info) => {
+                // Keep the label as written, so a linked image stays an image
+                result.push_str(&link.label);
+                result.push_str(&format.marker(info.citation_number));
+                links.push(info);
+            }
+            // Keep just the label, uncited
+            None if is_unfollowable_href(&link.info.url, opts) => result.push_str(&link.label),
+            // Left as an inline link, outside the references
+            None => result.push_str(markup),
+        }
+    }
+
+    (links, result)
+}
+
+/// Numbers links for citation in the order they are met.
+struct Citations<'a> {
+    opts: &'a Options,
+    base_host: Option<&'a str>,
+    numbers: HashMap<String, usize>,
+    next_number: usize,
+}
+
+impl<'a> Citations<'a> {
+    fn new(base_url: &'a Option<Url>, opts: &'a Options) -> Self {
+        Citations {
+            opts,
+            base_host: base_url.as_ref().and_then(|b| b.host_str()),
+            numbers: HashMap::new(),
+            next_number: 1,
+        }
+    }
+
+    /// The link as reported, numbered and placed relative to the base URL's
+    /// host. `None` if it isn't cited: an unfollowable href, or a contact
+    /// link without `cite_contact_links`.
+    fn cite(&mut self, link: &WalkedLink) -> Option<LinkInfo> {
+        let opts = self.opts;
+        let url = &link.info.url;
+        if is_unfollowable_href(url, opts)
+            || !opts.cite_contact_links && contact_kind(url).is_some()
+        {
+            return None;
+        }
+        let citation_number = if opts.dedupe_citations {
+            let next_number = &mut self.next_number;
+            let key = normalize_url(url, &[]);
+            *self.numbers.entry(key).or_insert_with(|| {
+                *next_number += 1;
+                *next_number - 1
+            })
+        } else {
+            self.next_number += 1;
+            self.next_number - 1
+        };
+
+        let host = match (self.base_host, Url::parse(url)) {
+            (Some(_), Ok(u)) => u.host_str().unwrap_or("").to_string(),
+            _ => String::new(),
+        };
+        let is_internal = match self.base_host {
+            Some(base_host) if !host.is_empty() => {
+                is_same_site(&host, base_host, opts.subdomain_policy)
+            }
+            _ => false,
+        };
+
+        Some(LinkInfo {
+            citation_number,
+            is_internal,
+            host,
+            ..link.info.clone()
+        })
+    }
+}
+
+/// A nested bullet list linking to each heading's anchor. Nesting follows
+/// the heading levels, so skipped levels don't leave gaps.
+fn generate_toc(headings: &[HeadingInfo], opts: &Options) -> String {
+    let mut toc = String::new();
+    let mut open_levels: Vec<u8> = Vec::new();
+    for heading in headings {
+        while open_levels.last().is_some_and(|&l| l >= heading.level) {
+            open_levels.pop();
+        }
+        let depth = open_levels.len();
+        open_levels.push(heading.level);
+        if depth >= opts.toc_max_depth {
+            continue;
+        }
+        toc.push_str(&"  ".repeat(depth));
+        toc.push_str(opts.style.bullet_marker);
+        toc.push(' ');
+        push_link(
+            &mut toc,
+            false,
+            &heading.text,
+            &format!("#{}", heading.anchor),
+            "",
+        );
+        toc.push('\n');
+    }
+    toc
+}
+
+/// Words in the markdown, skipping fenced code, URLs and tokens without any
+/// letters or digits (list markers, table pipes, rules).
+fn count_words(md: &str) -> usize {
+    let mut count = 0;
+    let mut open: Option<(char, usize)> = None;
+    for line in md.lines() {
+        let marker = fence_marker(line);
+        match open {
+            None if marker.is_some() => open = marker,
+            None => {
+                count += strip_links(line)
+                    .split_whitespace()
+                    .filter(|w| w.chars().any(char::is_alphanumeric))
+                    .filter(|w| !w.contains("://") && !w.starts_with("www."))
+                    .count();
+            }
+            Some((ch, len)) => {
+                let closes = marker.is_some_and(|(c, n)| c == ch && n >= len)
+                    && strip_quote_markers(line).trim().chars().all(|c| c == ch);
+                if closes {
+                    open = None;
+                }
+            }
+        }
+    }
+    count
+}
+
+/// Every distinct `mailto:` / `tel:` link in the marked markdown, in order.
+fn extract_contacts(md: &str, walked: &[WalkedLink]) -> Vec<ContactInfo> {
+    let mut contacts: Vec<ContactInfo> = Vec::new();
+    for segment in link_segments(md) {
+        let Segment::Link { index, .. } = segment else {
+            continue;
+        };
+        let Some(link) = walked.get(index) else {
+            continue;
+        };
+        let url = &link.info.url;
+        let Some(kind) = contact_kind(url) else {
+            continue;
+        };
+        let address = url.trim().split_once(':').map_or("", |(_, rest)| rest);
+        let value = address.split('?').next().unwrap_or("").to_string();
+        if value.is_empty() || contacts.iter().any(|c| c.kind == kind && c.value == value) {
+            continue;
+        }
+        contacts.push(ContactInfo {
+            kind: kind.to_string(),
+            value,
+            text: link.info.text.clone(),
+        });
+    }
+    contacts
+}
+
+/// Text, URL and title of a `RE_LINK` / `RE_IMAGE` match, with the markdown
+/// escaping undone.
+fn link_parts(caps: &regex::Captures) -> (String, String, String) {
+    let text = caps.get(1).map_or("", |c| c.as_str());
+    let url = caps
+        .get(2)
+        .or_else(|| caps.get(3))
+        .map_or("", |c| c.as_str());
+    let title = caps.get(4).map_or("", |c| c.as_str());
+    (
+        plain_link_text(text),
+        unescape_link_text(url),
+        unescape_link_text(title),
+    )
+}
+
+/// Captured link text as plain text: images inside it become their alt text
+/// and escapes are undone.
+fn plain_link_text(text: &str) -> String {
+    if !text.contains("![") {
+        return unescape_link_text(text);
+    }
+    let alts = RE_IMAGE.replace_all(text, |caps: &regex::Captures| {
+        escape_link_text(&link_parts(caps).0).into_owned()
+    });
+    unescape_link_text(&alts)
+}
+
+/// How citation markers and reference entries are written.
+struct CitationFormat {
+    style: CitationStyle,
+    /// Prepended to footnote labels when the content already uses `[^n]`.
+    footnote_prefix: String,
+}
+
+impl CitationFormat {
+    fn new(style: CitationStyle, md: &str) -> Self {
+        let mut footnote_prefix = String::new();
+        if style == CitationStyle::Footnote {
+            let existing: HashSet<&str> = RE_FOOTNOTE_LABEL
+                .captures_iter(md)
+                .filter_map(|c| c.get(1).map(|m| m.as_str()))
+                .collect();
+            // Pick a prefix no existing label starts with
+            let mut attempt = 0;
+            while existing.iter().any(|l| l.starts_with(&footnote_prefix)) {
+                attempt += 1;
+                footnote_prefix = format!("cite{}-", attempt);
+            }
+        }
+        CitationFormat {
+            style,
+            footnote_prefix,
+        }
+    }
+
+    /// `[n]` or `[^n]`, appended to the link text.
+    fn marker(&self, n: usize) -> String {
+        match self.style {
+            CitationStyle::Bracket => format!("[{}]", n),
+            CitationStyle::Footnote => format!("[^{}{}]", self.footnote_prefix, n),
+        }
+    }
+}
+
+fn generate_references(links: &[LinkInfo], format: &CitationFormat) -> String {
+    if links.is_empty() {
+        return String::new();
+    }
+    let mut refs = match format.style {
+        CitationStyle::Bracket => String::from("## References\n"),
+        CitationStyle::Footnote => String::new(),
+    };
+    let mut emitted = HashSet::new();
+    // Links sharing a number are listed once, with the first one's title
+    for link in links.iter().filter(|l| emitted.insert(l.citation_number)) {
+        refs.push_str(&format!(
+            "{}: {}",
+            format.marker(link.citation_number),
+            escape_link_url(&link.url)
+        ));
+        if !link.title.is_empty() {
+            refs.push_str(&format!(" \"{}\"", escape_link_title(&link.title)));
+        }
+        refs.push('\n');
+    }
+    refs
+}
+
+fn strip_links(md: &str) -> String {
+    // Replace ![alt](url) with alt, then [text](url) with text; code keeps
+    // its brackets
+    let s = strip_images(md);
+    let code = code_ranges(&s);
+    let s = RE_LINK.replace_all(&s, |caps: &regex::Captures| plain_link(caps, &code));
+    s.to_string()
+}
+
+/// Replace each `![alt](url)` outside code with its alt text.
+fn strip_images(md: &str) -> Cow<'_, str> {
+    let code = code_ranges(md);
+    RE_IMAGE.replace_all(md, |caps: &regex::Captures| plain_link(caps, &code))
+}
+
+/// The text of a link or image match, or the match as it stands when it
+/// starts inside one of the `code` ranges.
+fn plain_link(caps: &regex::Captures, code: &[Range<usize>]) -> String {
+    let m = caps.get(0).unwrap();
+    let i = code.partition_point(|r| r.end <= m.start());
+    if code.get(i).is_some_and(|r| r.start <= m.start()) {
+        m.as_str().to_string()
+    } else {
+        link_parts(caps).0
+    }
+}
+
+/// `strip_links(md).chars().count()`, without building the stripped text.
+fn plain_char_count(md: &str) -> usize {
+    let s = strip_images(md);
+    let code = code_ranges(&s);
+    let mut count = 0;
+    let mut last = 0;
+    for caps in RE_LINK.captures_iter(&s) {
+        let m = caps.get(0).unwrap();
+        count += s[last..m.start()].chars().count() + plain_link(&caps, &code).chars().count();
+        last = m.end();
+    }
+    count + s[last..].chars().count()
+}
+
+/// The `references_markdown` of a conversion: its links, numbered as in
+/// `markdown_with_citations`.
+fn references_markdown(raw: &str, links: &[LinkInfo], style: CitationStyle) -> String {
+    generate_references(links, &CitationFormat::new(style, raw))
+}
+
+/// The `markdown_references` of a conversion: the cited markdown followed by
+/// its references.
+fn markdown_references(md_with_citations: &str, references: &str) -> String {
+    if references.is_empty() {
+        md_with_citations.to_string()
+    } else {
+        format!("{}\n\n{}", md_with_citations, references)
+    }
+}
+
+/// Images in the markdown, with URLs resolved against `base_url` so they are
+/// absolute whenever links are.
+fn extract_images(
+    md: &str,
+    base_url: &Option<Url>,
+    image_meta: &HashMap<String, ImageMeta>,
+) -> Vec<ImageInfo> {
+    RE_IMAGE
+        .captures_iter(md)
+        .map(|caps| {
+            let (alt, url, title) = link_parts(&caps);
+            let meta = image_meta.get(&url).cloned().unwrap_or_default();
+            let sizes: Vec<u32> = meta.width.into_iter().chain(meta.height).collect();
+            ImageInfo {
+                alt,
+                format: image_format(&url),
+                url: resolve_url(&url, base_url),
+                title,
+                caption: meta.caption,
+                candidates: meta.candidates,
+                width: meta.width,
+                height: meta.height,
+                is_probably_icon: !sizes.is_empty() && sizes.iter().all(|&px| px <= 32),
+            }
+        })
+        .collect()
+}
+
+/// Effective base URL: the document's first `<base href>` (joined against the
+/// caller's base when relative) wins, as in browsers.
+fn document_base(doc: &Html, base: Option<Url>) -> Option<Url> {
+    let href = match doc
+        .select(&SEL_BASE)
+        .next()
+        .and_then(|b| b.value().attr("href"))
+    {
+        Some(h) if !h.trim().is_empty() => h.trim(),
+        _ => return base,
+    };
+    let joined = match &base {
+        Some(b) => b.join(href),
+        None => Url::parse(href),
+    };
+    joined.ok().or(base)
+}
+
+// ---------------------------------------------------------------------------
+// Main content detection
+// ---------------------------------------------------------------------------
+
+/// The node IDs of every `keep_selectors` match and its descendants.
+fn build_keep_set(doc: &Html, opts: &Options) -> HashSet<NodeId> {
+    let mut set = HashSet::new();
+    for keep in &opts.keep_selectors {
+        for el in doc.select(&keep.selector) {
+            set.extend(
+                el.descendants()
+                    .filter(|n| n.value().is_element())
+                    .map(|n| n.id()),
+            );
+        }
+    }
+    set
+}
+
+/// Whether a class or id word names a consent / newsletter / paywall overlay
+/// (`onetrust-consent-sdk`, `newsletter_modal`, …).
+fn is_overlay_candidate(el: &ElementRef) -> bool {
+    let class = el.value().attr("class").unwrap_or("");
+    let id = el.value().attr("id").unwrap_or("");
+    class
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .chain(id.split(|c: char| !c.is_ascii_alphanumeric()))
+        .any(|word| OVERLAY_WORDS.iter().any(|w| w.eq_ignore_ascii_case(word)))
+}
+
+/// Non-whitespace characters of text under `el`.
+fn text_len(el: &ElementRef) -> usize {
+    el.text()
+        .flat_map(str::chars)
+        .filter(|c| !c.is_whitespace())
+        .count()
+}
+
+/// The overlay subtrees, which every walk skips.
+fn build_overlay_set(
+    doc: &Html,
+    keep_ids: &HashSet<NodeId>,
+    opts: &Options,
+    diagnostics: &mut Diagnostics,
+) -> SkipSet {
+    let mut set = SkipSet::default();
+    if !opts.remove_overlays {
+        return set;
+    }
+    // The page's text is only counted once a candidate turns up
+    let max_len = std::cell::OnceCell::new();
+    let max_len =
+        || *max_len.get_or_init(|| text_len(&doc.root_element()) as f64 * OVERLAY_MAX_TEXT_SHARE);
+    let mut stack = vec![doc.root_element()];
+    while let Some(el) = stack.pop() {
+        if keep_ids.contains(&el.id()) {
+            continue;
+        }
+        if is_overlay_candidate(&el) && (text_len(&el) as f64) < max_len() {
+            set.add(&el);
+            diagnostics.overlay_nodes += subtree_size(&el, keep_ids);
+            continue;
+        }
+        stack.extend(el.children().filter_map(ElementRef::wrap));
+    }
+    set
+}
+
+/// A comment thread: `class`/`id` naming one of `COMMENT_SECTION_NAMES`, or
+/// `<section aria-label="Comments">`.
+fn is_comment_section(el: &ElementRef) -> bool {
+    let v = el.value();
+    let named = v
+        .attr("class")
+        .unwrap_or("")
+        .split_whitespace()
+        .chain(v.attr("id").map(str::trim))
+        .any(|name| COMMENT_SECTION_NAMES.contains(&name.to_ascii_lowercase().as_str()));
+    named
+        || (v.name() == "section"
+            && v.attr("aria-label")
+                .is_some_and(|l| l.trim().eq_ignore_ascii_case("comments")))
+}
+
+/// The outermost comment threads, in document order.
+fn find_comment_sections(doc: &Html) -> Vec<ElementRef<'_>> {
+    let mut found = Vec::new();
+    let mut stack = vec![doc.root_element()];
+    while let Some(el) = stack.pop() {
+        if is_comment_section(&el) {
+            found.push(el);
+            continue;
+        }
+        let children: Vec<_> = el.children().filter_map(ElementRef::wrap).collect();
+        stack.extend(children.into_iter().rev());
+    }
+    found
+}
+
+/// Subtrees a walk leaves out, recorded by their roots: membership is
+/// checked up the ancestor chain instead of expanding each subtree into a
+/// set of every node in it.
+#[derive(Clone, Default)]
+struct SkipSet {
+    /// Each root, and whether kept elements under it are exempt.
+    roots: HashMap<NodeId, bool>,
+}
+
+impl SkipSet {
+    /// Skip `el` and its descendants, less any kept ones.
+    fn add(&mut self, el: &ElementRef) {
+        self.roots.entry(el.id()).or_insert(true);
+    }
+
+    /// Skip `el` and all its descendants, kept ones included.
+    fn add_all(&mut self, el: &ElementRef) {
+        self.roots.insert(el.id(), false);
+    }
+
+    fn extend(&mut self, other: &SkipSet) {
+        for (&id, &exempt) in &other.roots {
+            let entry = self.roots.entry(id).or_insert(exempt);
+            *entry &= exempt;
+        }
+    }
+
+    /// Whether a skipped subtree starts at `el`. A top-down walk only meets
+    /// skipped elements at their roots or as kept elements, so this is all
+    /// it needs to check.
+    fn is_root(&self, el: &ElementRef) -> bool {
+        self.roots.contains_key(&el.id())
+    }
+
+    /// Whether `el` lies in a skipped subtree.
+    fn contains(&self, el: &ElementRef, keep_ids: &HashSet<NodeId>) -> bool {
+        if self.roots.is_empty() {
+            return false;
+        }
+        let kept = keep_ids.contains(&el.id());
+        std::iter::once(**el).chain(el.ancestors()).any(|a| {
+            self.roots
+                .get(&a.id())
+                .is_some_and(|exempt| !(kept && *exempt))
+        })
+    }
+
+    #[cfg(feature = "tracing")]
+    fn len(&self) -> usize {
+        self.roots.len()
+    }
+}
+
+/// Find the nav/clutter subtrees so the walker can skip them, adding them to
+/// the overlay set. Kept nodes never enter the set, so a kept element
+/// inside a clutter ancestor survives with its whole subtree.
+fn build_skip_set(
+    doc: &Html,
+    keep_ids: &HashSet<NodeId>,
+    overlays: &SkipSet,
+    diagnostics: &mut Diagnostics,
+) -> SkipSet {
+    let mut set = overlays.clone();
+    let root = doc.root_element();
+    let mut stack: Vec<_> = if set.is_root(&root) {
+        Vec::new()
+    } else {
+        root.children().filter_map(ElementRef::wrap).collect()
+    };
+    while let Some(el) = stack.pop() {
+        if keep_ids.contains(&el.id()) || set.is_root(&el) {
+            continue;
+        }
+        if should_skip(&el) {
+            set.add(&el);
+            diagnostics.hidden_nodes += subtree_size(&el, keep_ids);
+        } else if is_nav_clutter(&el) {
+            set.add(&el);
+            diagnostics.clutter_nodes += subtree_size(&el, keep_ids);
+        } else {
+            stack.extend(el.children().filter_map(ElementRef::wrap));
+        }
+    }
+    set
+}
+
+/// How many of `el` and its descendant elements aren't kept.
+fn subtree_size(el: &ElementRef, keep_ids: &HashSet<NodeId>) -> usize {
+    el.descendants()
+        .filter(|n| n.value().is_element() && !keep_ids.contains(&n.id()))
+        .count()
+}
+
+/// The content root and the selector that found it: the first match of the
+/// caller's `content_selectors`, else (unless disabled) the `MAIN_SELECTORS`
+/// match short of `body` with the most non-link text, else the readability
+/// scorer's pick, else `body`.
+fn find_main_content<'a>(
+    doc: &'a Html,
+    skip_ids: &SkipSet,
+    keep_ids: &HashSet<NodeId>,
+    opts: &Options,
+) -> Option<(ElementRef<'a>, String)> {
+    let skipped = |el: &ElementRef| skip_ids.contains(el, keep_ids);
+    let first_match = |sel: &Selector| doc.select(sel).find(|el| !skipped(el));
+
+    for custom in &opts.content_selectors {
+        if let Some(el) = first_match(&custom.selector) {
+            return Some((el, custom.source.clone()));
+        }
+    }
+    let defaults = || {
+        SEL_MAIN
+            .iter()
+            .zip(MAIN_SELECTORS.iter().copied())
+            .filter(|_| opts.default_content_selectors)
+    };
+    // Ties go to the earlier selector
+    let mut best: Option<(ElementRef, &str, usize)> = None;
+    for (sel, name) in defaults().filter(|(_, name)| *name != "body") {
+        for el in doc.select(sel).filter(|el| !skipped(el)) {
+            let (total, linked) = text_lengths(&el, skip_ids, keep_ids);
+            let len = total - linked;
+            if best.as_ref().is_none_or(|(_, _, best_len)| len > *best_len) {
+                best = Some((el, name, len));
+            }
+        }
+    }
+    if let Some((el, name, _)) = best {
+        return Some((el, name.to_string()));
+    }
+    if opts.use_readability_fallback {
+        if let Some(el) = readability_candidate(doc, skip_ids, keep_ids) {
+            return Some((el, "readability".to_string()));
+        }
+    }
+    for (sel, name) in defaults().filter(|(_, name)| *name == "body") {
+        if let Some(el) = first_match(sel) {
+            return Some((el, name.to_string()));
+        }
+    }
+    None
+}
+
+/// Paragraphs shorter than this don't vote for a content root.
+const READABILITY_MIN_PARAGRAPH: usize = 25;
+
+/// Readability-style scoring: each paragraph scores one point, one per comma
+/// and one per 100 characters (up to three), credited in full to its parent
+/// and half to its grandparent. Each block's total is then scaled down by
+/// its link density. `body` and `html` never win.
+fn readability_candidate<'a>(
+    doc: &'a Html,
+    skip_ids: &SkipSet,
+    keep_ids: &HashSet<NodeId>,
+) -> Option<ElementRef<'a>> {
+    let mut scores: HashMap<NodeId, f64> = HashMap::new();
+    for p in doc.select(&SEL_P) {
+        if skip_ids.contains(&p, keep_ids) {
+            continue;
+        }
+        let text: String = p.text().collect();
+        let len = text.trim().chars().count();
+        if len < READABILITY_MIN_PARAGRAPH {
+            continue;
+        }
+        let score = 1.0 + text.matches(',').count() as f64 + (len as f64 / 100.0).min(3.0);
+        let mut ancestors = p.ancestors().filter_map(ElementRef::wrap);
+        for share in [1.0, 0.5] {
+            match ancestors.next() {
+                Some(a) if !matches!(a.value().name(), "body" | "html") => {
+                    *scores.entry(a.id()).or_default() += score * share;
+                }
+                _ => break,
+            }
+        }
+    }
+
+    scores
+        .into_iter()
+        .filter_map(|(id, score)| {
+            let el = ElementRef::wrap(doc.tree.get(id)?)?;
+            let (total, linked) = text_lengths(&el, skip_ids, keep_ids);
+            let density = linked as f64 / total.max(1) as f64;
+            Some((el, score * (1.0 - density)))
+        })
+        .filter(|(_, score)| *score > 0.0)
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(el, _)| el)
+}
+
+/// Non-whitespace text under `el` outside skipped subtrees, and how much of
+/// that sits inside links.
+fn text_lengths(el: &ElementRef, skip_ids: &SkipSet, keep_ids: &HashSet<NodeId>) -> (usize, usize) {
+    let mut total = 0;
+    let mut linked = 0;
+    for node in el.descendants() {
+        let Node::Text(text) = node.value() else {
+            continue;
+        };
+        let parent_skipped = node
+            .parent()
+            .and_then(ElementRef::wrap)
+            .is_some_and(|p| skip_ids.contains(&p, keep_ids));
+        if parent_skipped {
+            continue;
+        }
+        let len = text.chars().filter(|c| !c.is_whitespace()).count();
+        total += len;
+        let in_link = node
+            .ancestors()
+            .take_while(|a| a.id() != el.id())
+            .filter_map(ElementRef::wrap)
+            .any(|a| a.value().name() == "a");
+        if in_link {
+            linked += len;
+        }
+    }
+    (total, linked)
+}
+
+/// `tag.class1.class2` for diagnostics.
+fn describe_element(el: &ElementRef) -> String {
+    let mut out = el.value().name().to_string();
+    for class in el.value().attr("class").unwrap_or("").split_whitespace() {
+        out.push('.');
+        out.push_str(class);
+    }
+    out
+}
+
+// ---------------------------------------------------------------------------
+// Fallback logic (same as Python _should_fallback)
+// ---------------------------------------------------------------------------
+
+/// Fewest letters `detect_language` will guess from.
+const DETECT_MIN_LETTERS: usize = 20;
+
+/// A coarse guess from the writing system of most letters in `text`:
+/// `ja` when kana appear, `ko` for Hangul, otherwise an undetermined
+/// language in the dominant script (`und-Latn`, `und-Cyrl`, `und-Arab`,
+/// `und-Hani`).
+fn detect_language(text: &str) -> Option<&'static str> {
+    let (mut latin, mut cyrillic, mut arabic, mut han, mut kana, mut hangul) = (0, 0, 0, 0, 0, 0);
+    for c in text.chars().filter(|c| c.is_alphabetic()) {
+        match c {
+            'a'..='z' | 'A'..='Z' | '\u{c0}'..='\u{24f}' => latin += 1,
+            '\u{400}'..='\u{52f}' => cyrillic += 1,
+            '\u{600}'..='\u{6ff}' | '\u{750}'..='\u{77f}' => arabic += 1,
+            '\u{3040}'..='\u{30ff}' => kana += 1,
+            '\u{4e00}'..='\u{9fff}' | '\u{3400}'..='\u{4dbf}' => han += 1,
+            '\u{ac00}'..='\u{d7af}' | '\u{1100}'..='\u{11ff}' => hangul += 1,
+            _ => {}
+        }
+    }
+    let total: usize = latin + cyrillic + arabic + han + kana + hangul;
+    if total < DETECT_MIN_LETTERS {
+        return None;
+    }
+    // Japanese mixes kanji with kana, so the two count together
+    let counts = [
+        (kana + if kana > 0 { han } else { 0 }, "ja"),
+        (hangul, "ko"),
+        (latin, "und-Latn"),
+        (cyrillic, "und-Cyrl"),
+        (arabic, "und-Arab"),
+        (if kana > 0 { 0 } else { han }, "und-Hani"),
+    ];
+    counts
+        .iter()
+        .max_by_key(|(count, _)| *count)
+        .map(|(_, tag)| *tag)
+}
+
+/// Markdown bytes per HTML byte.
+fn content_ratio(html: &str, md: &str) -> f64 {
+    md.len() as f64 / html.len().max(1) as f64
+}
+
+/// Which check sent the pipeline back to walk the whole document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallbackReason {
+    /// The content root produced no markdown.
+    Empty,
+    /// Under `min_md_len` bytes of markdown from a large page.
+    TooShort,
+    /// Markdown under `min_ratio` of the HTML size.
+    LowRatio,
+    /// A `site_rules` marker missing from the markdown.
+    SiteSpecific,
+}
+
+impl FallbackReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FallbackReason::Empty => "empty",
+            FallbackReason::TooShort => "too_short",
+            FallbackReason::LowRatio => "low_ratio",
+            FallbackReason::SiteSpecific => "site_specific",
+        }
+    }
+}
+
+/// The walks tried in turn until one yields content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalkStage {
+    /// The content root, with clutter and hidden elements skipped.
+    #[default]
+    ContentRoot,
+    /// The whole document, skipping only hidden elements and overlays.
+    Unfiltered,
+    /// The whole document with nothing but `SKIP_TAGS` skipped, for pages
+    /// whose content is all hidden until scripts run.
+    Raw,
+}
+
+impl WalkStage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WalkStage::ContentRoot => "content_root",
+            WalkStage::Unfiltered => "unfiltered",
+            WalkStage::Raw => "raw",
+        }
+    }
+}
+
+/// A site whose layout defeats content detection: on `host` (or any of its
+/// subdomains) the content root's markdown must contain `required`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SiteRule {
+    pub host: String,
+    pub required: String,
+}
+
+impl SiteRule {
+    fn matches_host(&self, host: &str) -> bool {
+        let pattern = self.host.trim_start_matches("*.").to_ascii_lowercase();
+        let host = host.to_ascii_lowercase();
+        host == pattern
+            || host
+                .strip_suffix(pattern.as_str())
+                .is_some_and(|rest| rest.ends_with('.'))
+    }
+}
+
+/// The first site rule for this page whose marker the markdown lacks.
+fn broken_site_rule<'a>(
+    md: &str,
+    base_url: &Option<Url>,
+    opts: &'a Options,
+) -> Option<&'a SiteRule> {
+    let host = base_url.as_ref()?.host_str()?;
+    opts.site_rules
+        .iter()
+        .find(|rule| rule.matches_host(host) && !md.contains(&rule.required))
+}
+
+/// Why the markdown from the content root is too sparse to use, if it is.
+fn fallback_reason(
+    html: &str,
+    md: &str,
+    base_url: &Option<Url>,
+    opts: &Options,
+) -> Option<FallbackReason> {
+    let html_len = html.len();
+    let md_len = md.len();
+    if md_len == 0 {
+        return Some(FallbackReason::Empty);
+    }
+    if html_len < opts.min_html_len {
+        return None;
+    }
+    if md_len < opts.min_md_len {
+        return Some(FallbackReason::TooShort);
+    }
+    if content_ratio(html, md) < opts.min_ratio {
+        return Some(FallbackReason::LowRatio);
+    }
+    if broken_site_rule(md, base_url, opts).is_some() {
+        return Some(FallbackReason::SiteSpecific);
+    }
+    None
+}
+
+// ---------------------------------------------------------------------------
+// Errors
+// ---------------------------------------------------------------------------
+
+/// The parts of a conversion, for naming where one failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipelineStage {
+    /// Parsing the HTML and the base URL.
+    Parse,
+    /// Finding the content root and walking it to markdown.
+    Walk,
+    /// Citations, metadata, images, chunks and the other derived fields.
+    PostProcess,
+}
+
+impl PipelineStage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PipelineStage::Parse => "parse",
+            PipelineStage::Walk => "walk",
+            PipelineStage::PostProcess => "post_process",
+        }
+    }
+}
+
+/// Why a checked conversion (`try_run_pipeline`, the Python module) failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// `base_url` isn't an absolute URL; only an error under `strict`.
+    InvalidBaseUrl { base_url: String, reason: String },
+    /// The conversion panicked, which is a bug; reported rather than
+    /// unwound into the caller.
+    Panic {
+        stage: PipelineStage,
+        message: String,
+    },
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidBaseUrl { base_url, reason } => {
+                write!(f, "invalid base_url {:?}: {}", base_url, reason)
+            }
+            Error::Panic { stage, message } => {
+                write!(
+                    f,
+                    "conversion failed in the {} stage: {}",
+                    stage.as_str(),
+                    message
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+thread_local! {
+    /// The stage of the conversion running on this thread, for `catch_panic`.
+    static STAGE: Cell<PipelineStage> = const { Cell::new(PipelineStage::Parse) };
+}
+
+fn enter_stage(stage: PipelineStage) {
+    STAGE.with(|s| s.set(stage));
+}
+
+/// Run `convert`, turning a panic into `Error::Panic` with the stage it
+/// happened in.
+fn catch_panic<T>(convert: impl FnOnce() -> T) -> Result<T, Error> {
+    enter_stage(PipelineStage::Parse);
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(convert)).map_err(|payload| {
+        Error::Panic {
+            stage: STAGE.with(Cell::get),
+            message: panic_message(payload.as_ref()),
+        }
+    })
+}
+
+/// `base_url` parsed, or `None` when it's empty.
+fn check_base_url(base_url: &str) -> Result<Option<Url>, Error> {
+    if base_url.is_empty() {
+        return Ok(None);
+    }
+    Url::parse(base_url)
+        .map(Some)
+        .map_err(|e| Error::InvalidBaseUrl {
+            base_url: base_url.to_string(),
+            reason: e.to_string(),
+        })
+}
+
+/// Run one conversion of a document at `base_url`: refused when `strict`
+/// and the base URL is unusable, and with panics caught.
+fn run_checked<T>(base_url: &str, opts: &Options, convert: impl FnOnce() -> T) -> Result<T, Error> {
+    if opts.strict {
+        check_base_url(base_url)?;
+    }
+    catch_panic(convert)
+}
+
+// ---------------------------------------------------------------------------
+// Top-level pipeline
+// ---------------------------------------------------------------------------
+
+/// Convert an HTML document with no base URL, so relative links stay
+/// relative.
+///
+/// ```
+/// use grub_md::Options;
+///
+/// let html = "<html><body><article><h1>Hello</h1><p>World</p></article></body></html>";
+/// let result = grub_md::convert(html, &Options::default());
+/// assert_eq!(result.raw_markdown, "# Hello\n\nWorld");
+/// ```
+pub fn convert(html: &str, opts: &Options) -> PipelineResult {
+    run_pipeline(html, "", opts)
+}
+
+/// Convert an HTML document, resolving its links and images against
+/// `base_url` (or the document's `<base href>`).
+///
+/// ```
+/// use grub_md::{run_pipeline, CitationStyle, Options};
+///
+/// let opts = Options {
+///     citation_style: CitationStyle::Footnote,
+///     ..Options::default()
+/// };
+/// let html = r#"<p>See <a href="/docs">the docs</a>.</p>"#;
+/// let result = run_pipeline(html, "https://example.com/", &opts);
+/// assert_eq!(result.links[0].url, "https://example.com/docs");
+/// assert!(result.markdown_with_citations.contains("[^1]"));
+/// ```
+pub fn run_pipeline(html: &str, base_url: &str, opts: &Options) -> PipelineResult {
+    convert_document(html, base_url, opts, false, Variants::Eager)
+}
+
+/// `run_pipeline`, failing on an unusable `base_url` when `opts.strict` is
+/// set, and with a panic inside the pipeline returned as an error naming
+/// the stage instead of unwinding.
+///
+/// ```
+/// use grub_md::{try_run_pipeline, Error, Options};
+///
+/// let opts = Options { strict: true, ..Options::default() };
+/// let err = try_run_pipeline("<p>Hi</p>", "not a url", &opts).unwrap_err();
+/// assert!(matches!(err, Error::InvalidBaseUrl { .. }));
+/// ```
+pub fn try_run_pipeline(
+    html: &str,
+    base_url: &str,
+    opts: &Options,
+) -> Result<PipelineResult, Error> {
+    run_checked(base_url, opts, || run_pipeline(html, base_url, opts))
+}
+
+/// Convert an HTML snippet as a whole: no content-root detection, clutter
+/// filtering or fallback, just hidden and non-content elements skipped.
+pub fn run_fragment_pipeline(html: &str, base_url: &str, opts: &Options) -> PipelineResult {
+    convert_document(html, base_url, opts, true, Variants::Eager)
+}
+
+/// Convert each element matching `selector` on its own, as a fragment. A
+/// match nested in another match is converted as part of the outer one.
+pub fn run_selection_pipeline(
+    html: &str,
+    selector: &Selector,
+    base_url: &str,
+    opts: &Options,
+) -> Vec<PipelineResult> {
+    let doc = Html::parse_document(html);
+    // The fragments lose any <base>, so resolve it here
+    let base = document_base(&doc, parse_base_url(base_url))
+        .map(String::from)
+        .unwrap_or_default();
+    let matches: Vec<ElementRef> = doc.select(selector).collect();
+    let ids: HashSet<NodeId> = matches.iter().map(|m| m.id()).collect();
+    matches
+        .iter()
+        .filter(|m| !m.ancestors().any(|a| ids.contains(&a.id())))
+        .map(|m| run_fragment_pipeline(&m.html(), &base, opts))
+        .collect()
+}
+
+/// The markdown from the content walk (after any fallback walks and the
+/// block filters) and what the walker recorded on the way.
+struct ContentWalk<'a> {
+    main_node: Option<ElementRef<'a>>,
+    /// `marked` keeps the link markers for the citation pass.
+    marked: String,
+    raw: String,
+    comments_markdown: String,
+    links: Vec<WalkedLink>,
+    image_meta: HashMap<String, ImageMeta>,
+    extra_images: Vec<ImageInfo>,
+    media: Vec<MediaInfo>,
+    headings: Vec<HeadingInfo>,
+    tables: Vec<TableInfo>,
+    table_count: usize,
+    code_block_count: usize,
+    /// Whether `max_depth`, `max_nodes` or `max_output_bytes` cut the
+    /// markdown short.
+    truncated: bool,
+}
+
+fn parse_base_url(base_url: &str) -> Option<Url> {
+    check_base_url(base_url).ok().flatten()
+}
+
+/// What the walks of a document leave out, and where the content walk
+/// starts.
+struct WalkPlan<'a> {
+    keep_ids: HashSet<NodeId>,
+    comment_sections: Vec<ElementRef<'a>>,
+    comment_ids: SkipSet,
+    /// Overlays and comment threads, left out of every walk but the raw one.
+    always_skip: SkipSet,
+    /// Everything the content walk leaves out.
+    skip_ids: SkipSet,
+    main_node: Option<ElementRef<'a>>,
+}
+
+/// Build the skip sets and find the content root. A fragment is its own
+/// root, with only overlays and comments skipped.
+fn plan_walk<'a>(
+    doc: &'a Html,
+    opts: &Options,
+    fragment: bool,
+    diagnostics: &mut Diagnostics,
+) -> WalkPlan<'a> {
+    enter_stage(PipelineStage::Walk);
+    let (keep_ids, comment_sections, comment_ids, always_skip, skip_ids) = {
+        span!("skip_set");
+        let keep_ids = build_keep_set(doc, opts);
+        let comment_sections = if opts.extract_comments {
+            find_comment_sections(doc)
+        } else {
+            Vec::new()
+        };
+        let mut comment_ids = SkipSet::default();
+        for section in &comment_sections {
+            comment_ids.add_all(section);
+        }
+        // Overlays and comment threads stay out of every walk but the raw
+        // one, which still leaves out comments
+        let mut always_skip = build_overlay_set(doc, &keep_ids, opts, diagnostics);
+        always_skip.extend(&comment_ids);
+        let skip_ids = if fragment {
+            always_skip.clone()
+        } else {
+            build_skip_set(doc, &keep_ids, &always_skip, diagnostics)
+        };
+        event!(
+            skipped_subtrees = skip_ids.len(),
+            kept_nodes = keep_ids.len(),
+            clutter_nodes = diagnostics.clutter_nodes,
+            hidden_nodes = diagnostics.hidden_nodes,
+            overlay_nodes = diagnostics.overlay_nodes,
+            "built skip set"
+        );
+        (
+            keep_ids,
+            comment_sections,
+            comment_ids,
+            always_skip,
+            skip_ids,
+        )
+    };
+
+    // Find main content node
+    let main_node = if fragment {
+        Some(doc.root_element())
+    } else {
+        span!("main_content");
+        let found = find_main_content(doc, &skip_ids, &keep_ids, opts).map(|(node, selector)| {
+            diagnostics.main_selector = selector;
+            diagnostics.main_node = describe_element(&node);
+            node
+        });
+        event!(
+            selector = %diagnostics.main_selector,
+            node = %diagnostics.main_node,
+            "found content root"
+        );
+        found
+    };
+
+    WalkPlan {
+        keep_ids,
+        comment_sections,
+        comment_ids,
+        always_skip,
+        skip_ids,
+        main_node,
+    }
+}
+
+/// Find the content root and walk it, falling back to whole-document walks
+/// when the result is too sparse. A fragment is walked whole, with neither.
+fn walk_content<'a>(
+    doc: &'a Html,
+    html: &str,
+    parsed_base: &Option<Url>,
+    opts: &Options,
+    fragment: bool,
+    diagnostics: &mut Diagnostics,
+) -> ContentWalk<'a> {
+    let plan = plan_walk(doc, opts, fragment, diagnostics);
+    walk_planned(doc, html, parsed_base, opts, fragment, plan, diagnostics)
+}
+
+/// `walk_content` with the skip sets and content root already worked out.
+fn walk_planned<'a>(
+    doc: &'a Html,
+    html: &str,
+    parsed_base: &Option<Url>,
+    opts: &Options,
+    fragment: bool,
+    plan: WalkPlan<'a>,
+    diagnostics: &mut Diagnostics,
+) -> ContentWalk<'a> {
+    let WalkPlan {
+        keep_ids,
+        comment_sections,
+        comment_ids,
+        always_skip,
+        skip_ids,
+        main_node,
+    } = plan;
+
+    let mut walker = Walker::new(parsed_base.clone(), opts, &skip_ids, &keep_ids);
+
+    let mut buf = String::with_capacity(html.len() / 4);
+    let (mut marked, mut raw) = {
+        span!("walk");
+        if let Some(node) = main_node {
+            walker.walk(node, &mut buf);
+        }
+        walker.truncated |= cap_markdown(&mut buf, opts.max_output_bytes);
+        let marked = clean_markdown(&buf);
+        let raw = strip_link_markers(&marked);
+        event!(
+            markdown_bytes = raw.len(),
+            links = walker.links.len(),
+            headings = walker.headings.len(),
+            "walked content root"
+        );
+        (marked, raw)
+    };
+
+    // Fallback: if too sparse, re-walk the entire document
+    if !fragment {
+        diagnostics.fallback_reason = fallback_reason(html, &raw, parsed_base, opts);
+    }
+    if diagnostics.fallback_reason == Some(FallbackReason::SiteSpecific) {
+        diagnostics.site_rule = broken_site_rule(&raw, parsed_base, opts).cloned();
+    }
+    // Stage one keeps nav content, since that filter may be what
+    // over-pruned; only an empty result goes on to the raw walk.
+    for stage in [WalkStage::Unfiltered, WalkStage::Raw] {
+        let needed = match stage {
+            WalkStage::Unfiltered => diagnostics.fallback_reason.is_some(),
+            _ => raw.is_empty(),
+        };
+        if !needed {
+            break;
+        }
+        span!("fallback", stage = stage.as_str());
+        let skip = if stage == WalkStage::Raw {
+            &comment_ids
+        } else {
+            &always_skip
+        };
+        walker = Walker::new(parsed_base.clone(), opts, skip, &keep_ids);
+        walker.skip_hidden = stage != WalkStage::Raw;
+        let mut full_buf = String::with_capacity(html.len() / 4);
+        // Walk root element (usually <html>)
+        let root = doc.root_element();
+        walker.walk(root, &mut full_buf);
+        walker.truncated |= cap_markdown(&mut full_buf, opts.max_output_bytes);
+        marked = clean_markdown(&full_buf);
+        raw = strip_link_markers(&marked);
+        diagnostics.stage = stage;
+        event!(
+            reason = diagnostics.fallback_reason.map(FallbackReason::as_str),
+            markdown_bytes = raw.len(),
+            "walked whole document"
+        );
+    }
+    if opts.dedupe_blocks {
+        marked = dedupe_blocks(&marked);
+        raw = strip_link_markers(&marked);
+    }
+    if opts.strip_boilerplate {
+        marked = strip_boilerplate_lines(&marked, opts);
+        raw = strip_link_markers(&marked);
+    }
+
+    let no_skip = SkipSet::default();
+    let mut comments_walker = Walker::new(parsed_base.clone(), opts, &no_skip, &keep_ids);
+    let mut comments_buf = String::new();
+    for section in &comment_sections {
+        comments_walker.walk(*section, &mut comments_buf);
+        comments_buf.push_str("\n\n");
+    }
+
+    // Whatever the caller makes of the walk from here is post-processing
+    enter_stage(PipelineStage::PostProcess);
+    ContentWalk {
+        main_node,
+        marked,
+        raw,
+        comments_markdown: strip_link_markers(&clean_markdown(&comments_buf)),
+        links: walker.links,
+        image_meta: walker.image_meta,
+        extra_images: walker.extra_images,
+        media: walker.media,
+        headings: walker.headings,
+        tables: walker.tables,
+        table_count: walker.table_count,
+        code_block_count: walker.code_block_count,
+        truncated: walker.truncated || comments_walker.truncated,
+    }
+}
+
+/// Cut marked markdown down to `max` bytes, dropping a link the cut would
+/// split. Returns whether anything was cut.
+fn cap_markdown(md: &mut String, max: usize) -> bool {
+    if md.len() <= max {
+        return false;
+    }
+    let mut end = max;
+    while !md.is_char_boundary(end) {
+        end -= 1;
+    }
+    md.truncate(end);
+    if let Some(open) = md.rfind(LINK_OPEN) {
+        if !md[open..].contains(LINK_CLOSE) {
+            md.truncate(open);
+        }
+    }
+    true
+}
+
+/// The links of the content root, as `run_pipeline` reports them, from a
+/// single walk: the markdown is never cleaned, deduplicated or checked for
+/// a fallback. So links in blocks `dedupe_blocks` or `strip_boilerplate`
+/// would drop are kept, and a sparse root isn't swapped for the whole
+/// document (which is walked only when no root is found).
+/// `same_host_only` keeps links on the base URL's host.
+pub fn run_link_pipeline(
+    html: &str,
+    base_url: &str,
+    opts: &Options,
+    same_host_only: bool,
+) -> Vec<LinkInfo> {
+    let doc = Html::parse_document(html);
+    let parsed_base = document_base(&doc, parse_base_url(base_url));
+    let mut diagnostics = Diagnostics::default();
+    let plan = plan_walk(&doc, opts, false, &mut diagnostics);
+    let (root, skip) = match plan.main_node {
+        Some(node) => (node, &plan.skip_ids),
+        None => (doc.root_element(), &plan.always_skip),
+    };
+    let mut walker = Walker::new(parsed_base.clone(), opts, skip, &plan.keep_ids);
+    let mut buf = String::with_capacity(html.len() / 4);
+    walker.walk(root, &mut buf);
+    cap_markdown(&mut buf, opts.max_output_bytes);
+    enter_stage(PipelineStage::PostProcess);
+
+    // The markers left in the markdown, in order, are the links it shows
+    let mut citations = Citations::new(&parsed_base, opts);
+    let mut links: Vec<LinkInfo> = link_segments(&buf)
+        .into_iter()
+        .filter_map(|segment| match segment {
+            Segment::Link { index, .. } => walker.links.get(index),
+            Segment::Text(_) => None,
+        })
+        .filter_map(|link| citations.cite(link))
+        .collect();
+    if same_host_only {
+        let base_host = parsed_base
+            .as_ref()
+            .and_then(|b| b.host_str())
+            .unwrap_or("");
+        links.retain(|l| l.host.eq_ignore_ascii_case(base_host));
+    }
+    links
+}
+
+/// Just `markdown_plain`, without the metadata, citation, image or chunking
+/// passes. The content root is walked with links written as their text.
+/// When that walk hits a limit, or might call for a fallback, the full
+/// walks of `run_pipeline` are used instead.
+pub fn run_text_pipeline(html: &str, base_url: &str, opts: &Options) -> String {
+    let doc = Html::parse_document(html);
+    let parsed_base = document_base(&doc, parse_base_url(base_url));
+    let mut diagnostics = Diagnostics::default();
+    let plan = plan_walk(&doc, opts, false, &mut diagnostics);
+    if let Some(text) = walk_text(html, &parsed_base, opts, &plan) {
+        return text;
+    }
+    let content = walk_planned(
+        &doc,
+        html,
+        &parsed_base,
+        opts,
+        false,
+        plan,
+        &mut diagnostics,
+    );
+    strip_links(&content.raw)
+}
+
+/// The text of the content root, or `None` when `walk_planned` could do
+/// something else with it: cut it at a limit, or replace it with a
+/// fallback walk.
+fn walk_text(
+    html: &str,
+    parsed_base: &Option<Url>,
+    opts: &Options,
+    plan: &WalkPlan,
+) -> Option<String> {
+    let root = plan.main_node?;
+    let mut walker = Walker::new(parsed_base.clone(), opts, &plan.skip_ids, &plan.keep_ids);
+    walker.links_as_text = true;
+    let mut buf = String::with_capacity(html.len() / 4);
+    walker.walk(root, &mut buf);
+    if walker.truncated || buf.len() > opts.max_output_bytes {
+        return None;
+    }
+    let mut text = clean_markdown(&buf);
+    // Links written as text are shorter than as markdown, so the size checks
+    // pass for the markdown whenever they pass here. Site rules look for
+    // strings that may only be in link URLs.
+    let host = parsed_base.as_ref().and_then(|b| b.host_str());
+    let site_rule = host.is_some_and(|h| opts.site_rules.iter().any(|r| r.matches_host(h)));
+    if site_rule || fallback_reason(html, &text, parsed_base, opts).is_some() {
+        return None;
+    }
+    if opts.dedupe_blocks {
+        text = dedupe_blocks(&text);
+    }
+    if opts.strip_boilerplate {
+        text = strip_boilerplate_lines(&text, opts);
+    }
+    Some(strip_images(&text).into_owned())
+}
+
+/// Convert each `(html, base_url)` pair in parallel, in input order, each
+/// checked as by `try_run_pipeline`: a panic while converting one document
+/// becomes that document's error instead of taking down the batch.
+fn run_batch<F>(
+    docs: &[(String, String)],
+    opts: &Options,
+    convert: F,
+) -> Vec<Result<PipelineResult, Error>>
+where
+    F: Fn(&str, &str) -> PipelineResult + Sync,
+{
+    docs.par_iter()
+        .map(|(html, base_url)| run_checked(base_url, opts, || convert(html, base_url)))
+        .collect()
+}
+
+/// Convert `(html, base_url)` pairs in parallel with `try_run_pipeline`, in
+/// input order.
+pub fn convert_batch(
+    docs: &[(String, String)],
+    opts: &Options,
+) -> Vec<Result<PipelineResult, Error>> {
+    run_batch(docs, opts, |html, base_url| {
+        run_pipeline(html, base_url, opts)
+    })
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "conversion panicked".to_string()
+    }
+}
+
+/// The data tables `run_pipeline` would emit, cell by cell.
+pub fn run_table_pipeline(html: &str, base_url: &str, opts: &Options) -> Vec<TableInfo> {
+    let doc = Html::parse_document(html);
+    let parsed_base = document_base(&doc, parse_base_url(base_url));
+    let mut diagnostics = Diagnostics::default();
+    walk_content(&doc, html, &parsed_base, opts, false, &mut diagnostics).tables
+}
+
+/// Whether `convert_document` builds the markdown variants that are derived
+/// from the rest of the result.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Variants {
+    Eager,
+    /// `references_markdown`, `markdown_references` and (unless language
+    /// detection needed it) `markdown_plain` are left empty, for the caller
+    /// to build on demand. Only the Python `MarkdownResult` does.
+    #[cfg_attr(not(feature = "python"), allow(dead_code))]
+    Deferred,
+}
+
+fn convert_document(
+    html: &str,
+    base_url: &str,
+    opts: &Options,
+    fragment: bool,
+    variants: Variants,
+) -> PipelineResult {
+    span!("convert", html_bytes = html.len(), fragment);
+    let doc = {
+        span!("parse");
+        let doc = if fragment {
+            Html::parse_fragment(html)
+        } else {
+            Html::parse_document(html)
+        };
+        event!(nodes = doc.tree.nodes().len(), "parsed");
+        doc
+    };
+    let given_base = parse_base_url(base_url);
+    let mut diagnostics = Diagnostics {
+        base_url_usable: given_base.is_some(),
+        ..Diagnostics::default()
+    };
+    let parsed_base = document_base(&doc, given_base);
+    let mut content = walk_content(&doc, html, &parsed_base, opts, fragment, &mut diagnostics);
+    let marked = std::mem::take(&mut content.marked);
+    let raw = std::mem::take(&mut content.raw);
+
+    let (title, metadata, open_graph, link_relations, json_ld) = {
+        span!("metadata");
+        let title = metadata::extract_title(&doc, content.main_node, opts.strip_title_suffix);
+        let metadata = metadata::extract_metadata(&doc);
+        let open_graph = metadata::extract_open_graph(&doc, &parsed_base);
+        let link_relations = metadata::extract_link_relations(&doc, &parsed_base);
+        let json_ld = if opts.extract_json_ld {
+            metadata::extract_json_ld(&doc)
+        } else {
+            Vec::new()
+        };
+        event!(
+            title_chars = title.chars().count(),
+            open_graph = open_graph.len(),
+            json_ld = json_ld.len(),
+            "extracted metadata"
+        );
+        (title, metadata, open_graph, link_relations, json_ld)
+    };
+
+    // Post-processing
+    let (links, md_with_citations) = {
+        span!("citations");
+        let citation_format = CitationFormat::new(opts.citation_style, &raw);
+        let (links, md_with_citations) = extract_links_and_citations(
+            &marked,
+            &content.links,
+            &parsed_base,
+            opts,
+            &citation_format,
+        );
+        event!(
+            links = links.len(),
+            markdown_bytes = md_with_citations.len(),
+            "numbered citations"
+        );
+        (links, md_with_citations)
+    };
+    let clean = clean_markdown_readable(&raw);
+    let mut plain = match variants {
+        Variants::Eager => strip_links(&raw),
+        Variants::Deferred => String::new(),
+    };
+    let images = {
+        span!("images");
+        let mut images = extract_images(&raw, &parsed_base, &content.image_meta);
+        images.append(&mut content.extra_images);
+        event!(images = images.len(), "collected images");
+        images
+    };
+    let mut urls: Vec<String> = links.iter().map(|l| l.url.clone()).collect();
+    if opts.normalize_urls {
+        let mut seen = HashSet::new();
+        urls.retain(|u| seen.insert(u.clone()));
+    }
+    let contacts = extract_contacts(&marked, &content.links);
+    let toc = generate_toc(&content.headings, opts);
+    let chunks = {
+        span!("chunks");
+        let chunks = chunks::chunk_markdown(&clean, opts.max_chunk_chars, opts.min_chunk_chars);
+        event!(chunks = chunks.len(), clean_bytes = clean.len(), "chunked");
+        chunks
+    };
+
+    let word_count = count_words(&raw);
+    let mut headings_by_level = [0; 6];
+    for heading in &content.headings {
+        headings_by_level[heading.level as usize - 1] += 1;
+    }
+    let stats = ContentStats {
+        word_count,
+        char_count: match variants {
+            Variants::Eager => plain.chars().count(),
+            Variants::Deferred => plain_char_count(&raw),
+        },
+        link_count: links.len(),
+        image_count: images.len(),
+        table_count: content.table_count,
+        code_block_count: content.code_block_count,
+        headings_by_level,
+        reading_time_minutes: word_count.div_ceil(200),
+        content_ratio: content_ratio(html, &raw),
+    };
+    event!(
+        words = stats.word_count,
+        markdown_bytes = raw.len(),
+        stage = diagnostics.stage.as_str(),
+        "converted"
+    );
+
+    let (references, md_references) = match variants {
+        Variants::Eager => {
+            let references = references_markdown(&raw, &links, opts.citation_style);
+            let md_references = markdown_references(&md_with_citations, &references);
+            (references, md_references)
+        }
+        Variants::Deferred => (String::new(), String::new()),
+    };
+
+    let html_comments = if opts.collect_comments {
+        doc.root_element()
+            .descendants()
+            .filter_map(|node| match node.value() {
+                Node::Comment(c) => Some(c.trim().to_string()),
+                _ => None,
+            })
+            .filter(|c| !c.is_empty())
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let mut language = metadata::extract_language(&doc);
+    if language.is_empty() {
+        if plain.is_empty() {
+            plain = strip_links(&raw);
+        }
+        language = detect_language(&plain).unwrap_or_default().to_string();
+    }
+
+    PipelineResult {
+        title,
+        language,
+        metadata,
+        open_graph,
+        json_ld,
+        link_relations,
+        raw_markdown: raw,
+        comments_markdown: content.comments_markdown,
+        html_comments,
+        clean_markdown: clean,
+        markdown_with_citations: md_with_citations,
+        references_markdown: references,
+        markdown_references: md_references,
+        markdown_plain: plain,
+        toc_markdown: toc,
+        links,
+        images,
+        media: content.media,
+        headings: content.headings,
+        chunks,
+        stats,
+        diagnostics,
+        truncated: content.truncated,
+        contacts,
+        urls,
+    }
+}
+
+/// Everything one conversion produces.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PipelineResult {
+    pub title: String,
+    /// Declared language tag, else a script guess such as `ja` or
+    /// `und-Cyrl` from `detect_language`.
+    pub language: String,
+    pub metadata: metadata::PageMetadata,
+    /// `og:*` / `twitter:*` properties in document order, all values kept.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serde_impls::open_graph"))]
+    pub open_graph: Vec<(String, Vec<String>)>,
+    /// Raw JSON text of each ld+json script (empty unless requested).
+    pub json_ld: Vec<String>,
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub link_relations: metadata::LinkRelations,
+    pub raw_markdown: String,
+    pub clean_markdown: String,
+    pub markdown_with_citations: String,
+    pub references_markdown: String,
+    pub markdown_references: String,
+    pub markdown_plain: String,
+    /// Nested list of links to the heading anchors.
+    pub toc_markdown: String,
+    /// Comment threads, when `extract_comments` is on.
+    pub comments_markdown: String,
+    /// Trimmed text of each HTML comment, when `collect_comments` is on.
+    pub html_comments: Vec<String>,
+    pub links: Vec<LinkInfo>,
+    pub images: Vec<ImageInfo>,
+    pub media: Vec<MediaInfo>,
+    pub headings: Vec<HeadingInfo>,
+    /// Sections of `clean_markdown` sized for embedding.
+    pub chunks: Vec<chunks::Chunk>,
+    pub stats: ContentStats,
+    pub diagnostics: Diagnostics,
+    /// Whether `max_depth`, `max_nodes` or `max_output_bytes` cut the
+    /// markdown short.
+    pub truncated: bool,
+    pub contacts: Vec<ContactInfo>,
+    pub urls: Vec<String>,
+}
+
+/// Serialization that doesn't follow the struct layout: the shapes here
+/// match what the Python dict has always had.
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use serde::ser::{SerializeMap, SerializeSeq, SerializeStruct, Serializer};
+    use serde::Serialize;
+
+    use super::{Diagnostics, FallbackReason, NamedSelector, Regex, SiteRule};
+
+    /// Single-valued properties as a string, repeated ones as a list.
+    pub(super) fn open_graph<S: Serializer>(
+        og: &[(String, Vec<String>)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(og.len()))?;
+        for (key, values) in og {
+            match values.as_slice() {
+                [value] => map.serialize_entry(key, value)?,
+                _ => map.serialize_entry(key, values)?,
+            }
+        }
+        map.end()
+    }
+
+    /// `{"h1": 1, "h2": 3, …}`.
+    pub(super) fn headings_by_level<S: Serializer>(
+        counts: &[usize; 6],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(counts.len()))?;
+        for (i, n) in counts.iter().enumerate() {
+            map.serialize_entry(&format!("h{}", i + 1), n)?;
+        }
+        map.end()
+    }
+
+    pub(super) fn patterns<S: Serializer>(
+        patterns: &[Regex],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(patterns.iter().map(Regex::as_str))
+    }
+
+    /// `[host, required]` pairs, as `site_rules` is given.
+    pub(super) fn site_rules<S: Serializer>(
+        rules: &[SiteRule],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(rules.len()))?;
+        for rule in rules {
+            seq.serialize_element(&(&rule.host, &rule.required))?;
+        }
+        seq.end()
+    }
+
+    impl Serialize for NamedSelector {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(&self.source)
+        }
+    }
+
+    impl Serialize for Diagnostics {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut d = serializer.serialize_struct("Diagnostics", 10)?;
+            d.serialize_field("base_url_usable", &self.base_url_usable)?;
+            d.serialize_field("main_selector", &self.main_selector)?;
+            d.serialize_field("main_node", &self.main_node)?;
+            d.serialize_field("fallback", &self.fallback_reason.is_some())?;
+            d.serialize_field("stage", self.stage.as_str())?;
+            d.serialize_field(
+                "fallback_reason",
+                &self.fallback_reason.map(FallbackReason::as_str),
+            )?;
+            d.serialize_field("site_rule", &self.site_rule)?;
+            d.serialize_field("clutter_nodes", &self.clutter_nodes)?;
+            d.serialize_field("hidden_nodes", &self.hidden_nodes)?;
+            d.serialize_field("overlay_nodes", &self.overlay_nodes)?;
+            d.end()
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Tests
+// ---------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basic_heading() {
+        let r = run_pipeline("<h1>Hello</h1><p>World</p>", "", &Options::default());
+        assert!(r.raw_markdown.contains("# Hello"));
+        assert!(r.raw_markdown.contains("World"));
+    }
+
+    #[test]
+    fn test_link_extraction() {
+        let r = run_pipeline(
+            r#"<p><a href="https://example.com">Example</a></p>"#,
+            "",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.contains("[Example](https://example.com)"));
+        assert_eq!(r.links.len(), 1);
+        assert_eq!(r.links[0].url, "https://example.com");
+        assert_eq!(r.links[0].citation_number, 1);
+    }
+
+    #[test]
+    fn test_relative_url_resolution() {
+        let r = run_pipeline(
+            r#"<p><a href="/page">Link</a></p>"#,
+            "https://example.com",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.contains("https://example.com/page"));
+    }
+
+    #[test]
+    fn test_image() {
+        let r = run_pipeline(
+            r#"<img src="test.png" alt="Test Image" title="A test">"#,
+            "",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.contains("![Test Image](test.png \"A test\")"));
+        assert_eq!(r.images.len(), 1);
+    }
+
+    #[test]
+    fn test_skip_script_style() {
+        let r = run_pipeline(
+            "<p>Keep</p><script>bad()</script><style>.x{}</style><p>Also keep</p>",
+            "",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.contains("Keep"));
+        assert!(r.raw_markdown.contains("Also keep"));
+        assert!(!r.raw_markdown.contains("bad()"));
+        assert!(!r.raw_markdown.contains(".x{}"));
+    }
+
+    #[test]
+    fn test_code_and_pre() {
+        let r = run_pipeline(
+            "<p>Use <code>foo()</code> and:</p><pre>bar()\nbaz()</pre>",
+            "",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.contains("`foo()`"));
+        assert!(r.raw_markdown.contains("```\nbar()\nbaz()\n```"));
+    }
+
+    #[test]
+    fn test_table_data() {
+        let r = run_pipeline(
+            "<table><tr><th>Name</th><th>Age</th></tr><tr><td>Alice</td><td>30</td></tr></table>",
+            "",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.contains("| Name | Age |"));
+        assert!(r.raw_markdown.contains("| --- | --- |"));
+        assert!(r.raw_markdown.contains("| Alice | 30 |"));
+    }
+
+    #[test]
+    fn test_empty_input() {
+        let r = run_pipeline("", "", &Options::default());
+        assert!(r.raw_markdown.is_empty());
+    }
+
+    #[test]
+    fn test_plain_strips_links() {
+        let r = run_pipeline(
+            r#"<p><a href="https://example.com">Click</a> here</p>"#,
+            "",
+            &Options::default(),
+        );
+        assert!(r.markdown_plain.contains("Click"));
+        assert!(!r.markdown_plain.contains("example.com"));
+    }
+
+    #[test]
+    fn test_citations() {
+        let r = run_pipeline(
+            r#"<p><a href="https://a.com">A</a> and <a href="https://b.com">B</a></p>"#,
+            "",
+            &Options::default(),
+        );
+        assert!(r.markdown_with_citations.contains("A[1]"));
+        assert!(r.markdown_with_citations.contains("B[2]"));
+        assert!(r.references_markdown.contains("[1]: https://a.com"));
+        assert!(r.references_markdown.contains("[2]: https://b.com"));
+    }
+
+    #[test]
+    fn test_main_content_detection() {
+        let html = r#"
+            <html><body>
+                <nav><a href="/home">Home</a></nav>
+                <main><h1>Main Title</h1><p>Main content</p></main>
+                <footer>Footer stuff</footer>
+            </body></html>
+        "#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("Main Title"));
+        assert!(r.raw_markdown.contains("Main content"));
+        // Nav and footer should be filtered out
+        assert!(!r.raw_markdown.contains("Home"));
+        assert!(!r.raw_markdown.contains("Footer stuff"));
+    }
+
+    #[test]
+    fn test_fallback_sparse() {
+        // Large HTML but tiny main content → should trigger fallback. The
+        // padding is link text so `main` still has the most content.
+        let padding = "<div><a href=\"#\">x</a></div>".repeat(500);
+        let html = format!(
+            "<html><body><main><p>tiny</p></main><article>{}</article></body></html>",
+            padding
+        );
+        let r = run_pipeline(&html, "", &Options::default());
+        // Fallback should include the repeated text
+        assert!(r.raw_markdown.contains("x"));
+        assert_eq!(r.diagnostics.main_selector, "main");
+        assert_eq!(
+            r.diagnostics.fallback_reason,
+            Some(FallbackReason::TooShort)
+        );
+        assert_eq!(r.diagnostics.stage, WalkStage::Unfiltered);
+    }
+
+    #[test]
+    fn test_staged_fallback() {
+        // Stage one brings back nav content but still skips hidden elements
+        let html = "<body><main></main><nav><a href=\"/docs\">Docs</a></nav>\
+            <p>Loose text</p><div hidden><p>Secret</p></div></body>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.diagnostics.stage, WalkStage::Unfiltered);
+        assert!(r.raw_markdown.contains("[Docs](/docs)"));
+        assert!(r.raw_markdown.contains("Loose text"));
+        assert!(!r.raw_markdown.contains("Secret"));
+
+        // Content that is all hidden until scripts run needs the raw walk
+        let html = "<body><main></main><div style=\"display:none\"><p>Rendered later</p></div>\
+            <script>show()</script></body>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.diagnostics.stage, WalkStage::Raw);
+        assert_eq!(r.raw_markdown, "Rendered later");
+
+        let r = run_pipeline(
+            "<body><main><p>Fine</p></main></body>",
+            "",
+            &Options::default(),
+        );
+        assert_eq!(r.diagnostics.stage, WalkStage::ContentRoot);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let html = "<html><head><script>var x;</script></head><body>\
+            <nav><a href=\"/\">Home</a><a href=\"/about\">About</a></nav>\
+            <div class=\"content\"><p>Body text</p><p hidden>secret</p></div></body></html>";
+        let r = run_pipeline(html, "", &Options::default());
+        let d = &r.diagnostics;
+        assert_eq!(d.main_selector, ".content");
+        assert_eq!(d.fallback_reason, None);
+        assert_eq!(d.clutter_nodes, 3);
+        assert_eq!(d.hidden_nodes, 2);
+
+        let r = run_pipeline(
+            "<html><body><main></main></body></html>",
+            "",
+            &Options::default(),
+        );
+        assert_eq!(r.diagnostics.fallback_reason, Some(FallbackReason::Empty));
+    }
+
+    #[test]
+    fn test_hidden_removed() {
+        let html = r#"<p>Visible</p><span class="sr-only">Hidden</span><div hidden>Also hidden</div>"#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("Visible"));
+        assert!(!r.raw_markdown.contains("Hidden"));
+        assert!(!r.raw_markdown.contains("Also hidden"));
+    }
+
+    #[test]
+    fn test_definition_list() {
+        let html = r#"<dl>
+            <dt>HTML</dt><dd>Markup, see <a href="https://html.spec.whatwg.org/">the spec</a></dd>
+            <div><dt>CSS</dt><dd>Style sheets</dd></div>
+        </dl>"#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("**HTML**\n  Markup, see"));
+        assert!(r
+            .raw_markdown
+            .contains("[the spec](https://html.spec.whatwg.org/)"));
+        assert!(r.raw_markdown.contains("**CSS**\n  Style sheets"));
+        assert_eq!(r.links.len(), 1);
+        assert_eq!(r.links[0].url, "https://html.spec.whatwg.org/");
+    }
+
+    #[test]
+    fn test_definition_list_extended() {
+        let opts = Options {
+            definition_list_style: DefinitionListStyle::Extended,
+            ..Options::default()
+        };
+        let r = run_pipeline(
+            "<dl><dt>Term</dt><dd>First</dd><dd>Second</dd></dl>",
+            "",
+            &opts,
+        );
+        assert!(r.raw_markdown.contains("Term\n: First\n: Second"));
+    }
+
+    #[test]
+    fn test_horizontal_rule() {
+        let r = run_pipeline("<p>a</p><hr><p>b</p>", "", &Options::default());
+        assert_eq!(r.raw_markdown, "a\n\n---\n\nb");
+
+        let r = run_pipeline(
+            "<main><hr><h2>Title</h2><p>x</p></main>",
+            "",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.starts_with("---\n\n## Title"));
+        assert!(!r.raw_markdown.contains("\n\n\n"));
+    }
+
+    #[test]
+    fn test_nested_lists() {
+        let html = "<ul>\
+            <li>One<ol><li>Two<ul><li>Three</li></ul></li><li>Two b</li></ol></li>\
+            <li>Four</li>\
+        </ul>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r
+            .raw_markdown
+            .contains("- One\n  1. Two\n     - Three\n  2. Two b\n- Four"));
+    }
+
+    #[test]
+    fn test_strikethrough() {
+        let r = run_pipeline(
+            r#"<p>Price: <del>$10</del> <s><a href="https://a.com">old</a></s></p>"#,
+            "",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.contains("~~$10~~"));
+        assert!(r.raw_markdown.contains("~~[old](https://a.com)~~"));
+
+        let r = run_pipeline("<p>a<strike></strike>b</p>", "", &Options::default());
+        assert_eq!(r.raw_markdown, "ab");
+
+        let r = run_pipeline(
+            "<p><strong><del>gone</del></strong></p>",
+            "",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.contains("**~~gone~~**"));
+    }
+
+    #[test]
+    fn test_sup_sub() {
+        let html = "<p>x<sup>2</sup> and H<sub>2</sub>O</p>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("x^2^"));
+        assert!(r.raw_markdown.contains("H~2~O"));
+
+        let opts = Options {
+            script_style: ScriptStyle::Parens,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert!(r.raw_markdown.contains("x^(2)"));
+        assert!(r.raw_markdown.contains("H_(2)O"));
+    }
+
+    #[test]
+    fn test_sup_footnote_link() {
+        let html = r##"<p>Claim<sup><a href="#fn1">1</a></sup></p>"##;
+        let r = run_pipeline(html, "https://example.com/page", &Options::default());
+        assert!(r.raw_markdown.contains("Claim^1^"));
+        assert!(r.links.is_empty());
+
+        let opts = Options {
+            resolve_fragment_links: true,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "https://example.com/page", &opts);
+        assert!(r
+            .raw_markdown
+            .contains("Claim^[1](https://example.com/page#fn1)^"));
+        assert_eq!(r.links.len(), 1);
+        assert_eq!(r.links[0].url, "https://example.com/page#fn1");
+    }
+
+    #[test]
+    fn test_invalid_option_value() {
+        let err = "fancy".parse::<ScriptStyle>().unwrap_err();
+        assert!(err.contains("script_style"));
+        assert!(err.contains("extended, parens"));
+    }
+
+    #[test]
+    fn test_figure_caption() {
+        let html = r#"<figure>
+            <img src="/photo.jpg" alt="Harbor">
+            <figcaption>Credit: <a href="https://photos.example.com">Jane</a></figcaption>
+        </figure>"#;
+        let r = run_pipeline(html, "https://example.com", &Options::default());
+        assert!(r.raw_markdown.contains(
+            "![Harbor](https://example.com/photo.jpg)\n\n*Credit: [Jane](https://photos.example.com/)*"
+        ));
+        assert_eq!(r.images.len(), 1);
+        assert_eq!(r.images[0].caption, "Credit: Jane");
+        assert_eq!(r.links.len(), 1);
+    }
+
+    #[test]
+    fn test_figure_without_image() {
+        let r = run_pipeline(
+            "<figure><blockquote>Quote</blockquote><figcaption>Someone</figcaption></figure>",
+            "",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.contains("> Quote"));
+        assert!(r.raw_markdown.contains("Someone"));
+        assert!(!r.raw_markdown.contains("*Someone*"));
+    }
+
+    #[test]
+    fn test_video_and_audio() {
+        let html = r#"
+            <video poster="/poster.jpg" title="Launch recap">
+                <source src="/launch.mp4" type="video/mp4">
+            </video>
+            <audio src="/episode.mp3"></audio>
+        "#;
+        let r = run_pipeline(html, "https://example.com", &Options::default());
+        assert!(r
+            .raw_markdown
+            .contains("[Launch recap](https://example.com/launch.mp4)"));
+        assert!(r
+            .raw_markdown
+            .contains("[Audio](https://example.com/episode.mp3)"));
+
+        assert_eq!(r.media.len(), 2);
+        assert_eq!(r.media[0].kind, "video");
+        assert_eq!(r.media[0].poster, "https://example.com/poster.jpg");
+        assert_eq!(r.media[1].kind, "audio");
+        assert!(r.media[1].poster.is_empty());
+
+        assert_eq!(r.images.len(), 1);
+        assert_eq!(r.images[0].url, "https://example.com/poster.jpg");
+    }
+
+    #[test]
+    fn test_video_figcaption_text() {
+        let html =
+            r#"<figure><video src="clip.webm"></video><figcaption>Demo</figcaption></figure>"#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("[Demo](clip.webm)"));
+    }
+
+    #[test]
+    fn test_srcset_parsing() {
+        let c =
+            parse_srcset("a.jpg 480w, https://cdn.example.com/w_800,h_600/b.jpg 800w,c.jpg 1200w");
+        let urls: Vec<&str> = c.iter().map(|c| c.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "a.jpg",
+                "https://cdn.example.com/w_800,h_600/b.jpg",
+                "c.jpg"
+            ]
+        );
+        assert_eq!(c[1].width, Some(800));
+        assert_eq!(largest_candidate(&c).unwrap().url, "c.jpg");
+
+        let c = parse_srcset("small.png, big.png 2x, mid.png 1.5x");
+        assert_eq!(largest_candidate(&c).unwrap().url, "big.png");
+    }
+
+    #[test]
+    fn test_picture_source_srcset() {
+        let html = r#"<picture>
+            <source srcset="/hero-800.webp 800w, /hero-1600.webp 1600w" type="image/webp">
+            <img src="data:image/gif;base64,R0lGODlhAQABAAAAACw=" srcset="/hero-1x.jpg 1x, /hero-2x.jpg 2x" alt="Hero">
+        </picture>"#;
+        let r = run_pipeline(html, "https://example.com", &Options::default());
+        assert!(r
+            .raw_markdown
+            .contains("![Hero](https://example.com/hero-1600.webp)"));
+        assert!(!r.raw_markdown.contains("data:"));
+        assert_eq!(r.images.len(), 1);
+        assert_eq!(r.images[0].candidates.len(), 4);
+        assert_eq!(
+            r.images[0].candidates[0],
+            "https://example.com/hero-800.webp"
+        );
+    }
+
+    #[test]
+    fn test_lazy_loaded_images() {
+        let html = r#"
+            <img src="data:image/gif;base64,R0lGODlhAQABAAAAACw=" data-src="/real.jpg" alt="Lazy">
+            <img data-srcset="/s.jpg 400w, /l.jpg 1200w" alt="Lazy set">
+            <img src="/img/spacer.gif" data-original="/full.jpg" alt="Original">
+            <img src="/thumb.jpg" data-src="/full.jpg" alt="Real">
+        "#;
+        let r = run_pipeline(html, "https://example.com", &Options::default());
+        assert!(r
+            .raw_markdown
+            .contains("![Lazy](https://example.com/real.jpg)"));
+        assert!(r
+            .raw_markdown
+            .contains("![Lazy set](https://example.com/l.jpg)"));
+        assert!(r
+            .raw_markdown
+            .contains("![Original](https://example.com/full.jpg)"));
+        assert!(!r.raw_markdown.contains("data:"));
+        // A real src isn't overridden
+        assert!(r
+            .raw_markdown
+            .contains("![Real](https://example.com/thumb.jpg)"));
+        assert!(is_placeholder_image("https://cdn.example/a/Blank.png?v=2"));
+        assert!(!is_placeholder_image("/blanket.jpg"));
+
+        let opts = Options {
+            lazy_image_attrs: vec!["data-original".to_string()],
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "https://example.com", &opts);
+        assert!(!r.raw_markdown.contains("real.jpg"));
+        assert!(r
+            .raw_markdown
+            .contains("![Original](https://example.com/full.jpg)"));
+    }
+
+    #[test]
+    fn test_inline_svg_icons() {
+        let html = r#"<div class="toolbar">
+            <a href="/"><svg><title>Home</title><path d="M0 0"/></svg></a>
+            <a href="/search"><svg aria-label="Search"><text>magnifier</text></svg></a>
+            <a href="/next">Next <svg><title>chevron</title></svg></a>
+            <span><svg><desc>decorative</desc><text>arrow</text></svg></span>
+        </div><p>Body text</p>"#;
+        let r = run_pipeline(html, "https://example.com", &Options::default());
+        assert!(r.raw_markdown.contains("[Home](https://example.com/)"));
+        assert!(r
+            .raw_markdown
+            .contains("[Search](https://example.com/search)"));
+        assert!(r.raw_markdown.contains("[Next](https://example.com/next)"));
+        for word in ["chevron", "magnifier", "decorative", "arrow"] {
+            assert!(!r.raw_markdown.contains(word), "leaked {}", word);
+        }
+
+        let opts = Options {
+            svg_placeholders: true,
+            ..Options::default()
+        };
+        let r = run_pipeline(r#"<p><svg aria-label="Logo"></svg> Brand</p>"#, "", &opts);
+        assert!(r.raw_markdown.contains("![Logo]()"));
+        assert!(r.images.is_empty());
+    }
+
+    #[test]
+    fn test_mathml_fraction() {
+        // Wikipedia markup for {\displaystyle {\frac {a}{b}}}
+        let html = r#"<p>Ratio <math xmlns="http://www.w3.org/1998/Math/MathML" alttext="{\displaystyle {\frac {a}{b}}}"><semantics><mrow class="MJX-TeXAtom-ORD"><mstyle displaystyle="true" scriptlevel="0"><mrow class="MJX-TeXAtom-ORD"><mfrac><mi>a</mi><mi>b</mi></mfrac></mrow></mstyle></mrow><annotation encoding="application/x-tex">{\displaystyle {\frac {a}{b}}}</annotation></semantics></math></p>"#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains(r"$\frac{a}{b}$"));
+    }
+
+    #[test]
+    fn test_mathml_superscript_display() {
+        // Wikipedia markup for E = mc^2, as display math
+        let html = r#"<math display="block" alttext="{\displaystyle E=mc^{2}}"><semantics><mrow class="MJX-TeXAtom-ORD"><mstyle displaystyle="true" scriptlevel="0"><mi>E</mi><mo>=</mo><mi>m</mi><msup><mi>c</mi><mrow class="MJX-TeXAtom-ORD"><mn>2</mn></mrow></msup></mstyle></mrow><annotation encoding="application/x-tex">{\displaystyle E=mc^{2}}</annotation></semantics></math>"#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.raw_markdown, "$$E=mc^{2}$$");
+    }
+
+    #[test]
+    fn test_mathml_alttext_fallback() {
+        let html = r#"<p><math alttext="\binom{n}{k}"><mtable><mtr><mtd><mi>n</mi></mtd></mtr></mtable></math></p>"#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains(r"$\binom{n}{k}$"));
+
+        let r = run_pipeline(
+            "<p><math><mi>α</mi><mo>×</mo><mi>x</mi></math></p>",
+            "",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.contains(r"$\alpha\times x$"));
+    }
+
+    #[test]
+    fn test_q_quotation_marks() {
+        let html = r#"<p>He said <q>she told me <q>no</q> twice</q>.</p>"#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.raw_markdown, r#"He said "she told me 'no' twice"."#);
+
+        let html = r#"<p><q cite="/speech"><a href="https://a.com">Ask</a> not</q></p>"#;
+        let r = run_pipeline(html, "https://example.com", &Options::default());
+        assert_eq!(r.raw_markdown, r#""[Ask](https://a.com/) not""#);
+        assert_eq!(r.links.len(), 1);
+
+        let opts = Options {
+            include_cite_urls: true,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "https://example.com", &opts);
+        assert_eq!(
+            r.raw_markdown,
+            r#""[Ask](https://a.com/) not" ([source](https://example.com/speech))"#
+        );
+        assert_eq!(r.links.len(), 2);
+    }
+
+    #[test]
+    fn test_cite_and_dfn() {
+        let html = r#"<p>Read <cite>Dune</cite>. A <dfn title="Application Programming Interface">API</dfn> is</p>"#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("*Dune*"));
+        assert!(r.raw_markdown.contains("*API*"));
+        assert!(!r.raw_markdown.contains("Interface"));
+
+        let opts = Options {
+            expand_dfn_titles: true,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert!(r
+            .raw_markdown
+            .contains("*API* (Application Programming Interface)"));
+    }
+
+    #[test]
+    fn test_blockquote_cite_attribution() {
+        let html = "<blockquote><p>text</p><footer>— <cite>Author</cite></footer></blockquote>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("> text"));
+        assert!(r
+            .raw_markdown
+            .lines()
+            .any(|l| l.starts_with("> —") && l.ends_with("*Author*")));
+    }
+
+    #[test]
+    fn test_abbr_expansion() {
+        let html = r#"<p><abbr title="HyperText Markup Language">HTML</abbr> rocks.</p>
+            <p>More <abbr title="HyperText Markup Language">HTML</abbr> and <abbr>CSS</abbr>.</p>"#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(
+            r.raw_markdown
+                .matches("(HyperText Markup Language)")
+                .count(),
+            1
+        );
+        assert!(r.raw_markdown.contains("HTML (HyperText Markup Language)"));
+        assert!(r.raw_markdown.contains("CSS"));
+        assert!(!r.raw_markdown.contains("CSS ("));
+
+        for (mode, count) in [(AbbrExpansion::Always, 2), (AbbrExpansion::Never, 0)] {
+            let opts = Options {
+                abbr_expansion: mode,
+                ..Options::default()
+            };
+            let r = run_pipeline(html, "", &opts);
+            assert_eq!(
+                r.raw_markdown
+                    .matches("(HyperText Markup Language)")
+                    .count(),
+                count
+            );
+        }
+    }
+
+    #[test]
+    fn test_kbd_samp_var() {
+        let html =
+            "<p>Press <kbd>Ctrl</kbd>+<kbd>Shift</kbd>+<kbd>C</kbd>, then set <var>x</var>.</p>\
+            <pre><samp>$ ls</samp></pre>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("`Ctrl`+`Shift`+`C`"));
+        assert!(r.raw_markdown.contains("`x`"));
+        assert!(r.raw_markdown.contains("```\n$ ls\n```"));
+    }
+
+    #[test]
+    fn test_mark_highlight() {
+        let html = r#"<p>Found <mark>match</mark> and <mark><a href="https://a.com">linked</a></mark></p>"#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("==match=="));
+        assert!(r.raw_markdown.contains("==[linked](https://a.com)=="));
+        assert!(r.markdown_with_citations.contains("==linked[1]=="));
+
+        let opts = Options {
+            mark_style: MarkStyle::Bold,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert!(r.raw_markdown.contains("**match**"));
+        assert!(!r.raw_markdown.contains("=="));
+    }
+
+    #[test]
+    fn test_table_caption() {
+        let html =
+            "<table><tr><th>A</th></tr><tr><td>1</td></tr><caption>Results</caption></table>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r
+            .raw_markdown
+            .contains("**Results**\n\n| A |\n| --- |\n| 1 |"));
+
+        // Layout tables drop their caption
+        let html = "<table><caption>Layout</caption><tr><td><div>Cell</div><div>More</div></td></tr></table>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("Cell"));
+        assert!(!r.raw_markdown.contains("Layout"));
+    }
+
+    #[test]
+    fn test_base_tag() {
+        let html = r#"<html><head><base href="https://cdn.example.org/docs/"></head>
+            <body><p><a href="guide.html">Guide</a> <img src="img/a.png" alt="A"></p></body></html>"#;
+        let r = run_pipeline(html, "https://example.com/page", &Options::default());
+        assert!(r
+            .raw_markdown
+            .contains("[Guide](https://cdn.example.org/docs/guide.html)"));
+        assert_eq!(r.links[0].url, "https://cdn.example.org/docs/guide.html");
+        assert_eq!(r.images[0].url, "https://cdn.example.org/docs/img/a.png");
+
+        // A relative <base> is joined against the caller's base URL
+        let html = r#"<head><base href="/v2/"></head><p><a href="x">X</a></p>"#;
+        let r = run_pipeline(html, "https://example.com/a/b", &Options::default());
+        assert_eq!(r.links[0].url, "https://example.com/v2/x");
+
+        // ...and used on its own when no base URL is passed
+        let html = r#"<head><base href="https://example.net/"></head><p><a href="x">X</a></p>"#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.links[0].url, "https://example.net/x");
+    }
+
+    #[test]
+    fn test_title_extraction() {
+        let html = r#"<html><head><meta property="og:title" content="OG Title">
+            <title>Doc Title | Example.com</title></head>
+            <body><main><h1>Heading</h1></main></body></html>"#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.title, "OG Title");
+
+        let html = "<html><head><title>Doc Title | Example.com</title></head>\
+            <body><main><h1>Heading</h1></main></body></html>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.title, "Doc Title | Example.com");
+        let opts = Options {
+            strip_title_suffix: true,
+            ..Options::default()
+        };
+        assert_eq!(run_pipeline(html, "", &opts).title, "Doc Title");
+
+        let html = "<html><body><h1>Outside</h1><main><h1>Main Heading</h1></main></body></html>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.title, "Main Heading");
+
+        let html = "<html><body><main><svg><title>Close icon</title></svg>\
+            <h1>Main Heading</h1></main></body></html>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.title, "Main Heading");
+
+        assert_eq!(run_pipeline("<p>x</p>", "", &Options::default()).title, "");
+    }
+
+    #[test]
+    fn test_metadata_blog_post() {
+        let html = r#"<html><head>
+            <title>Shipping Rust | Dev Blog</title>
+            <meta name="description" content="How we shipped our first Rust service.">
+            <meta name="author" content="Sam Lee">
+        </head><body><article>
+            <h1>Shipping Rust</h1>
+            <p>Posted <time datetime="2024-03-05T09:00:00Z" pubdate>March 5</time></p>
+        </article></body></html>"#;
+        let m = run_pipeline(html, "", &Options::default()).metadata;
+        assert_eq!(m.description, "How we shipped our first Rust service.");
+        assert_eq!(m.author, "Sam Lee");
+        assert_eq!(m.published_time, "2024-03-05T09:00:00Z");
+        assert_eq!(m.modified_time, "");
+    }
+
+    #[test]
+    fn test_metadata_news_article() {
+        let html = r#"<html><head>
+            <meta property="article:published_time" content="2024-06-01T12:30:00+00:00">
+            <meta property="article:modified_time" content="2024-06-02T08:00:00+00:00">
+            <meta property="article:author" content="https://news.example.com/staff/kim">
+        </head><body><article>
+            <time itemprop="datePublished">June 1, 2024</time><p>Story</p>
+        </article></body></html>"#;
+        let m = run_pipeline(html, "", &Options::default()).metadata;
+        assert_eq!(m.published_time, "2024-06-01T12:30:00+00:00");
+        assert_eq!(m.modified_time, "2024-06-02T08:00:00+00:00");
+        assert_eq!(m.author, "https://news.example.com/staff/kim");
+        assert_eq!(m.description, "");
+    }
+
+    #[test]
+    fn test_open_graph() {
+        let html = r#"<html><head>
+            <meta property="og:title" content="Launch">
+            <meta property="og:image" content="/img/cover.jpg">
+            <meta property="og:image" content="https://cdn.example.com/alt.jpg">
+            <meta name="twitter:card" content="summary_large_image">
+            <meta name="twitter:image" content="card.png">
+            <meta name="description" content="not og">
+        </head><body><p>Body</p></body></html>"#;
+        let r = run_pipeline(html, "https://example.com/news/", &Options::default());
+        let og: HashMap<_, _> = r.open_graph.iter().cloned().collect();
+        assert_eq!(og["og:title"], ["Launch"]);
+        assert_eq!(
+            og["og:image"],
+            [
+                "https://example.com/img/cover.jpg",
+                "https://cdn.example.com/alt.jpg"
+            ]
+        );
+        assert_eq!(og["twitter:card"], ["summary_large_image"]);
+        assert_eq!(og["twitter:image"], ["https://example.com/news/card.png"]);
+        assert!(!og.contains_key("description"));
+        assert_eq!(r.raw_markdown, "Body");
+    }
+
+    #[test]
+    fn test_json_ld() {
+        let html = r#"<html><head>
+            <script type="application/ld+json">{"@type": "NewsArticle", "headline": "Hi"}</script>
+            <script type="application/ld+json">   </script>
+        </head><body>
+            <p>Story</p>
+            <script type="Application/LD+JSON">[{"@type": "BreadcrumbList"}]</script>
+            <script type="application/ld+json">{"broken": </script>
+        </body></html>"#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.json_ld.is_empty());
+
+        let opts = Options {
+            extract_json_ld: true,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert_eq!(
+            r.json_ld,
+            [
+                r#"{"@type": "NewsArticle", "headline": "Hi"}"#,
+                r#"[{"@type": "BreadcrumbList"}]"#
+            ]
+        );
+        assert_eq!(r.raw_markdown, "Story");
+    }
+
+    #[test]
+    fn test_canonical_and_feeds() {
+        let html = r#"<html><head>
+            <link rel="canonical" href="/articles/42">
+            <link rel="canonical" href="/ignored">
+            <link rel="alternate" type="application/rss+xml" title="RSS" href="/feed.xml">
+            <link rel="alternate" type="application/atom+xml" href="https://example.com/atom">
+            <link rel="alternate" hreflang="de" href="/de/">
+            <link rel="next" href="?page=3"><link rel="prev" href="?page=1">
+        </head><body><p>Body</p></body></html>"#;
+        let rels = run_pipeline(
+            html,
+            "https://example.com/articles/42?page=2",
+            &Options::default(),
+        )
+        .link_relations;
+        assert_eq!(rels.canonical_url, "https://example.com/articles/42");
+        assert_eq!(rels.feeds.len(), 2);
+        assert_eq!(rels.feeds[0].url, "https://example.com/feed.xml");
+        assert_eq!(rels.feeds[0].kind, "application/rss+xml");
+        assert_eq!(rels.feeds[0].title, "RSS");
+        assert_eq!(rels.feeds[1].kind, "application/atom+xml");
+        assert_eq!(rels.next_page, "https://example.com/articles/42?page=3");
+        assert_eq!(rels.prev_page, "https://example.com/articles/42?page=1");
+    }
+
+    #[test]
+    fn test_table_cell_escaping() {
+        let html = "<table><tr><th>Cmd</th><th>Notes</th></tr>\
+            <tr><td><code>a | b</code></td><td>Line1<br>Line2</td></tr></table>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("| `a \\| b` | Line1<br>Line2 |"));
+        let row = r.raw_markdown.lines().last().unwrap();
+        assert_eq!(row.matches(" | ").count(), 1);
+    }
+
+    #[test]
+    fn test_table_cell_inline_content() {
+        let html = "<table><tr><th>Plan</th><th><em>Price</em></th></tr>\
+            <tr><td><strong>Pro</strong></td><td><a href=\"/signup\">Sign up</a></td></tr></table>";
+        let r = run_pipeline(html, "https://a.com", &Options::default());
+        assert!(r.raw_markdown.contains("| Plan | *Price* |"));
+        assert!(r.raw_markdown.contains("| **Pro** | [Sign up](https://a.com/signup) |"));
+        assert!(r.markdown_with_citations.contains("| Sign up[1] |"));
+        assert_eq!(r.links[0].url, "https://a.com/signup");
+        assert_eq!(r.links[0].citation_number, 1);
+    }
+
+    #[test]
+    fn test_table_separator_without_th() {
+        let html = "<table><tr><td>a</td><td>b</td></tr><tr><td>1</td><td>2</td></tr></table>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("| a | b |\n| --- | --- |\n| 1 | 2 |"));
+
+        let opts = Options {
+            table_header_separator: false,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert!(r.raw_markdown.contains("| a | b |\n| 1 | 2 |"));
+    }
+
+    #[test]
+    fn test_table_thead_after_tbody() {
+        let html = "<table><tbody><tr><td>1</td><td>2</td></tr></tbody>\
+            <thead><tr><th>A</th><th>B</th></tr></thead></table>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("| A | B |\n| --- | --- |\n| 1 | 2 |"));
+    }
+
+    #[test]
+    fn test_table_colspan_header() {
+        let html = "<table><tr><th colspan=\"2\">Name</th><th>Age</th></tr>\
+            <tr><td>Ada</td><td>Lovelace</td><td>36</td></tr></table>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r
+            .raw_markdown
+            .contains("| Name | | Age |\n| --- | --- | --- |\n| Ada | Lovelace | 36 |"));
+    }
+
+    #[test]
+    fn test_table_rowspan_body_cell() {
+        let html = "<table><tr><th>Team</th><th>Member</th><th>Role</th></tr>\
+            <tr><td rowspan=\"2\">Core</td><td>Ann</td><td>Lead</td></tr>\
+            <tr><td>Bob</td><td>Dev</td></tr></table>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("| Core | Ann | Lead |\n| | Bob | Dev |"));
+
+        let opts = Options {
+            repeat_spanned_cells: true,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert!(r.raw_markdown.contains("| Core | Bob | Dev |"));
+    }
+
+    #[test]
+    fn test_table_alignment_attributes() {
+        let html = "<table><colgroup><col><col align=\"center\"></colgroup>\
+            <tr><th>Item</th><th>Qty</th><th align=\"right\">Price</th></tr>\
+            <tr><td>Tea</td><td>2</td><td align=\"right\">4.00</td></tr></table>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("| --- | :---: | ---: |"));
+    }
+
+    #[test]
+    fn test_table_alignment_inline_style() {
+        let html = "<table><tr><th style=\"text-align: center\">A</th>\
+            <th style=\"font-weight:bold; text-align:right\">B</th><th>C</th></tr>\
+            <tr><td>1</td><td style=\"text-align: left\">2</td><td>3</td></tr></table>";
+        let r = run_pipeline(html, "", &Options::default());
+        // Column B's header and body disagree, so it falls back to the default
+        assert!(r.raw_markdown.contains("| :---: | --- | --- |"));
+    }
+
+    #[test]
+    fn test_long_two_column_table_with_headers() {
+        let mut html = String::from("<table><tr><th>Term</th><th>Meaning</th></tr>");
+        for i in 0..30 {
+            html.push_str(&format!("<tr><td>term{}</td><td>meaning {}</td></tr>", i, i));
+        }
+        html.push_str("</table>");
+        let r = run_pipeline(&html, "", &Options::default());
+        assert!(r.raw_markdown.contains("| Term | Meaning |\n| --- | --- |"));
+        assert!(r.raw_markdown.contains("| term29 | meaning 29 |"));
+
+        let opts = Options {
+            table_mode: TableMode::AlwaysLayout,
+            ..Options::default()
+        };
+        let r = run_pipeline(&html, "", &opts);
+        assert!(!r.raw_markdown.contains("| --- |"));
+    }
+
+    /// Trimmed from a wiki infobox-style comparison table: the outer table is
+    /// data, the inner one only positions an icon next to a label.
+    const WIKI_NESTED_TABLE: &str = r#"
+        <table class="wikitable sortable">
+          <tbody>
+            <tr><th>Browser</th><th>Engine</th><th>Released</th></tr>
+            <tr>
+              <td>
+                <table style="border:0;margin:0" role="presentation">
+                  <tr><td><img src="/icons/ff.png" alt="" width="16"></td><td><a href="/wiki/Firefox">Firefox</a></td></tr>
+                </table>
+              </td>
+              <td>Gecko</td>
+              <td>2004</td>
+            </tr>
+            <tr><td>Chrome</td><td>Blink</td><td>2008</td></tr>
+          </tbody>
+        </table>"#;
+
+    #[test]
+    fn test_nested_positioning_table_in_data_table() {
+        let r = run_pipeline(
+            WIKI_NESTED_TABLE,
+            "https://wiki.example",
+            &Options::default(),
+        );
+        let md = &r.raw_markdown;
+        assert!(md.contains("| Browser | Engine | Released |\n| --- | --- | --- |"));
+        let row = md.lines().find(|l| l.contains("Gecko")).unwrap();
+        assert!(row.contains("[Firefox](https://wiki.example/wiki/Firefox)"));
+        assert!(row.ends_with("| Gecko | 2004 |"));
+        assert!(md.contains("| Chrome | Blink | 2008 |"));
+    }
+
+    #[test]
+    fn test_table_cells_wrapped_in_paragraphs() {
+        let html = "<table><tr><td><p>Name</p></td><td><p>Value</p></td></tr>\
+            <tr><td><p>alpha</p></td><td><p>1</p></td></tr></table>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("| Name | Value |\n| --- | --- |\n| alpha | 1 |"));
+
+        let html = "<table><tr><td><p>One</p><p>Two</p></td><td>x</td></tr></table>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(!r.raw_markdown.contains('|'));
+    }
+
+    #[test]
+    fn test_layout_table_cell_separation() {
+        let html = "<table><tr><td><a href=\"/\">Home</a></td><td><a href=\"/about\">About</a></td>\
+            <td><a href=\"/contact\">Contact</a></td></tr><tr><td>Welcome</td><td>to</td><td>the site</td></tr></table>";
+        let opts = Options {
+            table_mode: TableMode::AlwaysLayout,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert!(r
+            .raw_markdown
+            .contains("[Home](/) [About](/about) [Contact](/contact)\nWelcome to the site"));
+
+        let opts = Options {
+            dedupe_tables: false,
+            ..opts
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert!(r.raw_markdown.contains("| [Home](/) | [About](/about) | [Contact](/contact) |"));
+    }
+
+    #[test]
+    fn test_inline_whitespace_preserved() {
+        let r = run_pipeline(
+            "<p>Hello <strong>world</strong>!</p>",
+            "",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.contains("Hello **world**!"));
+
+        let html = "<p><a href=\"/a\">one</a> and <a href=\"/b\">two</a></p>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("[one](/a) and [two](/b)"));
+
+        let html = "<p>(<em>x</em>) and <strong>bold </strong>text</p>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("(*x*) and **bold** text"));
+    }
+
+    #[test]
+    fn test_br_in_heading_and_cell() {
+        let html = "<h2>221B Baker Street<br>London</h2>\
+            <table><tr><th>Poem</th></tr><tr><td>Roses are red,<br>violets are blue</td></tr></table>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("## 221B Baker Street London"));
+        assert!(r.raw_markdown.contains("| Roses are red,<br>violets are blue |"));
+    }
+
+    #[test]
+    fn test_nbsp_and_invisible_characters() {
+        let html = "<p><a href=\"/\">Home</a>&nbsp;&nbsp;|&nbsp;&nbsp;<a href=\"/about\">About</a></p>\
+            <h2>Donau&shy;dampf&shy;schiff</h2><p>zero&#8203;width and&nbsp;&nbsp;&nbsp;gap</p>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("[Home](/) | [About](/about)"));
+        assert!(r.raw_markdown.contains("## Donaudampfschiff"));
+        assert!(r.raw_markdown.contains("zerowidth and gap"));
+        assert!(!r.raw_markdown.contains('\u{a0}'));
+
+        // Joiners carry meaning: Persian "mi-xaham" and a family emoji
+        let html = "<p>\u{645}\u{6cc}\u{200c}\u{62e}\u{648}\u{627}\u{647}\u{645} \
+            \u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}</p>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("\u{645}\u{6cc}\u{200c}\u{62e}"));
+        assert!(r
+            .raw_markdown
+            .contains("\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}"));
+    }
+
+    #[test]
+    fn test_code_fence_language() {
+        let github = "<div class=\"highlight highlight-source-rust notranslate\">\
+            <pre>fn main() {}</pre></div>";
+        let r = run_pipeline(github, "", &Options::default());
+        assert!(r.raw_markdown.contains("```rust\nfn main() {}\n```"));
+
+        let prism = "<pre class=\"line-numbers\"><code class=\"language-js\">let x = 1;</code></pre>";
+        let r = run_pipeline(prism, "", &Options::default());
+        assert!(r.raw_markdown.contains("```js\nlet x = 1;\n```"));
+
+        let hljs = "<pre><code class=\"hljs python\">print(1)</code></pre>";
+        let r = run_pipeline(hljs, "", &Options::default());
+        assert!(r.raw_markdown.contains("```python\nprint(1)\n```"));
+
+        let data_lang = "<pre data-lang=\"zig\">const x = 1;</pre>";
+        let r = run_pipeline(data_lang, "", &Options::default());
+        assert!(r.raw_markdown.contains("```zig\n"));
+
+        let natural = "<pre lang=\"en\">hello</pre>";
+        let r = run_pipeline(natural, "", &Options::default());
+        assert_eq!(r.raw_markdown, "```\nhello\n```");
+    }
+
+    #[test]
+    fn test_backticks_in_code() {
+        let html = "<pre>Use a fence:\n```js\nlet x;\n```</pre>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("````\nUse a fence:\n```js\nlet x;\n```\n````"));
+
+        let html = "<p>Type <code>`</code> to start</p>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("Type `` ` `` to start"));
+    }
+
+    #[test]
+    fn test_code_block_indentation_survives() {
+        let code = "\n    # setup\n    x = 1\n\n\n\ndef f(a,  b):\n    if a:\n        return b\n";
+        let html = format!("<p>Example:</p><pre><code class=\"language-python\">{}</code></pre>", code);
+        let r = run_pipeline(&html, "", &Options::default());
+        let expected =
+            "```python\n    # setup\n    x = 1\n\n\n\ndef f(a,  b):\n    if a:\n        return b\n```";
+        assert!(r.raw_markdown.contains(expected));
+        assert!(r.clean_markdown.contains(expected));
+
+        let html = "<blockquote><pre>def f():\n    return 1</pre></blockquote>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r
+            .clean_markdown
+            .contains("> ```\n> def f():\n>     return 1\n> ```"));
+    }
+
+    #[test]
+    fn test_blockquote_block_structure() {
+        let html = "<blockquote><p>Run:</p><pre>cargo build\n\ncargo test</pre></blockquote>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r
+            .raw_markdown
+            .contains("> Run:\n>\n> ```\n> cargo build\n>\n> cargo test\n> ```"));
+
+        let html = "<blockquote><p>Outer</p><blockquote><p>Inner</p></blockquote>\
+            <ul><li>point</li></ul></blockquote>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("> Outer\n>\n> > Inner\n>\n> - point"));
+
+        let html =
+            "<blockquote><p>Try:</p><pre>if x:\n    y  =  1  \n\n    z = 2</pre></blockquote>";
+        let r = run_pipeline(html, "", &Options::default());
+        let expected = "> ```\n> if x:\n>     y  =  1  \n>\n>     z = 2\n> ```";
+        assert!(r.raw_markdown.contains(expected));
+        assert!(r.clean_markdown.contains(expected));
+    }
+
+    #[test]
+    fn test_multi_block_list_items() {
+        let html = "<ul><li><p>Install:</p><pre>pip install  grub</pre></li><li>Next</li></ul>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r
+            .raw_markdown
+            .contains("- Install:\n\n  ```\n  pip install  grub\n  ```\n- Next"));
+
+        let html = "<ol><li><p>First para</p><p>Second para</p></li></ol>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("1. First para\n\n   Second para"));
+    }
+
+    #[test]
+    fn test_ordered_list_numbering() {
+        let r = run_pipeline(
+            "<ol start=\"5\"><li>five</li><li>six</li></ol>",
+            "",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.contains("5. five\n6. six"));
+
+        let r = run_pipeline(
+            "<ol><li>one</li><li value=\"12\">twelve</li><li>thirteen</li></ol>",
+            "",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.contains("1. one\n12. twelve\n13. thirteen"));
+
+        let r = run_pipeline(
+            "<ol reversed><li>three</li><li>two</li><li>one</li></ol>",
+            "",
+            &Options::default(),
+        );
+        assert!(r.raw_markdown.contains("3. three\n2. two\n1. one"));
+    }
+
+    #[test]
+    fn test_markdown_style_markers() {
+        let html = "<p><em>soft</em> and <strong>loud</strong></p>\
+            <ul><li>a</li><li>b</li></ul><ol><li>one</li></ol>";
+        let opts = Options {
+            style: MarkdownStyle {
+                bullet_marker: "*",
+                emphasis_marker: "_",
+                strong_marker: "__",
+                ordered_marker_suffix: ")",
+            },
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert!(r.raw_markdown.contains("_soft_ and __loud__"));
+        assert!(r.raw_markdown.contains("* a\n* b"));
+        assert!(r.raw_markdown.contains("1) one"));
+
+        assert_eq!(clean_markdown("x\n* \ny\n3) \nz"), "x\ny\nz");
+        let err = parse_marker("bullet_marker", "#", &["-", "*", "+"]).unwrap_err();
+        assert!(err.contains("expected one of: -, *, +"));
+    }
+
+    #[test]
+    fn test_heading_styles() {
+        let html = "<h1>Guide</h1><p>Intro</p><h2>Setup</h2><h3>Linux</h3><p>Steps</p>";
+        let render = |heading_style| {
+            let opts = Options {
+                heading_style,
+                ..Options::default()
+            };
+            run_pipeline(html, "", &opts)
+        };
+
+        let r = render(HeadingStyle::Atx);
+        assert!(r
+            .raw_markdown
+            .contains("# Guide\n\nIntro\n\n## Setup\n\n### Linux"));
+
+        let r = render(HeadingStyle::AtxClosed);
+        assert!(r
+            .raw_markdown
+            .contains("# Guide #\n\nIntro\n\n## Setup ##\n\n### Linux ###"));
+
+        let r = render(HeadingStyle::Setext);
+        assert!(r
+            .raw_markdown
+            .contains("Guide\n=====\n\nIntro\n\nSetup\n-----\n\n### Linux"));
+        assert!(r
+            .clean_markdown
+            .contains("Intro\n\nSetup\n-----\n\n### Linux\n\nSteps"));
+    }
+
+    #[test]
+    fn test_task_list_items() {
+        let html = r#"<ul class="contains-task-list">
+            <li class="task-list-item"><input type="checkbox" class="task-list-item-checkbox" disabled checked> Write docs</li>
+            <li class="task-list-item"><input type="checkbox" class="task-list-item-checkbox" disabled> Ship it
+              <ul class="contains-task-list">
+                <li class="task-list-item"><input type="checkbox" disabled checked> Tag release</li>
+              </ul>
+            </li>
+            <li>Plain item</li>
+        </ul>"#;
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r
+            .raw_markdown
+            .contains("- [x] Write docs\n- [ ] Ship it\n  - [x] Tag release\n- Plain item"));
+    }
+
+    #[test]
+    fn test_link_escaping() {
+        let html = "<p><a href=\"/wiki/Mercury_(planet)\">Mercury [planet]</a> and \
+            <img src=\"/img/Orbit (2).png\" alt=\"Orbit [diagram]\"></p>";
+        let r = run_pipeline(
+            html,
+            "https://en.wikipedia.org/wiki/Mercury",
+            &Options::default(),
+        );
+        assert!(r
+            .raw_markdown
+            .contains("[Mercury \\[planet\\]](<https://en.wikipedia.org/wiki/Mercury_(planet)>)"));
+        assert_eq!(
+            r.links[0].url,
+            "https://en.wikipedia.org/wiki/Mercury_(planet)"
+        );
+        assert_eq!(r.links[0].text, "Mercury [planet]");
+        assert!(r
+            .markdown_with_citations
+            .contains("Mercury \\[planet\\][1] and"));
+
+        assert_eq!(r.images.len(), 1);
+        assert_eq!(r.images[0].alt, "Orbit [diagram]");
+        assert_eq!(
+            r.images[0].url,
+            "https://en.wikipedia.org/img/Orbit%20(2).png"
+        );
+        assert_eq!(r.markdown_plain, "Mercury [planet] and Orbit [diagram]");
+    }
+
+    #[test]
+    fn test_quoted_titles() {
+        let html = r#"<p><a href="/talk" title='He said "hi"'>Talk</a>
+            <img src="/q.png" alt="Quote" title='She said "bye"'></p>"#;
+        let r = run_pipeline(html, "https://a.com", &Options::default());
+        assert!(r.raw_markdown.contains("[Talk](https://a.com/talk)"));
+        assert!(r
+            .raw_markdown
+            .contains(r#"![Quote](https://a.com/q.png "She said \"bye\"")"#));
+        assert_eq!(r.links[0].title, r#"He said "hi""#);
+        assert_eq!(r.images[0].title, r#"She said "bye""#);
+        assert!(r
+            .references_markdown
+            .contains(r#"[1]: https://a.com/talk "He said \"hi\"""#));
+    }
+
+    #[test]
+    fn test_linked_images() {
+        let html = "<p><a href=\"/full.jpg\"><img src=\"/thumb.jpg\" alt=\"Photo\"></a></p>";
+        let r = run_pipeline(html, "https://a.com", &Options::default());
+        assert!(r
+            .raw_markdown
+            .contains("[![Photo](https://a.com/thumb.jpg)](https://a.com/full.jpg)"));
+        assert_eq!(r.links.len(), 1);
+        assert_eq!(r.links[0].url, "https://a.com/full.jpg");
+        assert_eq!(r.links[0].text, "Photo");
+        assert_eq!(r.images[0].url, "https://a.com/thumb.jpg");
+        assert!(r
+            .markdown_with_citations
+            .contains("![Photo](https://a.com/thumb.jpg)[1]"));
+        assert_eq!(r.markdown_plain, "Photo");
+
+        let html = "<p><a href=\"/gallery\"><img src=\"/cover.png\" alt=\"Cover\"> \
+            <span>Open gallery</span></a></p>";
+        let r = run_pipeline(html, "https://a.com", &Options::default());
+        assert!(r
+            .raw_markdown
+            .contains("[![Cover](https://a.com/cover.png) Open gallery](https://a.com/gallery)"));
+        assert_eq!(r.links[0].text, "Cover Open gallery");
+    }
+
+    #[test]
+    fn test_heading_inline_content() {
+        let html = "<h2><a href=\"/install\">Installation</a></h2><p>Steps</p>\
+            <h2>Use <code>foo()</code> <em>carefully</em></h2>\
+            <h3 id=\"setup\">Setup<a class=\"headerlink\" href=\"#setup\">¶</a></h3>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("## [Installation](/install)"));
+        assert_eq!(r.links[0].url, "/install");
+        assert!(r.raw_markdown.contains("## Use `foo()` *carefully*"));
+        assert!(r.raw_markdown.ends_with("### Setup"));
+        assert!(!r.raw_markdown.contains('¶'));
+    }
+
+    #[test]
+    fn test_repeated_links_cited_in_place() {
+        let html = "<p><a href=\"/x\">a</a> <a href=\"/y\">b</a> <a href=\"/x\">a</a> \
+            <a href=\"/z\">c</a> <a href=\"/x\">a</a></p>";
+        let opts = Options {
+            dedupe_citations: false,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert_eq!(r.markdown_with_citations, "a[1] b[2] a[3] c[4] a[5]");
+        let numbers: Vec<usize> = r.links.iter().map(|l| l.citation_number).collect();
+        assert_eq!(numbers, vec![1, 2, 3, 4, 5]);
+        assert_eq!(r.links[4].url, "/x");
+    }
+
+    #[test]
+    fn test_dedupe_citations() {
+        let html = "<p><a href=\"/edit\" title=\"Edit\">Edit this page</a> \
+            <a href=\"/api/Type\">Type</a> <a href=\"https://a.com/edit\">edit</a> \
+            <a href=\"/api/Type\">Type</a> <a href=\"/edit/#top\">top</a> \
+            <a href=\"https://A.com/api/Type/\">Type</a></p>";
+        let r = run_pipeline(html, "https://a.com", &Options::default());
+        assert!(r
+            .markdown_with_citations
+            .contains("Edit this page[1] Type[2] edit[1] Type[2] top[1] Type[2]"));
+        assert_eq!(r.links.len(), 6);
+        let numbers: Vec<usize> = r.links.iter().map(|l| l.citation_number).collect();
+        assert_eq!(numbers, vec![1, 2, 1, 2, 1, 2]);
+        assert_eq!(
+            r.references_markdown,
+            "## References\n[1]: https://a.com/edit \"Edit\"\n[2]: https://a.com/api/Type\n"
+        );
+    }
+
+    #[test]
+    fn test_citation_styles() {
+        let html = "<p>See <a href=\"/docs\" title=\"Docs\">the docs</a> and <a href=\"/faq\">FAQ</a>.</p>";
+        let r = run_pipeline(html, "https://a.com", &Options::default());
+        assert!(r
+            .markdown_with_citations
+            .contains("See the docs[1] and FAQ[2]."));
+        assert_eq!(
+            r.references_markdown,
+            "## References\n[1]: https://a.com/docs \"Docs\"\n[2]: https://a.com/faq\n"
+        );
+
+        let opts = Options {
+            citation_style: CitationStyle::Footnote,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "https://a.com", &opts);
+        assert!(r
+            .markdown_with_citations
+            .contains("See the docs[^1] and FAQ[^2]."));
+        assert_eq!(
+            r.references_markdown,
+            "[^1]: https://a.com/docs \"Docs\"\n[^2]: https://a.com/faq\n"
+        );
+        assert!(!r.markdown_references.contains("## References"));
+
+        // Existing footnote labels in the content are left alone
+        let html = "<p>Claim[^1] with <a href=\"/src\">source</a>.</p>";
+        let r = run_pipeline(html, "https://a.com", &opts);
+        assert!(r
+            .markdown_with_citations
+            .contains("Claim[^1] with source[^cite1-1]."));
+        assert!(r
+            .references_markdown
+            .starts_with("[^cite1-1]: https://a.com/src"));
+    }
+
+    #[test]
+    fn test_fragment_and_javascript_links() {
+        let html = "<ul><li><a href=\"#intro\">Intro</a></li><li><a href=\"#usage\">Usage</a></li>\
+            <li><a href=\"#top\"><img src=\"/up.png\" alt=\"Top\"></a></li></ul>\
+            <p><a href=\"javascript:void(0)\">Menu</a> and <a href=\" JavaScript:open()\">Open</a>, \
+            see <a href=\"/guide\">the guide</a>.</p>";
+        let r = run_pipeline(html, "https://a.com/page", &Options::default());
+        assert!(r
+            .raw_markdown
+            .contains("- Intro\n- Usage\n- ![Top](https://a.com/up.png)"));
+        assert!(r
+            .raw_markdown
+            .contains("Menu and Open, see [the guide](https://a.com/guide)."));
+        assert_eq!(r.links.len(), 1);
+        assert_eq!(r.urls, vec!["https://a.com/guide"]);
+        assert!(r.markdown_with_citations.contains("see the guide[1]."));
+
+        let opts = Options {
+            resolve_fragment_links: true,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "https://a.com/page", &opts);
+        assert!(r
+            .raw_markdown
+            .contains("- [Intro](https://a.com/page#intro)"));
+        assert!(r.raw_markdown.contains("Menu and Open"));
+        let urls: Vec<&str> = r.links.iter().map(|l| l.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://a.com/page#intro",
+                "https://a.com/page#usage",
+                "https://a.com/page#top",
+                "https://a.com/guide"
+            ]
+        );
+
+        // Text that merely looks like a link stays text
+        let html = "<p>[Top](#top) and <a href=\"/doc\">Doc</a></p>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.markdown_with_citations, "[Top](#top) and Doc[1]");
+        assert_eq!(r.links.len(), 1);
+    }
+
+    #[test]
+    fn test_contact_links() {
+        let html = "<main><h1>Contact</h1>\
+            <p><a href=\"mailto:sales@example.com?subject=Quote%20request&amp;cc=ops@example.com\">Email us</a> \
+            or call <a href=\"tel:+1-555-123-4567\">+1 555 123 4567</a>.</p>\
+            <p><a href=\"MAILTO:sales@example.com\">sales@example.com</a> \
+            <a href=\"/offices\">Offices</a></p></main>";
+        let r = run_pipeline(html, "https://example.com/contact", &Options::default());
+        assert!(r.raw_markdown.contains(
+            "[Email us](mailto:sales@example.com?subject=Quote%20request&cc=ops@example.com)"
+        ));
+        assert!(r
+            .raw_markdown
+            .contains("[+1 555 123 4567](tel:+1-555-123-4567)"));
+        let contacts: Vec<(&str, &str, &str)> = r
+            .contacts
+            .iter()
+            .map(|c| (c.kind.as_str(), c.value.as_str(), c.text.as_str()))
+            .collect();
+        assert_eq!(
+            contacts,
+            vec![
+                ("email", "sales@example.com", "Email us"),
+                ("phone", "+1-555-123-4567", "+1 555 123 4567"),
+            ]
+        );
+        assert_eq!(r.urls.len(), 4);
+        assert_eq!(r.urls[1], "tel:+1-555-123-4567");
+
+        let opts = Options {
+            cite_contact_links: false,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "https://example.com/contact", &opts);
+        assert_eq!(r.urls, vec!["https://example.com/offices"]);
+        assert_eq!(r.contacts.len(), 2);
+        assert!(r
+            .markdown_with_citations
+            .contains("or call [+1 555 123 4567](tel:+1-555-123-4567)."));
+        assert!(r.markdown_with_citations.contains("Offices[1]"));
+    }
+
+    #[test]
+    fn test_protocol_relative_and_schemeless_urls() {
+        let html = "<p><a href=\"//cdn.example.com/lib\">CDN</a> \
+            <img src=\"//images.example.com/a.png\" alt=\"A\"> \
+            <a href=\"www.example.org/page\">Partner</a></p>";
+
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.urls[0], "https://cdn.example.com/lib");
+        assert_eq!(r.images[0].url, "https://images.example.com/a.png");
+        assert_eq!(r.urls[1], "www.example.org/page");
+
+        let r = run_pipeline(html, "http://site.com/docs/", &Options::default());
+        assert_eq!(r.urls[0], "http://cdn.example.com/lib");
+        assert_eq!(r.urls[1], "http://site.com/docs/www.example.org/page");
+
+        let opts = Options {
+            fix_schemeless: true,
+            ..Options::default()
+        };
+        for base in ["", "http://site.com/docs/"] {
+            let r = run_pipeline(html, base, &opts);
+            assert_eq!(r.urls[1], "https://www.example.org/page");
+            assert!(r
+                .raw_markdown
+                .contains("[Partner](https://www.example.org/page)"));
+        }
+    }
+
+    #[test]
+    fn test_url_normalization() {
+        assert_eq!(
+            normalize_url(
+                "HTTPS://Example.COM:443/a/?utm_source=x&id=7&fbclid=abc#top",
+                &Options::default().strip_query_params
+            ),
+            "https://example.com/a?id=7"
+        );
+        assert_eq!(
+            normalize_url("/relative?utm_source=x", &[]),
+            "/relative?utm_source=x"
+        );
+
+        let html = "<p><a href=\"/post?utm_source=news&amp;utm_medium=email\">Post</a> \
+            <a href=\"/post?utm_source=feed\">Again</a> <a href=\"/post#comments\">Comments</a> \
+            <a href=\"/other/?ref=home&amp;gclid=1\">Other</a></p>";
+        let opts = Options {
+            normalize_urls: true,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "https://a.com", &opts);
+        assert_eq!(
+            r.urls,
+            vec!["https://a.com/post", "https://a.com/other?ref=home"]
+        );
+        assert_eq!(r.links[1].url, "https://a.com/post");
+        assert!(r
+            .markdown_with_citations
+            .contains("Post[1] Again[1] Comments[1] Other[2]"));
+        // The markdown keeps the links as written
+        assert!(r
+            .raw_markdown
+            .contains("[Again](https://a.com/post?utm_source=feed)"));
+
+        let opts = Options {
+            normalize_markdown_urls: true,
+            strip_query_params: vec!["ref".to_string()],
+            ..opts
+        };
+        let r = run_pipeline(html, "https://a.com", &opts);
+        assert!(r
+            .raw_markdown
+            .contains("[Other](https://a.com/other?gclid=1)"));
+        assert!(r.raw_markdown.contains("[Comments](https://a.com/post)"));
+    }
+
+    #[test]
+    fn test_internal_and_external_links() {
+        let html = "<p><a href=\"https://www.example.co.uk/about\">About</a> \
+            <a href=\"/pricing\">Pricing</a> <a href=\"https://blog.example.co.uk/\">Blog</a> \
+            <a href=\"https://other.co.uk/\">Other</a> <a href=\"mailto:hi@example.co.uk\">Mail</a></p>";
+        let summary = |r: &PipelineResult| -> Vec<(String, bool)> {
+            r.links
+                .iter()
+                .map(|l| (l.host.clone(), l.is_internal))
+                .collect()
+        };
+        let r = run_pipeline(html, "https://example.co.uk/", &Options::default());
+        assert_eq!(
+            summary(&r),
+            vec![
+                ("www.example.co.uk".to_string(), true),
+                ("example.co.uk".to_string(), true),
+                ("blog.example.co.uk".to_string(), true),
+                ("other.co.uk".to_string(), false),
+                (String::new(), false),
+            ]
+        );
+
+        let opts = Options {
+            subdomain_policy: SubdomainPolicy::External,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "https://example.co.uk/", &opts);
+        let internal: Vec<bool> = r.links.iter().map(|l| l.is_internal).collect();
+        assert_eq!(internal, vec![true, true, false, false, false]);
+
+        // Without a base URL nothing is classified
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.links.iter().all(|l| !l.is_internal && l.host.is_empty()));
+
+        // IP hosts have no registrable part to share
+        assert_eq!(registrable_domain("192.168.1.10"), "192.168.1.10");
+        assert_eq!(registrable_domain("[2001:db8::1]"), "[2001:db8::1]");
+        assert_eq!(registrable_domain("2001:db8::1"), "2001:db8::1");
+        assert_eq!(registrable_domain("docs.example.co.uk"), "example.co.uk");
+        let html = "<p><a href=\"http://10.0.0.2/\">Peer</a> <a href=\"/local\">Local</a></p>";
+        let r = run_pipeline(html, "http://10.0.0.1/", &Options::default());
+        let internal: Vec<bool> = r.links.iter().map(|l| l.is_internal).collect();
+        assert_eq!(internal, vec![false, true]);
+    }
+
+    #[test]
+    fn test_link_rel_target_and_aria_label() {
+        let html = "<p><a href=\"/a\">Plain</a> \
+            <a href=\"https://shop.example/x\" rel=\"Nofollow  sponsored\" target=\"_blank\">Deal</a> \
+            <a href=\"/a\" rel=\"ugc\">Again</a> \
+            <a href=\"/search\" aria-label=\"Search the site\"><i class=\"icon\"></i></a></p>";
+        let r = run_pipeline(html, "https://a.com", &Options::default());
+        assert_eq!(r.links.len(), 4);
+        assert!(r.links[0].rel.is_empty());
+        assert_eq!(r.links[1].rel, vec!["nofollow", "sponsored"]);
+        assert_eq!(r.links[1].target, "_blank");
+        assert_eq!(r.links[2].rel, vec!["ugc"]);
+        assert_eq!(r.links[3].text, "Search the site");
+        assert_eq!(r.links[3].aria_label, "Search the site");
+        assert!(r
+            .raw_markdown
+            .contains("[Search the site](https://a.com/search)"));
+    }
+
+    #[test]
+    fn test_links_with_brackets_and_parentheses() {
+        let html = "<p><a href=\"/wiki/Foo_(bar)\">Foo (bar)</a>, \
+            <a href=\"/notes\">[draft] notes]</a> and \
+            <a href=\"/q?a=1)\" title=\"Say &quot;hi&quot; (now)\">odd)</a>.</p>";
+        let r = run_pipeline(html, "https://a.com", &Options::default());
+        let links: Vec<(&str, &str)> = r
+            .links
+            .iter()
+            .map(|l| (l.text.as_str(), l.url.as_str()))
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                ("Foo (bar)", "https://a.com/wiki/Foo_(bar)"),
+                ("[draft] notes]", "https://a.com/notes"),
+                ("odd)", "https://a.com/q?a=1)"),
+            ]
+        );
+        assert_eq!(r.links[2].title, "Say \"hi\" (now)");
+        assert_eq!(
+            r.markdown_with_citations,
+            "Foo (bar)[1], \\[draft\\] notes\\][2] and odd)[3]."
+        );
+        assert!(r
+            .raw_markdown
+            .contains("[Foo (bar)](<https://a.com/wiki/Foo_(bar)>)"));
+        assert!(!r.raw_markdown.contains(LINK_OPEN));
+    }
+
+    #[test]
+    fn test_image_urls_resolved() {
+        let r = run_pipeline(
+            "<p><img src=\"img/a.png\" alt=\"A\"></p>",
+            "https://a.com/docs/page",
+            &Options::default(),
+        );
+        assert_eq!(r.images[0].url, "https://a.com/docs/img/a.png");
+
+        let base = Url::parse("https://a.com/docs/").ok();
+        let images = extract_images("![B](../b.png) ![C](/c.png)", &base, &HashMap::new());
+        let urls: Vec<&str> = images.iter().map(|i| i.url.as_str()).collect();
+        assert_eq!(urls, vec!["https://a.com/b.png", "https://a.com/c.png"]);
+    }
+
+    #[test]
+    fn test_image_dimensions_and_format() {
+        let html = "<article><p>Story<img src=\"https://t.example/p.gif?id=9\" width=\"1\" height=\"1\" alt=\"\"></p>\
+            <p><img src=\"/favicon.ico\" width=\"16\" height=\"16\" alt=\"Site\"> \
+            <img src=\"/hero.JPG?v=2\" style=\"width: 1200px; height:630px\" alt=\"Hero\"> \
+            <img src=\"/photo\" width=\"50%\" alt=\"Photo\"></p></article>";
+        let r = run_pipeline(html, "https://a.com", &Options::default());
+        assert!(!r.raw_markdown.contains("p.gif"));
+        let summary: Vec<(Option<u32>, Option<u32>, bool, &str)> = r
+            .images
+            .iter()
+            .map(|i| (i.width, i.height, i.is_probably_icon, i.format.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Some(16), Some(16), true, "ico"),
+                (Some(1200), Some(630), false, "jpg"),
+                (None, None, false, ""),
+            ]
+        );
+
+        let opts = Options {
+            skip_tracking_pixels: false,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "https://a.com", &opts);
+        assert_eq!(r.images.len(), 4);
+        assert!(r.images[0].is_probably_icon);
+        assert_eq!(r.images[0].format, "gif");
+        assert_eq!(image_format("data:image/svg+xml;base64,AAAA"), "svg");
+    }
+
+    #[test]
+    fn test_data_uri_images() {
+        let big = format!("data:image/png;base64,{}", "iVBORw0KGgo".repeat(4000));
+        let small = "data:image/gif;base64,R0lGODlhAQABAAAAACw=";
+        let html = format!(
+            "<p>Intro</p><p><img src=\"{big}\" alt=\"One\"> \
+             <img src=\"{big}\" data-src=\"/two.png\" alt=\"Two\"> \
+             <img src=\"{big}\" srcset=\"/three-1x.png 1x, /three-2x.png 2x\" alt=\"Three\"> \
+             <img src=\"{small}\" alt=\"Dot\"></p>"
+        );
+
+        let r = run_pipeline(&html, "https://a.com", &Options::default());
+        assert!(r.raw_markdown.len() < 400);
+        assert!(!r.raw_markdown.contains("One"));
+        assert!(r.raw_markdown.contains("![Two](https://a.com/two.png)"));
+        assert!(r
+            .raw_markdown
+            .contains("![Three](https://a.com/three-2x.png)"));
+        assert!(r.raw_markdown.contains(&format!("![Dot]({small})")));
+        assert_eq!(r.images.len(), 3);
+
+        let opts = Options {
+            data_uri_images: DataUriImages::Truncate,
+            data_uri_limit: 32,
+            ..Options::default()
+        };
+        let r = run_pipeline(&html, "https://a.com", &opts);
+        assert!(r
+            .raw_markdown
+            .contains("![One](data:image/png;base64,iVBORw0KGg…)"));
+        assert!(r.markdown_with_citations.len() < 600);
+
+        let opts = Options {
+            data_uri_images: DataUriImages::Keep,
+            ..Options::default()
+        };
+        let r = run_pipeline(&html, "https://a.com", &opts);
+        assert!(r.raw_markdown.contains(&big));
+    }
+
+    #[test]
+    fn test_background_images() {
+        let html = "<main><section class=\"hero\" aria-label=\"Summer sale\" \
+            style=\"color: red; background-image: url('/hero.jpg')\"><h1>Sale</h1></section>\
+            <div style=\"background: #fff url(&quot;img/tile.webp&quot;) repeat\"><p>Tiles</p></div>\
+            <div style=\"BACKGROUND-IMAGE: url( https://cdn.example.com/bg.png )\"><p>Plain</p></div>\
+            <div data-bg=\"/lazy.avif\"><p>Lazy</p></div></main>";
+        let r = run_pipeline(html, "https://a.com/shop/", &Options::default());
+        assert!(r.images.is_empty());
+
+        let opts = Options {
+            background_images: true,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "https://a.com/shop/", &opts);
+        let images: Vec<(&str, &str)> = r
+            .images
+            .iter()
+            .map(|i| (i.url.as_str(), i.alt.as_str()))
+            .collect();
+        assert_eq!(
+            images,
+            vec![
+                ("https://a.com/hero.jpg", "Summer sale"),
+                ("https://a.com/shop/img/tile.webp", ""),
+                ("https://cdn.example.com/bg.png", ""),
+                ("https://a.com/lazy.avif", ""),
+            ]
+        );
+        assert!(!r.raw_markdown.contains("hero.jpg"));
+    }
+
+    #[test]
+    fn test_heading_outline() {
+        let html = "<nav><h2>Menu</h2></nav><main><h1>Guide</h1><p>Intro</p>\
+            <h2>Setup</h2><p>a</p><h3>Notes</h3><p>b</p>\
+            <h2>Usage</h2><p>c</p><h3>Notes</h3><p>d</p><h3 id=\"faq\">FAQ<a class=\"headerlink\" href=\"#faq\">¶</a></h3>\
+            <h2>C# &amp; <code>F#</code> tips!</h2><p>e</p><h3>Notes</h3><p>f</p></main>";
+        let r = run_pipeline(html, "", &Options::default());
+        let outline: Vec<(u8, &str, usize, &str)> = r
+            .headings
+            .iter()
+            .map(|h| (h.level, h.text.as_str(), h.index, h.anchor.as_str()))
+            .collect();
+        assert_eq!(
+            outline,
+            vec![
+                (1, "Guide", 0, "guide"),
+                (2, "Setup", 1, "setup"),
+                (3, "Notes", 2, "notes"),
+                (2, "Usage", 3, "usage"),
+                (3, "Notes", 4, "notes-1"),
+                (3, "FAQ", 5, "faq"),
+                (2, "C# & F# tips!", 6, "c--f-tips"),
+                (3, "Notes", 7, "notes-2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_table_of_contents() {
+        let html = "<main><h1>Manual</h1><p>x</p><h2>Install</h2><p>x</p>\
+            <h3>Linux</h3><p>x</p><h4>Debian</h4><p>x</p><h3>macOS</h3><p>x</p>\
+            <h2>Usage</h2><p>x</p><h3>Linux</h3><p>x</p><h2></h2><h2>[Beta] API</h2><p>x</p></main>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(
+            r.toc_markdown,
+            "- [Manual](#manual)\n\
+             \x20 - [Install](#install)\n\
+             \x20   - [Linux](#linux)\n\
+             \x20   - [macOS](#macos)\n\
+             \x20 - [Usage](#usage)\n\
+             \x20   - [Linux](#linux-1)\n\
+             \x20 - [\\[Beta\\] API](#beta-api)\n"
+        );
+
+        let opts = Options {
+            toc_max_depth: 4,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert!(r.toc_markdown.contains("      - [Debian](#debian)\n"));
+    }
+
+    #[test]
+    fn test_chunks_follow_sections() {
+        let para = |n: usize| format!("<p>{}</p>", "word ".repeat(n));
+        let html = format!(
+            "<main><h1>Guide</h1>{}<h2>Installation</h2>{}<h3>From source</h3>{}\
+             <h3>Building</h3>{}{}{}<h2>Usage</h2>{}</main>",
+            para(60),
+            para(5),
+            para(20),
+            para(60),
+            para(60),
+            para(60),
+            para(60),
+        );
+        let opts = Options {
+            max_chunk_chars: 700,
+            min_chunk_chars: 100,
+            ..Options::default()
+        };
+        let r = run_pipeline(&html, "", &opts);
+        let paths: Vec<Vec<&str>> = r
+            .chunks
+            .iter()
+            .map(|c| c.heading_path.iter().map(String::as_str).collect())
+            .collect();
+        // The short Installation section absorbs From source; Building is
+        // too long and splits between paragraphs
+        assert_eq!(
+            paths,
+            vec![
+                vec!["Guide"],
+                vec!["Guide", "Installation"],
+                vec!["Guide", "Installation", "Building"],
+                vec!["Guide", "Installation", "Building"],
+                vec!["Guide", "Usage"],
+            ]
+        );
+        assert!(r.chunks[1].text.starts_with("## Installation"));
+        assert!(r.chunks[1].text.contains("### From source"));
+        assert!(r.chunks.iter().all(|c| c.text.chars().count() <= 700));
+        for c in &r.chunks {
+            let slice: String = r
+                .clean_markdown
+                .chars()
+                .skip(c.start)
+                .take(c.end - c.start)
+                .collect();
+            assert_eq!(slice, c.text);
+        }
+    }
+
+    #[test]
+    fn test_chunks_keep_code_fences_whole() {
+        let code = (0..40)
+            .map(|i| format!("# step {}\n\nrun({})\n", i, i))
+            .collect::<String>();
+        let html = format!(
+            "<main><h2>Build</h2><p>Intro text.</p><pre><code>{}</code></pre><p>After.</p></main>",
+            code
+        );
+        let opts = Options {
+            max_chunk_chars: 200,
+            min_chunk_chars: 0,
+            ..Options::default()
+        };
+        let r = run_pipeline(&html, "", &opts);
+        let fenced: Vec<&chunks::Chunk> =
+            r.chunks.iter().filter(|c| c.text.contains("```")).collect();
+        assert_eq!(fenced.len(), 1);
+        assert!(fenced[0].text.starts_with("```") && fenced[0].text.ends_with("```"));
+        assert!(fenced[0].text.contains("# step 0") && fenced[0].text.contains("# step 39"));
+        assert!(r.chunks.iter().all(|c| c.heading_path == vec!["Build"]));
+    }
+
+    #[test]
+    fn test_chunks_large_input() {
+        // As much multi-byte text as max_output_bytes lets through by
+        // default: offsets must not be recounted from the start per chunk
+        let paragraph = "Größe über Maß, ohne Ärger. ".repeat(40);
+        let mut md = String::new();
+        let mut sections = 0;
+        let limit = Options::default().max_output_bytes;
+        while md.len() < limit {
+            md.push_str(&format!("## Schritt {}\n\n{}\n\n", sections, paragraph));
+            sections += 1;
+        }
+        let start = std::time::Instant::now();
+        let chunks = chunks::chunk_markdown(&md, 2000, 200);
+        assert!(start.elapsed().as_secs() < 5, "{:?}", start.elapsed());
+        assert_eq!(chunks.len(), sections);
+        assert!(chunks
+            .iter()
+            .all(|c| c.end - c.start == c.text.chars().count()));
+        let last = chunks.last().unwrap();
+        assert_eq!(last.heading_path, [format!("Schritt {}", sections - 1)]);
+        assert_eq!(last.end, md.trim_end().chars().count());
+        let tail: String = md.chars().skip(last.start).collect();
+        assert_eq!(tail.trim_end(), last.text);
+    }
+
+    #[test]
+    fn test_content_stats() {
+        let html = "<main><h1>Report</h1><p>Read the <a href=\"https://a.com/x\">full results</a> \
+            at https://a.com/x today.</p><h2>Data</h2>\
+            <table><tr><th>Year</th><th>Total</th></tr><tr><td>2024</td><td>12</td></tr></table>\
+            <h2>Code</h2><pre><code>let words = not counted here;</code></pre>\
+            <ul><li>One item</li></ul><p><img src=\"/c.png\" alt=\"Chart\"></p></main>";
+        let r = run_pipeline(html, "", &Options::default());
+        let s = &r.stats;
+        // Report, Read the full results at today, Data, Year Total 2024 12,
+        // Code, One item, Chart
+        assert_eq!(s.word_count, 16);
+        assert_eq!(s.link_count, 1);
+        assert_eq!(s.image_count, 1);
+        assert_eq!(s.table_count, 1);
+        assert_eq!(s.code_block_count, 1);
+        assert_eq!(s.headings_by_level, [1, 2, 0, 0, 0, 0]);
+        assert_eq!(s.reading_time_minutes, 1);
+        assert_eq!(s.char_count, r.markdown_plain.chars().count());
+        assert!((s.content_ratio - r.raw_markdown.len() as f64 / html.len() as f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_deferred_variants() {
+        let html = r#"<html lang="en"><body><article><h1>Title</h1>
+            <p>See <a href="/a">the docs</a> and <a href="/b">[more](x)</a>.</p>
+            <a href="/c"><img src="/i.png" alt="pic"></a></article></body></html>"#;
+        for style in [CitationStyle::Bracket, CitationStyle::Footnote] {
+            let opts = Options {
+                citation_style: style,
+                ..Options::default()
+            };
+            let eager = run_pipeline(html, "https://example.com/", &opts);
+            let lazy = convert_document(
+                html,
+                "https://example.com/",
+                &opts,
+                false,
+                Variants::Deferred,
+            );
+            assert!(lazy.markdown_plain.is_empty());
+            assert!(lazy.references_markdown.is_empty());
+            assert_eq!(lazy.stats.char_count, eager.stats.char_count);
+            assert_eq!(strip_links(&lazy.raw_markdown), eager.markdown_plain);
+            let references = references_markdown(&lazy.raw_markdown, &lazy.links, style);
+            assert_eq!(references, eager.references_markdown);
+            assert_eq!(
+                markdown_references(&lazy.markdown_with_citations, &references),
+                eager.markdown_references
+            );
+        }
+    }
+
+    #[test]
+    fn test_content_selectors() {
+        let html =
+            "<body><main><p>Wrapper chrome</p><div id=\"docs-body\"><p>Real docs</p></div></main>\
+            <div class=\"comments\"><p>Nice post</p></div></body>";
+        let opts = Options {
+            content_selectors: parse_selectors("content_selectors", &["#docs-body".to_string()])
+                .unwrap(),
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert_eq!(r.raw_markdown, "Real docs");
+        assert_eq!(r.diagnostics.main_selector, "#docs-body");
+
+        // No match: the built-in list still applies unless disabled
+        let missing =
+            parse_selectors("content_selectors", &[".markdown-body".to_string()]).unwrap();
+        let opts = Options {
+            content_selectors: missing.clone(),
+            ..Options::default()
+        };
+        assert_eq!(
+            run_pipeline(html, "", &opts).diagnostics.main_selector,
+            "main"
+        );
+        let opts = Options {
+            content_selectors: missing,
+            default_content_selectors: false,
+            ..Options::default()
+        };
+        assert_eq!(run_pipeline(html, "", &opts).diagnostics.main_selector, "");
+
+        let err = parse_selectors(
+            "content_selectors",
+            &["main".to_string(), "div[[".to_string()],
+        )
+        .unwrap_err();
+        assert!(
+            err.starts_with("invalid content_selectors selector \"div[[\""),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_keep_selectors() {
+        let html = "<body><nav class=\"menu\"><a href=\"/\">Home</a></nav>\
+            <nav class=\"toc\"><a href=\"/api/parse\">parse()</a></nav>\
+            <aside><nav class=\"toc\"><a href=\"/api/render\">render()</a></nav><p>Ad</p></aside>\
+            <p>Reference docs.</p></body>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(!r.raw_markdown.contains("parse()"));
+
+        let opts = Options {
+            keep_selectors: parse_selectors("keep_selectors", &["nav.toc".to_string()]).unwrap(),
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert!(
+            r.raw_markdown.contains("[parse()](/api/parse)"),
+            "{}",
+            r.raw_markdown
+        );
+        // Kept wins inside a dropped ancestor, but only for its own subtree
+        assert!(
+            r.raw_markdown.contains("[render()](/api/render)"),
+            "{}",
+            r.raw_markdown
+        );
+        assert!(!r.raw_markdown.contains("Home"));
+        assert!(!r.raw_markdown.contains("Ad"));
+        assert!(r.raw_markdown.contains("Reference docs."));
+    }
+
+    #[test]
+    fn test_role_and_id_clutter() {
+        let html = "<body>\
+            <div role=\"banner\"><p>Site logo</p></div>\
+            <div id=\"sidebar\"><p>Popular posts</p></div>\
+            <div role=\"navigation\"><a href=\"/\">Home</a></div>\
+            <div role=\"search\"><p>Search the site</p></div>\
+            <div role=\"main\" class=\"nav\"><h1>Title</h1><p>Body text.</p>\
+              <div role=\"complementary\"><p>See also</p></div></div>\
+            <div role=\"contentinfo\"><p>Copyright</p></div></body>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.diagnostics.main_selector, "[role=\"main\"]");
+        assert_eq!(r.raw_markdown, "# Title\n\nBody text.");
+
+        // The landmark roles are dropped even when walking the whole page
+        let mut diagnostics = Diagnostics::default();
+        let doc = Html::parse_document(html);
+        let skip = build_skip_set(&doc, &HashSet::new(), &SkipSet::default(), &mut diagnostics);
+        let body = doc.select(&sel!("body")).next().unwrap();
+        let kept: Vec<_> = body
+            .children()
+            .filter_map(ElementRef::wrap)
+            .filter(|e| !skip.contains(e, &HashSet::new()))
+            .map(|e| e.value().attr("role").unwrap_or(""))
+            .collect();
+        assert_eq!(kept, ["main"]);
+    }
+
+    #[test]
+    fn test_overlay_removal() {
+        let article = "Bake the dough for twelve minutes until golden. ".repeat(20);
+        let html = format!(
+            "<body><div id=\"onetrust-consent-sdk\"><div id=\"onetrust-banner-sdk\">\
+             <p>We use cookies to improve your experience.</p></div></div>\
+             <div class=\"newsletter-modal\"><p>Subscribe for updates</p></div>\
+             <div class=\"post cookie-recipes\"><h1>Chocolate chip cookies</h1><p>{}</p></div></body>",
+            article
+        );
+        let r = run_pipeline(&html, "", &Options::default());
+        assert!(
+            !r.raw_markdown.contains("We use cookies"),
+            "{}",
+            r.raw_markdown
+        );
+        assert!(!r.raw_markdown.contains("Subscribe"));
+        // The article's class names cookies too, but it is most of the page
+        assert!(r.raw_markdown.starts_with("# Chocolate chip cookies"));
+        assert_eq!(r.diagnostics.overlay_nodes, 5);
+
+        // Walk the whole body so the banners are in reach
+        let opts = Options {
+            remove_overlays: false,
+            use_readability_fallback: false,
+            ..Options::default()
+        };
+        let r = run_pipeline(&html, "", &opts);
+        assert!(r.raw_markdown.contains("We use cookies"));
+        assert_eq!(r.diagnostics.overlay_nodes, 0);
+    }
+
+    #[test]
+    fn test_overlay_removed_in_fallback() {
+        let html = "<body><main></main><div class=\"gdpr-banner\"><p>Accept all cookies</p></div>\
+            <div><p>Story text that only the full walk finds, long enough to outweigh the banner.</p></div></body>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.diagnostics.fallback_reason, Some(FallbackReason::Empty));
+        assert!(
+            r.raw_markdown.starts_with("Story text"),
+            "{}",
+            r.raw_markdown
+        );
+        assert!(!r.raw_markdown.contains("Accept all cookies"));
+    }
+
+    #[test]
+    fn test_aria_hidden_and_inline_style() {
+        for hidden in [
+            "aria-hidden=\"true\"",
+            "aria-hidden=\"TRUE\"",
+            "style=\"display:none\"",
+            "style=\"color: red; DISPLAY : None !important\"",
+            "style=\"visibility: hidden;\"",
+        ] {
+            let html = format!(
+                "<body><main><p>Shown</p><div {}><p>Flyout menu</p></div></main></body>",
+                hidden
+            );
+            let r = run_pipeline(&html, "", &Options::default());
+            assert_eq!(r.raw_markdown, "Shown", "{}", hidden);
+            assert_eq!(r.diagnostics.hidden_nodes, 2, "{}", hidden);
+        }
+
+        let html = "<body><main><div style=\"display: block\" aria-hidden=\"false\">\
+            <p>Visible</p></div></main></body>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.raw_markdown, "Visible");
+    }
+
+    #[test]
+    fn test_readability_fallback() {
+        let farm = |name: &str| {
+            let links: String = (0..15)
+                .map(|i| {
+                    format!(
+                        "<li><a href=\"/{0}/{1}\">{0} story number {1}, updated</a></li>",
+                        name, i
+                    )
+                })
+                .collect();
+            format!("<div class=\"{}\"><ul>{}</ul></div>", name, links)
+        };
+        let article = "<div class=\"x9f story\"><h1>Harbor reopens</h1>\
+            <p>The harbor reopened on Monday, after three weeks of repairs, officials said.</p>\
+            <p>Ferries resumed service at dawn, and the first boats left on time, with full decks.</p>\
+            <p>Local shops, which had closed during the work, expect a busy weekend.</p></div>";
+        let html = format!(
+            "<body>{}<div>{}</div>{}</body>",
+            farm("trending"),
+            article,
+            farm("more")
         );
-        assert!(r.raw_markdown.contains("[Example](https://example.com)"));
-        assert_eq!(r.links.len(), 1);
-        assert_eq!(r.links[0].url, "https://example.com");
-        assert_eq!(r.links[0].citation_number, 1);
+
+        let r = run_pipeline(&html, "", &Options::default());
+        assert_eq!(r.diagnostics.main_selector, "readability");
+        assert_eq!(r.diagnostics.main_node, "div.x9f.story");
+        assert!(r.raw_markdown.starts_with("# Harbor reopens"));
+        assert!(!r.raw_markdown.contains("trending story"));
+
+        let opts = Options {
+            use_readability_fallback: false,
+            ..Options::default()
+        };
+        let r = run_pipeline(&html, "", &opts);
+        assert_eq!(r.diagnostics.main_selector, "body");
+        assert!(r.raw_markdown.contains("trending story"));
     }
 
     #[test]
-    fn test_relative_url_resolution() {
-        let r = run_pipeline(
-            r#"<p><a href="/page">Link</a></p>"#,
-            "https://example.com",
-            true,
+    fn test_main_candidates_by_text_length() {
+        let html =
+            "<body><main></main><div class=\"post-content\"><p>The post itself.</p></div></body>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.diagnostics.main_selector, ".post-content");
+        assert_eq!(r.raw_markdown, "The post itself.");
+
+        let teasers: String = (1..=5)
+            .map(|i| {
+                format!(
+                    "<article><a href=\"/p/{0}\">Teaser {0}</a><p>Short blurb.</p></article>",
+                    i
+                )
+            })
+            .collect();
+        let html = format!(
+            "<body>{}<article><h1>Full story</h1><p>{}</p></article></body>",
+            teasers,
+            "Every paragraph of the real article. ".repeat(5)
         );
-        assert!(r.raw_markdown.contains("https://example.com/page"));
+        let r = run_pipeline(&html, "", &Options::default());
+        assert_eq!(r.diagnostics.main_selector, "article");
+        assert!(
+            r.raw_markdown.starts_with("# Full story"),
+            "{}",
+            r.raw_markdown
+        );
+
+        // Hidden duplicates don't count towards the length
+        let html = "<body><article><p>Shown summary.</p></article>\
+            <article><p>Visible.</p><div hidden><p>A much longer hidden duplicate of the text.</p></div></article></body>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.raw_markdown, "Shown summary.");
     }
 
     #[test]
-    fn test_image() {
-        let r = run_pipeline(
-            r#"<img src="test.png" alt="Test Image" title="A test">"#,
-            "",
-            true,
+    fn test_fallback_thresholds() {
+        // About 2% markdown: fine by default, too sparse at a 5% minimum
+        let html = format!(
+            "<body><main><p>{}</p></main><div>{}</div></body>",
+            "Main text. ".repeat(50),
+            "<span></span>".repeat(2000)
+        );
+        let r = run_pipeline(&html, "", &Options::default());
+        assert_eq!(r.diagnostics.fallback_reason, None);
+        let opts = Options {
+            min_ratio: 0.05,
+            ..Options::default()
+        };
+        let r = run_pipeline(&html, "", &opts);
+        assert_eq!(
+            r.diagnostics.fallback_reason,
+            Some(FallbackReason::LowRatio)
+        );
+
+        let opts = Options {
+            min_md_len: 1000,
+            ..Options::default()
+        };
+        let r = run_pipeline(&html, "", &opts);
+        assert_eq!(
+            r.diagnostics.fallback_reason,
+            Some(FallbackReason::TooShort)
         );
-        assert!(r.raw_markdown.contains("![Test Image](test.png \"A test\")"));
-        assert_eq!(r.images.len(), 1);
     }
 
     #[test]
-    fn test_skip_script_style() {
-        let r = run_pipeline(
-            "<p>Keep</p><script>bad()</script><style>.x{}</style><p>Also keep</p>",
-            "",
-            true,
+    fn test_site_rules() {
+        let html = &format!(
+            "<body><main><p>{}</p></main><table><tr><td><a href=\"/thread/1\">First thread</a>\
+             </td></tr></table>{}</body>",
+            "Top stories today. ".repeat(30),
+            "<span></span>".repeat(400)
         );
-        assert!(r.raw_markdown.contains("Keep"));
-        assert!(r.raw_markdown.contains("Also keep"));
-        assert!(!r.raw_markdown.contains("bad()"));
-        assert!(!r.raw_markdown.contains(".x{}"));
+        let rule = SiteRule {
+            host: "forum.example".to_string(),
+            required: "/thread/".to_string(),
+        };
+        let opts = Options {
+            site_rules: vec![rule.clone()],
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "https://www.forum.example/", &opts);
+        assert_eq!(
+            r.diagnostics.fallback_reason,
+            Some(FallbackReason::SiteSpecific)
+        );
+        assert_eq!(r.diagnostics.site_rule, Some(rule));
+        assert!(r.raw_markdown.contains("First thread"));
+
+        // Other hosts and the defaults are unaffected
+        let r = run_pipeline(html, "https://notforum.example/", &opts);
+        assert_eq!(r.diagnostics.fallback_reason, None);
+        let r = run_pipeline(html, "https://www.forum.example/", &Options::default());
+        assert_eq!(r.diagnostics.fallback_reason, None);
+        assert_eq!(r.diagnostics.site_rule, None);
     }
 
     #[test]
-    fn test_code_and_pre() {
-        let r = run_pipeline(
-            "<p>Use <code>foo()</code> and:</p><pre>bar()\nbaz()</pre>",
-            "",
-            true,
+    fn test_dedupe_blocks() {
+        let menu: String = (1..=20)
+            .map(|i| format!("<li><a href=\"/section/{0}\">Section {0}</a></li>", i))
+            .collect();
+        let html = format!(
+            "<body><div class=\"desktop\"><ul>{0}</ul></div><p>Read more</p>\
+             <p>Welcome to the site.</p><p>Read more</p><div class=\"mobile\"><ul>{0}</ul></div></body>",
+            menu
+        );
+        let opts = Options {
+            dedupe_blocks: true,
+            ..Options::default()
+        };
+        let r = run_pipeline(&html, "", &opts);
+        assert_eq!(
+            r.raw_markdown.matches("[Section 20](/section/20)").count(),
+            1
+        );
+        // Short repeated phrases stay
+        assert_eq!(r.raw_markdown.matches("Read more").count(), 2);
+        assert_eq!(r.links.len(), 20);
+
+        let r = run_pipeline(&html, "", &Options::default());
+        assert_eq!(
+            r.raw_markdown.matches("[Section 20](/section/20)").count(),
+            2
         );
-        assert!(r.raw_markdown.contains("`foo()`"));
-        assert!(r.raw_markdown.contains("```\nbar()\nbaz()\n```"));
     }
 
     #[test]
-    fn test_table_data() {
+    fn test_strip_boilerplate() {
+        let html = "<body><main><h1>Sharing tips</h1>\
+            <p>Here is how to share this article with a friend.</p>\
+            <p><a href=\"/share\">Share this article</a></p>\
+            <p><strong>Advertisement</strong></p><p>Share on Twitter</p>\
+            <p>Powered by Acme</p><pre><code>Read more</code></pre></main></body>";
+        let opts = Options {
+            strip_boilerplate: true,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert_eq!(
+            r.raw_markdown,
+            "# Sharing tips\n\nHere is how to share this article with a friend.\n\n\
+             Powered by Acme\n\n```\nRead more\n```"
+        );
+        assert!(r.links.is_empty());
+
+        let mut opts = opts;
+        opts.boilerplate_phrases.push("powered by acme".to_string());
+        let r = run_pipeline(html, "", &opts);
+        assert!(!r.raw_markdown.contains("Powered by"));
+
+        let r = run_pipeline(html, "", &Options::default());
+        assert!(r.raw_markdown.contains("Advertisement"));
+    }
+
+    #[test]
+    fn test_language() {
+        let page = |head: &str, body: &str| {
+            format!(
+                "<html{}><body><main><p>{}</p></main></body></html>",
+                head, body
+            )
+        };
+        let text = "Plain English text that is long enough to guess from.";
+        let r = run_pipeline(&page(" lang=\"de-AT\"", text), "", &Options::default());
+        assert_eq!(r.language, "de-AT");
+        let html = format!(
+            "<html><head><meta property=\"og:locale\" content=\"fr_FR\"></head>{}",
+            page("", text)
+        );
+        assert_eq!(
+            run_pipeline(&html, "", &Options::default()).language,
+            "fr-FR"
+        );
+        let html = format!(
+            "<html><head><meta http-equiv=\"Content-Language\" content=\"nl, en\"></head>{}",
+            page("", text)
+        );
+        assert_eq!(run_pipeline(&html, "", &Options::default()).language, "nl");
+
+        // Undeclared: guessed from the script
         let r = run_pipeline(
-            "<table><tr><th>Name</th><th>Age</th></tr><tr><td>Alice</td><td>30</td></tr></table>",
+            &page(
+                "",
+                "東京は日本の首都であり、世界で最も人口の多い都市圏のひとつです。",
+            ),
             "",
-            true,
+            &Options::default(),
+        );
+        assert_eq!(r.language, "ja");
+        let r = run_pipeline(
+            &page("", "Москва является столицей России и крупнейшим городом."),
+            "",
+            &Options::default(),
+        );
+        assert_eq!(r.language, "und-Cyrl");
+        assert_eq!(
+            run_pipeline(&page("", "Hi"), "", &Options::default()).language,
+            ""
         );
-        assert!(r.raw_markdown.contains("| Name | Age |"));
-        assert!(r.raw_markdown.contains("| --- | --- |"));
-        assert!(r.raw_markdown.contains("| Alice | 30 |"));
     }
 
     #[test]
-    fn test_empty_input() {
-        let r = run_pipeline("", "", true);
-        assert!(r.raw_markdown.is_empty());
+    fn test_extract_comments() {
+        let html = "<body><article><h1>Post</h1><p>The post body.</p></article>\
+            <div id=\"disqus_thread\"><p>First! <a href=\"https://spam.example/\">Visit</a></p></div>\
+            <section aria-label=\"Comments\"><ol class=\"comment-list\"><li>Great read.</li></ol></section>\
+            </body>";
+        let opts = Options {
+            extract_comments: true,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert_eq!(r.raw_markdown, "# Post\n\nThe post body.");
+        assert_eq!(
+            r.comments_markdown,
+            "First! [Visit](https://spam.example/)\n\n1. Great read."
+        );
+        assert!(r.links.is_empty());
+
+        // Comments stay out of the fallback walks too
+        let html = "<body><main></main><p>Loose text</p>\
+            <div class=\"comments\"><p>A comment</p></div></body>";
+        let r = run_pipeline(html, "", &opts);
+        assert_eq!(r.raw_markdown, "Loose text");
+        assert_eq!(r.comments_markdown, "A comment");
+
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(r.comments_markdown, "");
     }
 
     #[test]
-    fn test_plain_strips_links() {
-        let r = run_pipeline(
-            r#"<p><a href="https://example.com">Click</a> here</p>"#,
-            "",
-            true,
+    fn test_html_comments_never_render() {
+        let html = "<body><main><!-- wp:paragraph --><p>Text<!-- ab:variant=B --></p><!-- /wp:paragraph -->\
+            <!--[if IE]><p>Upgrade your browser</p><![endif]--><p><![CDATA[ raw ]]>After</p>\
+            <h2>Head<!-- x --></h2><pre><code>a<!-- hidden -->b</code></pre>\
+            <table><tr><th>H</th></tr><tr><td>c<!-- cell --></td></tr></table></main></body>";
+        let r = run_pipeline(html, "", &Options::default());
+        assert_eq!(
+            r.raw_markdown,
+            "Text\n\nAfter\n\n## Head\n\n```\nab\n```\n\n| H |\n| --- |\n| c |"
+        );
+        assert!(r.html_comments.is_empty());
+
+        let opts = Options {
+            collect_comments: true,
+            ..Options::default()
+        };
+        let r = run_pipeline(html, "", &opts);
+        assert_eq!(
+            r.html_comments,
+            [
+                "wp:paragraph",
+                "ab:variant=B",
+                "/wp:paragraph",
+                "[if IE]><p>Upgrade your browser</p><![endif]",
+                "[CDATA[ raw ]]",
+                "x",
+                "hidden",
+                "cell"
+            ]
         );
-        assert!(r.markdown_plain.contains("Click"));
-        assert!(!r.markdown_plain.contains("example.com"));
     }
 
     #[test]
-    fn test_citations() {
-        let r = run_pipeline(
-            r#"<p><a href="https://a.com">A</a> and <a href="https://b.com">B</a></p>"#,
+    fn test_fragment() {
+        let r = run_fragment_pipeline(
+            "<td>Price: <b>$5</b> <span hidden>internal</span></td>",
             "",
-            true,
+            &Options::default(),
         );
-        assert!(r.markdown_with_citations.contains("A[1]"));
-        assert!(r.markdown_with_citations.contains("B[2]"));
-        assert!(r.references_markdown.contains("[1]: https://a.com"));
-        assert!(r.references_markdown.contains("[2]: https://b.com"));
+        assert_eq!(r.raw_markdown, "Price: **$5**");
+        assert_eq!(r.diagnostics.fallback_reason, None);
+
+        // A short list inside a <nav> would be clutter (and sparse) on a page
+        let html = "<nav><ul><li><a href=\"/a\">First</a></li><li>Second</li></ul></nav>";
+        let r = run_fragment_pipeline(html, "https://example.com/", &Options::default());
+        assert_eq!(r.raw_markdown, "- [First](https://example.com/a)\n- Second");
+        assert_eq!(r.links.len(), 1);
     }
 
     #[test]
-    fn test_main_content_detection() {
-        let html = r#"
-            <html><body>
-                <nav><a href="/home">Home</a></nav>
-                <main><h1>Main Title</h1><p>Main content</p></main>
-                <footer>Footer stuff</footer>
-            </body></html>
-        "#;
-        let r = run_pipeline(html, "", true);
-        assert!(r.raw_markdown.contains("Main Title"));
-        assert!(r.raw_markdown.contains("Main content"));
-        // Nav and footer should be filtered out
-        assert!(!r.raw_markdown.contains("Home"));
-        assert!(!r.raw_markdown.contains("Footer stuff"));
+    fn test_selection() {
+        let html = "<html><head><base href=\"https://example.com/releases/\"></head><body>\
+            <div class=\"release-note\"><h2>v2.0</h2><p>New <a href=\"v2\">API</a>.</p>\
+              <div class=\"release-note\"><p>Nested patch note</p></div></div>\
+            <nav>Menu</nav><div class=\"release-note\"><h2>v1.0</h2><p>First release.</p></div></body></html>";
+        let results = run_selection_pipeline(html, &sel!(".release-note"), "", &Options::default());
+        let markdown: Vec<&str> = results.iter().map(|r| r.raw_markdown.as_str()).collect();
+        assert_eq!(
+            markdown,
+            [
+                "## v2.0\n\nNew [API](https://example.com/releases/v2).\n\nNested patch note",
+                "## v1.0\n\nFirst release."
+            ]
+        );
+        assert_eq!(results[0].links.len(), 1);
+
+        assert!(
+            run_selection_pipeline(html, &sel!(".missing"), "", &Options::default()).is_empty()
+        );
+        let err = parse_selectors("selector", &["div >".to_string()]).unwrap_err();
+        assert!(err.contains("\"div >\""), "{}", err);
     }
 
     #[test]
-    fn test_fallback_sparse() {
-        // Large HTML but tiny main content → should trigger fallback
-        let padding = "<div>x</div>".repeat(500);
+    fn test_link_pipeline() {
+        let html = "<body><nav><a href=\"/home\">Home</a></nav><main>\
+            <p>See <a href=\"/docs\" rel=\"help\">the docs</a>, \
+            <a href=\"https://blog.example.com/post\">the blog</a> and \
+            <a href=\"https://other.org/\">elsewhere</a>.</p>\
+            <p hidden><a href=\"/secret\">Secret</a></p></main></body>";
+        let base = "https://example.com/";
+        let opts = Options::default();
+        let links = run_link_pipeline(html, base, &opts, false);
+        let full = run_pipeline(html, base, &opts);
+        let urls = |links: &[LinkInfo]| links.iter().map(|l| l.url.clone()).collect::<Vec<_>>();
+        assert_eq!(urls(&links), urls(&full.links));
+        assert_eq!(
+            urls(&links),
+            [
+                "https://example.com/docs",
+                "https://blog.example.com/post",
+                "https://other.org/"
+            ]
+        );
+        assert_eq!(links[0].rel, ["help"]);
+        assert!(links[1].is_internal);
+
+        let same_host = run_link_pipeline(html, base, &opts, true);
+        assert_eq!(urls(&same_host), ["https://example.com/docs"]);
+    }
+
+    #[test]
+    fn test_table_pipeline() {
+        let rows = "<tr><td>a</td><td>b</td></tr>".repeat(20);
         let html = format!(
-            "<html><body><main><p>tiny</p></main><article>{}</article></body></html>",
-            padding
+            r#"<html><body><main>
+            <table>
+              <caption>Prices</caption>
+              <thead><tr><th>Item</th><th>Cost</th></tr></thead>
+              <tbody>
+                <tr><td>Pipe | cell</td><td><a href="/x">$1</a></td></tr>
+                <tr><td>Fork</td><td>$2</td></tr>
+              </tbody>
+            </table>
+            <table>
+              <tr><th>Name</th><th>Q1</th><th>Q2</th></tr>
+              <tr><td>Total</td><td colspan="2">10</td></tr>
+            </table>
+            <table>{rows}</table>
+            </main></body></html>"#
         );
-        let r = run_pipeline(&html, "", true);
-        // Fallback should include the repeated text
-        assert!(r.raw_markdown.contains("x"));
+        let opts = Options::default();
+        let tables = run_table_pipeline(&html, "https://example.com/", &opts);
+        assert_eq!(tables.len(), 2, "layout table must be excluded");
+
+        let first = &tables[0];
+        assert_eq!(first.index, 0);
+        assert_eq!(first.caption.as_deref(), Some("Prices"));
+        assert_eq!(
+            first.header.as_deref(),
+            Some(&["Item", "Cost"].map(String::from)[..])
+        );
+        assert_eq!(first.rows, [["Pipe | cell", "$1"], ["Fork", "$2"]]);
+
+        let second = &tables[1];
+        assert_eq!(second.index, 1);
+        assert_eq!(second.caption, None);
+        assert_eq!(second.rows, [["Total", "10", ""]]);
     }
 
     #[test]
-    fn test_hidden_removed() {
-        let html = r#"<p>Visible</p><span class="sr-only">Hidden</span><div hidden>Also hidden</div>"#;
-        let r = run_pipeline(html, "", true);
-        assert!(r.raw_markdown.contains("Visible"));
-        assert!(!r.raw_markdown.contains("Hidden"));
-        assert!(!r.raw_markdown.contains("Also hidden"));
+    fn test_text_pipeline() {
+        let article = r#"<html><body>
+            <nav><a href="/">Home</a> <a href="/about">About</a></nav>
+            <article><h1>Title</h1>
+            <p>Some <a href="https://example.com/a">linked</a> text with an
+            <img src="/pic.png" alt="inline image"> and <em>emphasis</em>.</p>
+            <table><tr><th>A</th><th>B</th></tr><tr><td>1</td><td>2</td></tr></table>
+            <pre><code>let x = 1;</code></pre>
+            </article>
+            <footer>Copyright</footer></body></html>"#;
+        let sparse = format!(
+            "<html><body><main><p>Short.</p></main><div>{}</div></body></html>",
+            "<p>Outside the main element, but real content.</p>".repeat(200)
+        );
+        let links = format!(
+            r#"<html><body><article><h2>About <a href="/h">the [team]</a></h2>
+            <p><a href="/full.jpg"><img src="/thumb.jpg" alt="Photo"> of us</a>, a
+            <q cite="/src">quote</q>, <video src="/v.mp4" title="Tour"></video> and
+            AT&amp;amp;T in <a href="/t">a \ link</a>.</p>
+            <blockquote><p>Quoted <a href="/q">link</a></p></blockquote>
+            <table><tr><th>Name</th></tr><tr><td><a href="/n">Cell link</a></td></tr></table>
+            {}<p>End <img src="/deco.png" alt=""></p></article></body></html>"#,
+            "<p>Paragraph with <a href=\"/p\">a link</a> and enough words to count.</p>".repeat(40)
+        );
+        let fixtures = [
+            article.to_string(),
+            sparse,
+            "<p>Just a paragraph</p>".into(),
+            links,
+            "<p>Use <code>[c](d)</code> or <a href=\"/e\"><code>e</code></a>.</p>\
+             <pre><code>[f](g) ![h](i)</code></pre>"
+                .into(),
+        ];
+        let dedupe = Options {
+            dedupe_blocks: true,
+            include_cite_urls: true,
+            ..Options::default()
+        };
+        let site_rule = Options {
+            site_rules: vec![SiteRule {
+                host: "example.com".into(),
+                required: "/p".into(),
+            }],
+            ..Options::default()
+        };
+        for opts in [Options::default(), dedupe, site_rule] {
+            for html in &fixtures {
+                let full = run_pipeline(html, "https://example.com/", &opts);
+                assert_eq!(
+                    run_text_pipeline(html, "https://example.com/", &opts),
+                    full.markdown_plain
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_batch() {
+        let docs: Vec<(String, String)> = (0..300)
+            .map(|i| {
+                let html = format!("<html><body><p>Document number {i}</p></body></html>");
+                let base_url = if i == 123 {
+                    "not a url".to_string()
+                } else {
+                    format!("https://example.com/{i}")
+                };
+                (html, base_url)
+            })
+            .collect();
+        let strict = Options {
+            strict: true,
+            ..Options::default()
+        };
+        let results = convert_batch(&docs, &strict);
+        assert_eq!(results.len(), 300);
+        for (i, result) in results.iter().enumerate() {
+            match result {
+                Ok(r) => assert_eq!(r.raw_markdown, format!("Document number {i}")),
+                Err(err) => {
+                    assert_eq!(i, 123);
+                    assert!(
+                        matches!(err, Error::InvalidBaseUrl { base_url, .. } if base_url == "not a url")
+                    );
+                }
+            }
+        }
+        assert!(results[123].is_err());
+        // Lenient: the bad base URL is only a diagnostic
+        let results = convert_batch(&docs, &Options::default());
+        assert!(!results[123].as_ref().unwrap().diagnostics.base_url_usable);
+    }
+
+    #[test]
+    fn test_strict_base_url() {
+        let html = r#"<p>Read <a href="/docs">the docs</a>.</p>"#;
+        let strict = Options {
+            strict: true,
+            ..Options::default()
+        };
+        let err = try_run_pipeline(html, "not a url", &strict).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            r#"invalid base_url "not a url": relative URL without a base"#
+        );
+        // Lenient: converted without the base, and the diagnostics say so
+        let r = try_run_pipeline(html, "not a url", &Options::default()).unwrap();
+        assert!(!r.diagnostics.base_url_usable);
+        assert_eq!(r.links[0].url, "/docs");
+        let r = try_run_pipeline(html, "https://example.com/", &strict).unwrap();
+        assert!(r.diagnostics.base_url_usable);
+        assert_eq!(r.links[0].url, "https://example.com/docs");
+        // No base URL at all is not an error
+        assert!(try_run_pipeline(html, "", &strict).is_ok());
+    }
+
+    #[test]
+    fn test_panic_names_stage() {
+        let err = catch_panic(|| {
+            let doc = Html::parse_document("<p>text</p>");
+            let mut diagnostics = Diagnostics::default();
+            let opts = Options::default();
+            walk_content(&doc, "<p>text</p>", &None, &opts, false, &mut diagnostics);
+            panic!("broken citations");
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "conversion failed in the post_process stage: broken citations"
+        );
+    }
+
+    #[test]
+    fn test_options_from_json() {
+        let opts = Options::from_json(
+            r#"{
+                "citation_style": "footnote",
+                "min_md_len": 100,
+                "min_ratio": 0.5,
+                "content_selectors": [".post"],
+                "site_rules": [["example.com", "/post/"]],
+                "boilerplate_phrases": ["Sponsored"],
+                "lazy_image_attrs": null
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(opts.citation_style, CitationStyle::Footnote);
+        assert_eq!(opts.min_md_len, 100);
+        assert_eq!(opts.min_ratio, 0.5);
+        assert_eq!(opts.content_selectors[0].source, ".post");
+        assert_eq!(opts.site_rules[0].required, "/post/");
+        // Phrases add to the defaults; null keeps the default
+        assert!(opts.boilerplate_phrases.len() > 1);
+        assert!(opts.boilerplate_phrases.contains(&"sponsored".to_string()));
+        assert_eq!(opts.lazy_image_attrs, Options::default().lazy_image_attrs);
+
+        for bad in [
+            r#"{"min_md_len": -1}"#,
+            r#"{"dedupe_tables": "yes"}"#,
+            r#"{"table_mode": "grid"}"#,
+            r#"{"keep_selectors": ["div["]}"#,
+            r#"{"site_rules": [["example.com"]]}"#,
+            r#"{"unknown": 1}"#,
+            "[]",
+        ] {
+            assert!(Options::from_json(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_entities_decoded_once() {
+        let html = "<p>Tom&#8217;s caf&eacute; &amp; bar&nbsp;open &#x27;late&#x27; \
+            &notareal; &amp;amp; stays</p>\
+            <p><a href=\"/search?q=a&amp;b\">amp</a> \
+            <a href=\"/s?q=a&nbsp;b\">nbsp</a> \
+            <a href=\"/it&#x27;s\">apos</a> \
+            <a href=\"/p?a=1&notareal;\">bogus</a> \
+            <a href=\"/q?a=&amp;amp;\" title=\"R&amp;amp;D\">lit</a></p>";
+        let r = run_pipeline(html, "https://example.com/", &Options::default());
+        // Text: decoded by the parser, never re-encoded. A bogus entity reads
+        // as a browser shows it (the legacy `&not` prefix decodes)
+        assert!(r
+            .raw_markdown
+            .starts_with("Tom\u{2019}s caf\u{e9} & bar open 'late' \u{ac}areal; "));
+        // Text that still looks like an entity is escaped so markdown readers
+        // don't decode it a second time
+        assert!(r.raw_markdown.contains(r"areal; \&amp; stays"));
+        assert!(r.markdown_plain.contains(r"areal; \&amp; stays"));
+        let urls: Vec<&str> = r.links.iter().map(|l| l.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://example.com/search?q=a&b",
+                "https://example.com/s?q=a%C2%A0b",
+                "https://example.com/it's",
+                "https://example.com/p?a=1&notareal;",
+                "https://example.com/q?a=&amp;",
+            ]
+        );
+        assert_eq!(r.urls, urls);
+        assert!(r
+            .raw_markdown
+            .contains("[amp](https://example.com/search?q=a&b)"));
+        assert!(r
+            .raw_markdown
+            .contains(r"[lit](https://example.com/q?a=\&amp;)"));
+        assert!(r
+            .references_markdown
+            .contains("[5]: https://example.com/q?a=\\&amp; \"R\\&amp;D\""));
+        assert_eq!(r.links[4].title, "R&amp;D");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize_result() {
+        let html = r#"<html><head><title>Tides</title>
+            <meta property="og:title" content="Tides">
+            <meta property="og:image" content="/a.png">
+            <meta property="og:image" content="/b.png">
+            <link rel="alternate" type="application/rss+xml" href="/feed.xml" title="Feed">
+            </head><body><article><h1>Tides</h1><h2>Pools</h2>
+            <p>See <a href="/rocks" rel="nofollow">rocks</a>.</p>
+            <img src="/pool.jpg" alt="A pool" width="640"></article></body></html>"#;
+        let result = run_pipeline(html, "https://coast.example/", &Options::default());
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&result).unwrap()).unwrap();
+
+        assert_eq!(json["title"], result.title);
+        assert_eq!(json["raw_markdown"], result.raw_markdown);
+        assert_eq!(json["open_graph"]["og:title"], "Tides");
+        assert_eq!(json["open_graph"]["og:image"].as_array().unwrap().len(), 2);
+        // Link relations sit at the top level, as in the dict
+        assert_eq!(json["feeds"][0]["url"], result.link_relations.feeds[0].url);
+        assert_eq!(json["feeds"][0]["type"], "application/rss+xml");
+        assert!(json.get("link_relations").is_none());
+        let link = &result.links[0];
+        assert_eq!(json["links"][0]["url"], link.url);
+        assert_eq!(json["links"][0]["rel"][0], "nofollow");
+        assert_eq!(json["links"][0]["citation_number"], link.citation_number);
+        assert_eq!(json["images"][0]["width"], 640);
+        assert_eq!(json["images"][0]["height"], serde_json::Value::Null);
+        assert_eq!(json["headings"][1]["text"], result.headings[1].text);
+        assert_eq!(json["stats"]["headings_by_level"]["h2"], 1);
+        assert_eq!(json["stats"]["word_count"], result.stats.word_count);
+        assert_eq!(
+            json["diagnostics"]["stage"],
+            result.diagnostics.stage.as_str()
+        );
+        assert_eq!(
+            json["diagnostics"]["fallback"],
+            result.diagnostics.fallback_reason.is_some()
+        );
+        assert_eq!(json["chunks"][0]["text"], result.chunks[0].text);
+        assert_eq!(json.as_object().unwrap().len(), 28);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize_options() {
+        let opts = Options::from_json(
+            r#"{"citation_style": "footnote", "bullet_marker": "*",
+                "content_selectors": [".post"], "site_rules": [["example.com", "/post/"]]}"#,
+        )
+        .unwrap();
+        let json = serde_json::to_value(&opts).unwrap();
+        assert_eq!(json["citation_style"], "footnote");
+        assert_eq!(json["table_mode"], "auto");
+        assert_eq!(json["bullet_marker"], "*");
+        assert_eq!(json["content_selectors"], serde_json::json!([".post"]));
+        assert_eq!(
+            json["site_rules"],
+            serde_json::json!([["example.com", "/post/"]])
+        );
+        // Every key is one `Options::set` accepts
+        let mut fresh = Options::default();
+        for (name, value) in json.as_object().unwrap() {
+            fresh.set(name, value).unwrap();
+        }
+        assert_eq!(fresh.citation_style, CitationStyle::Footnote);
+        assert_eq!(fresh.style.bullet_marker, "*");
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_events() {
+        use std::sync::{Arc, Mutex};
+        use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+        /// Records `span/span: message` for every event.
+        struct Capture(Arc<Mutex<Vec<String>>>);
+
+        impl<S> Layer<S> for Capture
+        where
+            S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+        {
+            fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+                struct Message(String);
+                impl tracing::field::Visit for Message {
+                    fn record_debug(
+                        &mut self,
+                        field: &tracing::field::Field,
+                        value: &dyn std::fmt::Debug,
+                    ) {
+                        if field.name() == "message" {
+                            self.0 = format!("{:?}", value);
+                        }
+                    }
+                }
+                let mut message = Message(String::new());
+                event.record(&mut message);
+                let scope: Vec<&str> = ctx
+                    .event_scope(event)
+                    .into_iter()
+                    .flat_map(|s| s.from_root())
+                    .map(|span| span.name())
+                    .collect();
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("{}: {}", scope.join("/"), message.0));
+            }
+        }
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(Capture(events.clone()));
+        let html = include_str!("../tests/fixtures/article.html");
+        tracing::subscriber::with_default(subscriber, || {
+            run_pipeline(html, "https://coast.example/", &Options::default())
+        });
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "convert/parse: parsed",
+                "convert/skip_set: built skip set",
+                "convert/main_content: found content root",
+                "convert/walk: walked content root",
+                "convert/metadata: extracted metadata",
+                "convert/citations: numbered citations",
+                "convert/images: collected images",
+                "convert/chunks: chunked",
+                "convert: converted",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deep_nesting_truncates() {
+        // Inline elements: html5ever's scope checks make 50,000 nested
+        // blocks quadratic to parse, whatever the walk does with them
+        let depth = 50_000;
+        let html = format!(
+            "<html><body><article><p>Shallow text.</p>{}Buried{}</article></body></html>",
+            "<span>".repeat(depth),
+            "</span>".repeat(depth)
+        );
+        let r = run_pipeline(&html, "", &Options::default());
+        assert!(r.truncated);
+        assert_eq!(r.raw_markdown, "Shallow text.");
+
+        let html = format!(
+            "<article><p>Shallow text.</p>{}<p>Buried</p>{}</article>",
+            "<div>".repeat(300),
+            "</div>".repeat(300)
+        );
+        assert!(run_pipeline(&html, "", &Options::default()).truncated);
+        let deeper = Options {
+            max_depth: 400,
+            ..Options::default()
+        };
+        let r = run_pipeline(&html, "", &deeper);
+        assert!(!r.truncated);
+        assert!(r.raw_markdown.ends_with("Buried"));
+    }
+
+    #[test]
+    fn test_output_and_node_caps() {
+        let html = format!(
+            "<article><p>{}</p><p>See <a href=\"/more\">more</a>.</p></article>",
+            "word ".repeat(100_000)
+        );
+        let capped = Options {
+            max_output_bytes: 1000,
+            ..Options::default()
+        };
+        let r = run_pipeline(&html, "", &capped);
+        assert!(r.truncated);
+        assert!(r.raw_markdown.len() <= 1000);
+        assert!(r.raw_markdown.starts_with("word word"));
+        assert!(r.links.is_empty());
+        assert!(!run_pipeline(&html, "", &Options::default()).truncated);
+
+        // A cut through a link drops the whole link
+        let mut md = format!("Text {}0{}[a](/b){}", LINK_OPEN, LINK_SEP, LINK_CLOSE);
+        assert!(cap_markdown(&mut md, 12));
+        assert_eq!(md, "Text ");
+
+        let tables = "<table><tr><th>a</th></tr><tr><td>1</td></tr></table>".repeat(10_000);
+        let few_nodes = Options {
+            max_nodes: 100,
+            ..Options::default()
+        };
+        let r = run_pipeline(&format!("<article>{}</article>", tables), "", &few_nodes);
+        assert!(r.truncated);
+        assert!(r.stats.table_count < 100);
+    }
+
+    #[test]
+    fn test_malformed_link_markers() {
+        let (open, sep, close) = (LINK_OPEN, LINK_SEP, LINK_CLOSE);
+        // Well-formed
+        let md = format!("a {}3{}[b](/b){} c", open, sep, close);
+        assert_eq!(strip_link_markers(&md), "a [b](/b) c");
+        let indices: Vec<usize> = link_segments(&md)
+            .into_iter()
+            .filter_map(|segment| match segment {
+                Segment::Link { index, .. } => Some(index),
+                Segment::Text(_) => None,
+            })
+            .collect();
+        assert_eq!(indices, [3]);
+
+        // No separator, no close, an index that doesn't fit
+        for md in [
+            format!("a {}3[b](/b){} c", open, close),
+            format!("a {}3{}[b](/b) c", open, sep),
+            format!("a {}99999999999999999999999{}[b](/b){} c", open, sep, close),
+        ] {
+            let text = strip_link_markers(&md);
+            assert!(text.starts_with("a [b](/b)"), "{:?}", text);
+            assert!(!text.contains([open, sep, close]), "{:?}", text);
+            assert!(link_segments(&md)
+                .iter()
+                .all(|segment| matches!(segment, Segment::Text(_))));
+        }
+        assert_eq!(strip_link_markers(&format!("x {}12", open)), "x ");
+        // A broken marker doesn't swallow the next link
+        let md = format!("{}1[a](/a) {}2{}[b](/b){}", open, open, sep, close);
+        assert_eq!(strip_link_markers(&md), "[a](/a) [b](/b)");
+        assert!(matches!(
+            link_segments(&md)[..],
+            [
+                Segment::Text(_),
+                Segment::Text(_),
+                Segment::Link { index: 2, .. },
+                Segment::Text("")
+            ]
+        ));
+    }
+
+    /// The skip sets as they were built before `SkipSet`: every node of
+    /// every skipped subtree, expanded into a `HashSet`.
+    fn expanded_skip_sets(
+        doc: &Html,
+        keep_ids: &HashSet<NodeId>,
+        opts: &Options,
+    ) -> (HashSet<NodeId>, HashSet<NodeId>, Diagnostics) {
+        fn add_subtree(
+            el: &ElementRef,
+            set: &mut HashSet<NodeId>,
+            keep: &HashSet<NodeId>,
+        ) -> usize {
+            let mut count = 0;
+            for e in el.descendants().filter_map(ElementRef::wrap) {
+                if !keep.contains(&e.id()) {
+                    set.insert(e.id());
+                    count += 1;
+                }
+            }
+            count
+        }
+        fn collect_nav_ids(
+            el: &ElementRef,
+            set: &mut HashSet<NodeId>,
+            keep: &HashSet<NodeId>,
+            diagnostics: &mut Diagnostics,
+        ) {
+            if keep.contains(&el.id()) || set.contains(&el.id()) {
+                return;
+            }
+            if should_skip(el) {
+                diagnostics.hidden_nodes += add_subtree(el, set, keep);
+            } else if is_nav_clutter(el) {
+                diagnostics.clutter_nodes += add_subtree(el, set, keep);
+            } else {
+                for child in el.children().filter_map(ElementRef::wrap) {
+                    collect_nav_ids(&child, set, keep, diagnostics);
+                }
+            }
+        }
+
+        let mut diagnostics = Diagnostics::default();
+        let mut always_skip = HashSet::new();
+        let max_len = text_len(&doc.root_element()) as f64 * OVERLAY_MAX_TEXT_SHARE;
+        let mut stack = vec![doc.root_element()];
+        while let Some(el) = stack.pop() {
+            if keep_ids.contains(&el.id()) {
+                continue;
+            }
+            if is_overlay_candidate(&el) && (text_len(&el) as f64) < max_len {
+                diagnostics.overlay_nodes += add_subtree(&el, &mut always_skip, keep_ids);
+                continue;
+            }
+            stack.extend(el.children().filter_map(ElementRef::wrap));
+        }
+        if opts.extract_comments {
+            for section in find_comment_sections(doc) {
+                add_subtree(&section, &mut always_skip, &HashSet::new());
+            }
+        }
+        let mut skip = always_skip.clone();
+        for el in doc.root_element().children().filter_map(ElementRef::wrap) {
+            collect_nav_ids(&el, &mut skip, keep_ids, &mut diagnostics);
+        }
+        (always_skip, skip, diagnostics)
+    }
+
+    #[test]
+    fn test_skip_set_matches_expanded_sets() {
+        let deep_nav = format!(
+            "<body><div class=\"sidebar\"><nav><ul>{}</ul></nav>\
+             <div class=\"share\"><p>Share</p></div></div>\
+             <article><h1>Title <span class=\"sr-only\">skip</span></h1><p>Body text.</p>\
+             <div class=\"newsletter-modal\"><p>Subscribe</p></div></article></body>",
+            "<li class=\"menu\"><a href=\"/x\">x</a><ul>".repeat(40) + &"</ul></li>".repeat(40)
+        );
+        let kept_in_clutter = "<body><nav><p>Menu</p><div class=\"toc\"><ul><li>Intro</li>\
+            <li class=\"menu\" hidden>Hidden in kept</li></ul></div></nav>\
+            <div id=\"cookie-banner\"><nav><p>Consent menu</p></nav></div>\
+            <div class=\"comments\"><p>First!</p><div class=\"toc\"><p>Kept comment</p></div>\
+            <footer>Reply</footer></div><div class=\"comments newsletter\"><p>Sign up</p>\
+            <p class=\"toc\">Kept under both</p></div><main><p>Text</p></main></body>";
+        let docs = [
+            (
+                include_str!("../tests/fixtures/article.html").to_string(),
+                vec![],
+            ),
+            (deep_nav, vec![]),
+            (kept_in_clutter.to_string(), vec![".toc".to_string()]),
+            (
+                "<html class=\"comments\"><body><nav>Nav</nav><p>Text</p></body></html>".into(),
+                vec![],
+            ),
+        ];
+        for (html, keep) in &docs {
+            for extract_comments in [false, true] {
+                let opts = Options {
+                    keep_selectors: parse_selectors("keep_selectors", keep).unwrap(),
+                    extract_comments,
+                    ..Options::default()
+                };
+                let doc = Html::parse_document(html);
+                let keep_ids = build_keep_set(&doc, &opts);
+                let (old_always, old_skip, old_diagnostics) =
+                    expanded_skip_sets(&doc, &keep_ids, &opts);
+
+                let mut diagnostics = Diagnostics::default();
+                let mut always_skip = build_overlay_set(&doc, &keep_ids, &opts, &mut diagnostics);
+                let mut comment_ids = SkipSet::default();
+                if extract_comments {
+                    for section in find_comment_sections(&doc) {
+                        comment_ids.add_all(&section);
+                    }
+                }
+                always_skip.extend(&comment_ids);
+                let skip = build_skip_set(&doc, &keep_ids, &always_skip, &mut diagnostics);
+
+                for el in doc
+                    .root_element()
+                    .descendants()
+                    .filter_map(ElementRef::wrap)
+                {
+                    let id = el.id();
+                    let context = format!("{} in {:.60}", describe_element(&el), html);
+                    assert_eq!(
+                        always_skip.contains(&el, &keep_ids),
+                        old_always.contains(&id),
+                        "{}",
+                        context
+                    );
+                    assert_eq!(
+                        skip.contains(&el, &keep_ids),
+                        old_skip.contains(&id),
+                        "{}",
+                        context
+                    );
+                }
+                assert_eq!(diagnostics.overlay_nodes, old_diagnostics.overlay_nodes);
+                assert_eq!(diagnostics.clutter_nodes, old_diagnostics.clutter_nodes);
+                assert_eq!(diagnostics.hidden_nodes, old_diagnostics.hidden_nodes);
+            }
+        }
     }
 }
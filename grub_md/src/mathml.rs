@@ -0,0 +1,193 @@
+// ---------------------------------------------------------------------------
+// MathML → LaTeX
+// ---------------------------------------------------------------------------
+//
+// Covers the presentation-MathML subset that Wikipedia and most scientific
+// sites emit. Anything outside it makes the conversion fail so the caller can
+// fall back to the element's `alttext`.
+
+use scraper::{ElementRef, Node};
+
+/// Identifiers that LaTeX typesets as upright function names.
+const FUNCTION_NAMES: &[&str] = &[
+    "sin", "cos", "tan", "cot", "sec", "csc", "arcsin", "arccos", "arctan", "sinh", "cosh", "tanh",
+    "log", "ln", "exp", "lim", "max", "min", "sup", "inf", "det", "gcd",
+];
+
+/// Unicode characters with a LaTeX command equivalent.
+const SYMBOLS: &[(char, &str)] = &[
+    ('α', "\\alpha"),
+    ('β', "\\beta"),
+    ('γ', "\\gamma"),
+    ('δ', "\\delta"),
+    ('ε', "\\epsilon"),
+    ('θ', "\\theta"),
+    ('λ', "\\lambda"),
+    ('μ', "\\mu"),
+    ('π', "\\pi"),
+    ('ρ', "\\rho"),
+    ('σ', "\\sigma"),
+    ('τ', "\\tau"),
+    ('φ', "\\phi"),
+    ('ω', "\\omega"),
+    ('Γ', "\\Gamma"),
+    ('Δ', "\\Delta"),
+    ('Σ', "\\Sigma"),
+    ('Ω', "\\Omega"),
+    ('×', "\\times"),
+    ('·', "\\cdot"),
+    ('⋅', "\\cdot"),
+    ('±', "\\pm"),
+    ('−', "-"),
+    ('≤', "\\leq"),
+    ('≥', "\\geq"),
+    ('≠', "\\neq"),
+    ('≈', "\\approx"),
+    ('∞', "\\infty"),
+    ('∑', "\\sum"),
+    ('∏', "\\prod"),
+    ('∫', "\\int"),
+    ('∂', "\\partial"),
+    ('→', "\\to"),
+    ('∈', "\\in"),
+    ('\u{2061}', ""), // invisible function application
+    ('\u{2062}', ""), // invisible times
+];
+
+/// Deeper nesting than any real formula; past it the conversion fails.
+const MAX_NESTING: usize = 64;
+
+/// Convert a `<math>` element to a LaTeX string, or `None` when it uses a
+/// construct we don't handle.
+pub(crate) fn mathml_to_latex(math: &ElementRef) -> Option<String> {
+    let latex = convert_children(math, 0)?;
+    let latex = latex.trim();
+    if latex.is_empty() {
+        None
+    } else {
+        Some(latex.to_string())
+    }
+}
+
+/// The TeX source from a `<semantics>` annotation, which Wikipedia includes
+/// alongside the presentation markup.
+pub(crate) fn tex_annotation(math: &ElementRef) -> Option<String> {
+    math.descendants()
+        .filter_map(ElementRef::wrap)
+        .find(|e| {
+            e.value().name() == "annotation"
+                && e.value().attr("encoding") == Some("application/x-tex")
+        })
+        .map(|e| e.text().collect::<String>().trim().to_string())
+        .filter(|t| !t.is_empty())
+}
+
+fn convert(el: &ElementRef, depth: usize) -> Option<String> {
+    if depth >= MAX_NESTING {
+        return None;
+    }
+    let args = || {
+        el.children()
+            .filter_map(ElementRef::wrap)
+            .map(|c| convert(&c, depth + 1))
+            .collect::<Option<Vec<String>>>()
+    };
+    match el.value().name() {
+        "mi" => {
+            let text = token_text(el);
+            if FUNCTION_NAMES.contains(&text.as_str()) {
+                Some(format!("\\{}", text))
+            } else if text.chars().count() > 1 && text.chars().all(char::is_alphabetic) {
+                Some(format!("\\mathrm{{{}}}", text))
+            } else {
+                Some(map_symbols(&text))
+            }
+        }
+        "mn" | "mo" => Some(map_symbols(&token_text(el))),
+        "mtext" => Some(format!("\\text{{{}}}", token_text(el))),
+        "mspace" | "annotation" | "annotation-xml" => Some(String::new()),
+        "mrow" | "mstyle" | "mpadded" | "mphantom" | "math" => convert_children(el, depth),
+        "semantics" => el
+            .children()
+            .filter_map(ElementRef::wrap)
+            .next()
+            .and_then(|c| convert(&c, depth + 1)),
+        "msup" => match args()?.as_slice() {
+            [base, sup] => Some(format!("{}^{{{}}}", group(base), sup)),
+            _ => None,
+        },
+        "msub" => match args()?.as_slice() {
+            [base, sub] => Some(format!("{}_{{{}}}", group(base), sub)),
+            _ => None,
+        },
+        "msubsup" => match args()?.as_slice() {
+            [base, sub, sup] => Some(format!("{}_{{{}}}^{{{}}}", group(base), sub, sup)),
+            _ => None,
+        },
+        "mfrac" => match args()?.as_slice() {
+            [num, den] => Some(format!("\\frac{{{}}}{{{}}}", num, den)),
+            _ => None,
+        },
+        "msqrt" => Some(format!("\\sqrt{{{}}}", convert_children(el, depth)?)),
+        "mroot" => match args()?.as_slice() {
+            [base, index] => Some(format!("\\sqrt[{}]{{{}}}", index, base)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn convert_children(el: &ElementRef, depth: usize) -> Option<String> {
+    let mut out = String::new();
+    for child in el.children() {
+        match child.value() {
+            Node::Element(_) => {
+                if let Some(child_el) = ElementRef::wrap(child) {
+                    push_token(&mut out, &convert(&child_el, depth + 1)?);
+                }
+            }
+            Node::Text(t) if !t.trim().is_empty() => return None,
+            _ => {}
+        }
+    }
+    Some(out)
+}
+
+/// Append a token, separating a trailing `\command` from a following letter.
+fn push_token(out: &mut String, token: &str) {
+    let ends_with_command = {
+        let word = out.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        word.len() < out.len() && word.ends_with('\\')
+    };
+    if ends_with_command && token.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        out.push(' ');
+    }
+    out.push_str(token);
+}
+
+fn token_text(el: &ElementRef) -> String {
+    el.text().collect::<String>().trim().to_string()
+}
+
+fn map_symbols(text: &str) -> String {
+    let mut out = String::new();
+    for ch in text.chars() {
+        match SYMBOLS.iter().find(|(c, _)| *c == ch) {
+            Some((_, cmd)) => push_token(&mut out, cmd),
+            None => {
+                let mut tmp = [0u8; 4];
+                push_token(&mut out, ch.encode_utf8(&mut tmp));
+            }
+        }
+    }
+    out
+}
+
+/// Brace a multi-character script base so `^`/`_` bind to all of it.
+fn group(base: &str) -> String {
+    if base.chars().count() <= 1 || (base.starts_with('\\') && !base.contains(' ')) {
+        base.to_string()
+    } else {
+        format!("{{{}}}", base)
+    }
+}
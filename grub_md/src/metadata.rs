@@ -0,0 +1,275 @@
+// ---------------------------------------------------------------------------
+// Document metadata (head-only parsing)
+// ---------------------------------------------------------------------------
+
+use once_cell::sync::Lazy;
+use scraper::{ElementRef, Html, Selector};
+use url::Url;
+
+use crate::{collapse_whitespace, resolve_url};
+
+static SEL_OG_TITLE: Lazy<Selector> = Lazy::new(|| sel!(r#"meta[property="og:title"]"#));
+static SEL_TITLE: Lazy<Selector> = Lazy::new(|| sel!("head > title"));
+static SEL_H1: Lazy<Selector> = Lazy::new(|| sel!("h1"));
+static SEL_META: Lazy<Selector> = Lazy::new(|| sel!("meta[content]"));
+static SEL_SCRIPT_TYPED: Lazy<Selector> = Lazy::new(|| sel!("script[type]"));
+static SEL_LINK_REL: Lazy<Selector> = Lazy::new(|| sel!("link[rel][href]"));
+static SEL_HTML_LANG: Lazy<Selector> = Lazy::new(|| sel!("html[lang]"));
+static SEL_CONTENT_LANGUAGE: Lazy<Selector> = Lazy::new(|| sel!("meta[http-equiv][content]"));
+static SEL_TIME_PUBLISHED: Lazy<Selector> =
+    Lazy::new(|| sel!(r#"time[pubdate], time[itemprop="datePublished"]"#));
+
+/// Separators sites put between the page title and their own name.
+const TITLE_SEPARATORS: &[&str] = &[" | ", " - ", " – ", " — ", " · ", " :: "];
+
+/// Descriptive metadata from `<meta>` tags and `<time>` elements. Values are
+/// raw strings; absent fields are empty.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PageMetadata {
+    pub description: String,
+    pub author: String,
+    pub published_time: String,
+    pub modified_time: String,
+}
+
+/// Content of the first non-empty `<meta>` whose `name`, `property`, or
+/// `itemprop` is one of `keys` (checked in order of `keys`).
+fn meta_content(doc: &Html, keys: &[&str]) -> String {
+    for key in keys {
+        let found = doc
+            .select(&SEL_META)
+            .filter(|m| {
+                let v = m.value();
+                [v.attr("name"), v.attr("property"), v.attr("itemprop")]
+                    .iter()
+                    .flatten()
+                    .any(|k| k.eq_ignore_ascii_case(key))
+            })
+            .filter_map(|m| m.value().attr("content"))
+            .map(str::trim)
+            .find(|c| !c.is_empty());
+        if let Some(content) = found {
+            return content.to_string();
+        }
+    }
+    String::new()
+}
+
+pub(crate) fn extract_metadata(doc: &Html) -> PageMetadata {
+    let mut published_time = meta_content(doc, &["article:published_time"]);
+    if published_time.is_empty() {
+        // <time datetime> is ISO already; fall back to the visible text
+        published_time = doc
+            .select(&SEL_TIME_PUBLISHED)
+            .map(|t| match t.value().attr("datetime") {
+                Some(dt) if !dt.trim().is_empty() => dt.trim().to_string(),
+                _ => collapse_whitespace(&t.text().collect::<String>()),
+            })
+            .find(|t| !t.is_empty())
+            .unwrap_or_default();
+    }
+    if published_time.is_empty() {
+        published_time = meta_content(doc, &["datePublished"]);
+    }
+
+    PageMetadata {
+        description: meta_content(doc, &["description"]),
+        author: meta_content(doc, &["author", "article:author"]),
+        published_time,
+        modified_time: meta_content(doc, &["article:modified_time", "dateModified"]),
+    }
+}
+
+/// The declared language: `<html lang>`, then `og:locale`, then a
+/// `Content-Language` meta. Underscores become hyphens (`en_US` → `en-US`).
+pub(crate) fn extract_language(doc: &Html) -> String {
+    let html_lang = doc
+        .select(&SEL_HTML_LANG)
+        .filter_map(|h| h.value().attr("lang"))
+        .map(str::trim)
+        .find(|l| !l.is_empty());
+    let lang = match html_lang {
+        Some(lang) => lang.to_string(),
+        None => {
+            let locale = meta_content(doc, &["og:locale"]);
+            if locale.is_empty() {
+                doc.select(&SEL_CONTENT_LANGUAGE)
+                    .filter(|m| {
+                        m.value()
+                            .attr("http-equiv")
+                            .is_some_and(|h| h.trim().eq_ignore_ascii_case("content-language"))
+                    })
+                    .filter_map(|m| m.value().attr("content"))
+                    // The header allows a list; the first entry is primary
+                    .filter_map(|c| c.split(',').next())
+                    .map(str::trim)
+                    .find(|c| !c.is_empty())
+                    .unwrap_or_default()
+                    .to_string()
+            } else {
+                locale
+            }
+        }
+    };
+    lang.replace('_', "-")
+}
+
+/// `<link type>` values that identify a syndication feed.
+const FEED_TYPES: &[&str] = &[
+    "application/rss+xml",
+    "application/atom+xml",
+    "application/feed+json",
+];
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FeedLink {
+    pub url: String,
+    /// MIME type from the `<link type>` attribute.
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
+    pub kind: String,
+    pub title: String,
+}
+
+/// Canonical, feed, and pagination `<link>` relations, resolved against the
+/// base URL.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct LinkRelations {
+    pub canonical_url: String,
+    pub feeds: Vec<FeedLink>,
+    pub next_page: String,
+    pub prev_page: String,
+}
+
+pub(crate) fn extract_link_relations(doc: &Html, base: &Option<Url>) -> LinkRelations {
+    let mut rels = LinkRelations::default();
+    for link in doc.select(&SEL_LINK_REL) {
+        let v = link.value();
+        let href = v.attr("href").unwrap_or("").trim();
+        if href.is_empty() {
+            continue;
+        }
+        let rel = v.attr("rel").unwrap_or("").to_ascii_lowercase();
+        for token in rel.split_whitespace() {
+            let slot = match token {
+                "canonical" => &mut rels.canonical_url,
+                "next" => &mut rels.next_page,
+                "prev" | "previous" => &mut rels.prev_page,
+                "alternate" => {
+                    let kind = v.attr("type").unwrap_or("").trim().to_ascii_lowercase();
+                    if FEED_TYPES.contains(&kind.as_str()) {
+                        rels.feeds.push(FeedLink {
+                            url: resolve_url(href, base),
+                            kind,
+                            title: collapse_whitespace(v.attr("title").unwrap_or("")),
+                        });
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            // First declaration wins
+            if slot.is_empty() {
+                *slot = resolve_url(href, base);
+            }
+        }
+    }
+    rels
+}
+
+/// OpenGraph / Twitter-card properties whose values are image URLs.
+const OG_IMAGE_PROPERTIES: &[&str] = &[
+    "og:image",
+    "og:image:url",
+    "og:image:secure_url",
+    "twitter:image",
+    "twitter:image:src",
+];
+
+/// All `og:*` and `twitter:*` meta properties in document order. Repeated
+/// properties (several `og:image` tags) keep every value.
+pub(crate) fn extract_open_graph(doc: &Html, base: &Option<Url>) -> Vec<(String, Vec<String>)> {
+    let mut props: Vec<(String, Vec<String>)> = Vec::new();
+    for m in doc.select(&SEL_META) {
+        let v = m.value();
+        let key = match v.attr("property").or_else(|| v.attr("name")) {
+            Some(k) => k.trim().to_ascii_lowercase(),
+            None => continue,
+        };
+        if !key.starts_with("og:") && !key.starts_with("twitter:") {
+            continue;
+        }
+        let content = v.attr("content").unwrap_or("").trim();
+        if content.is_empty() {
+            continue;
+        }
+        let value = if OG_IMAGE_PROPERTIES.contains(&key.as_str()) {
+            resolve_url(content, base)
+        } else {
+            content.to_string()
+        };
+        match props.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, values)) => values.push(value),
+            None => props.push((key, vec![value])),
+        }
+    }
+    props
+}
+
+/// Raw text of every well-formed `<script type="application/ld+json">`
+/// block, in document order. Parsing into objects is left to the caller.
+pub(crate) fn extract_json_ld(doc: &Html) -> Vec<String> {
+    doc.select(&SEL_SCRIPT_TYPED)
+        .filter(|s| {
+            s.value()
+                .attr("type")
+                .is_some_and(|t| t.trim().eq_ignore_ascii_case("application/ld+json"))
+        })
+        .map(|s| s.text().collect::<String>().trim().to_string())
+        .filter(|json| !json.is_empty() && serde_json::from_str::<serde_json::Value>(json).is_ok())
+        .collect()
+}
+
+/// Page title from `og:title`, then the `<title>` in `<head>` (not an SVG
+/// one in the body), then the first `<h1>` in the main content (or the whole
+/// document when there is none).
+pub(crate) fn extract_title(doc: &Html, main: Option<ElementRef>, strip_suffix: bool) -> String {
+    let head_title = doc
+        .select(&SEL_OG_TITLE)
+        .filter_map(|m| m.value().attr("content"))
+        .map(collapse_whitespace)
+        .find(|t| !t.is_empty())
+        .or_else(|| {
+            doc.select(&SEL_TITLE)
+                .map(|t| collapse_whitespace(&t.text().collect::<String>()))
+                .find(|t| !t.is_empty())
+        });
+    if let Some(title) = head_title {
+        return if strip_suffix {
+            strip_site_suffix(&title).to_string()
+        } else {
+            title
+        };
+    }
+
+    let h1 = match main {
+        Some(node) => node.select(&SEL_H1).next(),
+        None => doc.select(&SEL_H1).next(),
+    };
+    h1.map(|h| collapse_whitespace(&h.text().collect::<String>()))
+        .unwrap_or_default()
+}
+
+/// Drop a trailing " | Site Name" style segment.
+fn strip_site_suffix(title: &str) -> &str {
+    let cut = TITLE_SEPARATORS
+        .iter()
+        .filter_map(|sep| title.rfind(sep))
+        .max();
+    match cut {
+        Some(i) if !title[..i].trim().is_empty() => title[..i].trim_end(),
+        _ => title,
+    }
+}
@@ -0,0 +1,887 @@
+// ---------------------------------------------------------------------------
+// PyO3 bindings
+// ---------------------------------------------------------------------------
+//
+// The Python module: option parsing, the entry points, and conversion of
+// results to dicts and lists. Built with the `python` feature.
+
+// pyo3 0.22's `#[pyfunction]` expansion trips `useless_conversion` on the
+// `PyResult` return type.
+#![allow(clippy::useless_conversion)]
+
+use pyo3::prelude::*;
+use pyo3::sync::GILOnceCell;
+use pyo3::types::{PyBool, PyDict, PyFloat, PyList, PyLong, PyString, PyTuple};
+
+use super::*;
+
+// The macro checks pyo3's own `gil-refs` feature, which this crate lacks
+#[allow(unexpected_cfgs)]
+mod exceptions {
+    pyo3::create_exception!(
+        grub_md,
+        GrubMdError,
+        pyo3::exceptions::PyException,
+        "A conversion failed: an invalid base_url under strict=True, or a bug \
+         inside the converter (the message names the stage)."
+    );
+}
+use exceptions::GrubMdError;
+
+/// Run `convert` with the GIL released, raising `GrubMdError` as
+/// `try_run_pipeline` would fail. Without `strict` an unusable `base_url`
+/// only warns, and the conversion goes ahead without it.
+fn convert_checked<T: Send>(
+    py: Python<'_>,
+    base_url: &str,
+    opts: &Options,
+    convert: impl FnOnce() -> T + Send,
+) -> PyResult<T> {
+    if !opts.strict {
+        if let Err(err) = check_base_url(base_url) {
+            let category = py.get_type_bound::<pyo3::exceptions::PyUserWarning>();
+            let message = format!("{}; converting without it", err);
+            PyErr::warn_bound(py, &category, &message, 1)?;
+        }
+    }
+    py.allow_threads(|| run_checked(base_url, opts, convert))
+        .map_err(|e| GrubMdError::new_err(e.to_string()))
+}
+
+/// The setting names `Options::set` accepts, so a misspelt keyword argument
+/// raises `TypeError` as it would on any Python function.
+static OPTION_NAMES: Lazy<HashSet<String>> =
+    Lazy::new(|| match serde_json::to_value(Options::default()) {
+        Ok(serde_json::Value::Object(fields)) => fields.into_iter().map(|(k, _)| k).collect(),
+        _ => HashSet::new(),
+    });
+
+/// A keyword argument's value as the JSON `Options::set` takes: `None`,
+/// booleans, numbers, strings, and lists or tuples of those.
+fn json_value(value: &Bound<'_, PyAny>) -> PyResult<serde_json::Value> {
+    use serde_json::Value;
+    if value.is_none() {
+        Ok(Value::Null)
+    } else if let Ok(flag) = value.downcast::<PyBool>() {
+        Ok(Value::Bool(flag.is_true()))
+    } else if value.is_instance_of::<PyLong>() {
+        match value.extract::<u64>() {
+            Ok(n) => Ok(n.into()),
+            Err(_) => Ok(value.extract::<i64>()?.into()),
+        }
+    } else if let Ok(x) = value.downcast::<PyFloat>() {
+        Ok(x.value().into())
+    } else if let Ok(text) = value.downcast::<PyString>() {
+        Ok(text.to_str()?.into())
+    } else if value.is_instance_of::<PyList>() || value.is_instance_of::<PyTuple>() {
+        value.iter()?.map(|item| json_value(&item?)).collect()
+    } else {
+        Err(pyo3::exceptions::PyTypeError::new_err(format!(
+            "unsupported option value {}",
+            value.repr()?
+        )))
+    }
+}
+
+/// Build `Options` from the keyword arguments of an entry point, so they all
+/// accept (and validate) the same settings.
+fn options_from_kwargs(kwargs: Option<&Bound<'_, PyDict>>) -> PyResult<Options> {
+    let mut opts = Options::default();
+    for (name, value) in kwargs.into_iter().flatten() {
+        let name: String = name.extract()?;
+        if !OPTION_NAMES.contains(&name) {
+            return Err(pyo3::exceptions::PyTypeError::new_err(format!(
+                "unexpected keyword argument {:?}",
+                name
+            )));
+        }
+        opts.set(&name, &json_value(&value)?)
+            .map_err(pyo3::exceptions::PyValueError::new_err)?;
+    }
+    Ok(opts)
+}
+
+/// Conversion settings built once and passed to any entry point as
+/// `options=`. Takes the same keyword arguments as the entry points, named
+/// as the fields of the Rust `Options`.
+#[pyclass(frozen, module = "grub_md")]
+struct ConversionOptions {
+    opts: Options,
+    /// The keyword arguments as given, so overrides can be layered on top.
+    kwargs: Py<PyDict>,
+}
+
+#[pymethods]
+impl ConversionOptions {
+    #[new]
+    #[pyo3(signature = (**kwargs))]
+    fn new(py: Python<'_>, kwargs: Option<&Bound<'_, PyDict>>) -> PyResult<Self> {
+        let kwargs = match kwargs {
+            Some(kwargs) => kwargs.copy()?,
+            None => PyDict::new_bound(py),
+        };
+        Ok(ConversionOptions {
+            opts: options_from_kwargs(Some(&kwargs))?,
+            kwargs: kwargs.unbind(),
+        })
+    }
+
+    /// A copy with some settings changed.
+    #[pyo3(signature = (**overrides))]
+    fn replace(&self, py: Python<'_>, overrides: Option<&Bound<'_, PyDict>>) -> PyResult<Self> {
+        let kwargs = self.kwargs.bind(py).copy()?;
+        if let Some(overrides) = overrides {
+            kwargs.update(overrides.as_mapping())?;
+        }
+        Self::new(py, Some(&kwargs))
+    }
+
+    /// The settings given, without the defaults.
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        self.kwargs.bind(py).copy()
+    }
+
+    fn __repr__(&self, py: Python<'_>) -> PyResult<String> {
+        let args = self
+            .kwargs
+            .bind(py)
+            .iter()
+            .map(|(k, v)| Ok(format!("{}={}", k, v.repr()?)))
+            .collect::<PyResult<Vec<_>>>()?;
+        Ok(format!("ConversionOptions({})", args.join(", ")))
+    }
+}
+
+/// A converter set up once, for workers converting many documents with the
+/// same settings. Selectors, phrase filters and site rules are checked and
+/// compiled when it is built, so a bad one raises then rather than on every
+/// document.
+#[pyclass(frozen, module = "grub_md")]
+struct MarkdownConverter {
+    opts: Options,
+}
+
+#[pymethods]
+impl MarkdownConverter {
+    #[new]
+    #[pyo3(signature = (options=None, **kwargs))]
+    fn new(
+        py: Python<'_>,
+        options: Option<Bound<'_, ConversionOptions>>,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Self> {
+        Ok(MarkdownConverter {
+            opts: resolve_options(py, options, kwargs)?,
+        })
+    }
+
+    /// Convert one document, as `generate_markdown` does.
+    #[pyo3(signature = (html, base_url=String::new()))]
+    fn convert(&self, py: Python<'_>, html: String, base_url: String) -> PyResult<PyObject> {
+        let result = convert_checked(py, &base_url, &self.opts, || {
+            run_pipeline(&html, &base_url, &self.opts)
+        })?;
+        result_to_dict(py, &result)
+    }
+
+    /// Convert `(html, base_url)` pairs in parallel, as
+    /// `generate_markdown_batch` does.
+    fn convert_batch(&self, py: Python<'_>, docs: Vec<(String, String)>) -> PyResult<PyObject> {
+        batch_to_list(py, &docs, &self.opts)
+    }
+}
+
+/// The settings for an entry point: `options` if given, with any keyword
+/// arguments overriding it.
+fn resolve_options(
+    py: Python<'_>,
+    options: Option<Bound<'_, ConversionOptions>>,
+    overrides: Option<&Bound<'_, PyDict>>,
+) -> PyResult<Options> {
+    match options {
+        None => options_from_kwargs(overrides),
+        Some(options) if overrides.is_none_or(|o| o.is_empty()) => Ok(options.get().opts.clone()),
+        Some(options) => Ok(options.get().replace(py, overrides)?.opts),
+    }
+}
+
+/// Convert an HTML document to markdown. Settings come from `options` (a
+/// `ConversionOptions`) and/or keyword arguments, which take precedence;
+/// an unknown setting raises `TypeError` and a bad value `ValueError`. The
+/// GIL is released for the conversion itself, so threads can convert
+/// documents in parallel.
+#[pyfunction]
+#[pyo3(signature = (html, base_url=String::new(), options=None, **kwargs))]
+fn generate_markdown(
+    py: Python<'_>,
+    html: String,
+    base_url: String,
+    options: Option<Bound<'_, ConversionOptions>>,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<PyObject> {
+    let opts = resolve_options(py, options, kwargs)?;
+    let result = convert_checked(py, &base_url, &opts, || {
+        run_pipeline(&html, &base_url, &opts)
+    })?;
+    result_to_dict(py, &result)
+}
+
+/// `generate_markdown`, returning the result as one JSON string under the
+/// same keys as the dict, for callers that store or forward it as is.
+#[pyfunction]
+#[pyo3(signature = (html, base_url=String::new(), options=None, **kwargs))]
+fn generate_markdown_json(
+    py: Python<'_>,
+    html: String,
+    base_url: String,
+    options: Option<Bound<'_, ConversionOptions>>,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<String> {
+    let opts = resolve_options(py, options, kwargs)?;
+    convert_checked(py, &base_url, &opts, || {
+        serde_json::to_string(&run_pipeline(&html, &base_url, &opts))
+    })?
+    .map_err(|e| pyo3::exceptions::PyValueError::new_err(e.to_string()))
+}
+
+/// `generate_markdown`, returning a `MarkdownResult` whose fields are read
+/// as attributes instead of a dict. The plain and reference variants are
+/// only built when read.
+#[pyfunction]
+#[pyo3(signature = (html, base_url=String::new(), options=None, **kwargs))]
+fn generate_markdown_result(
+    py: Python<'_>,
+    html: String,
+    base_url: String,
+    options: Option<Bound<'_, ConversionOptions>>,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<MarkdownResult> {
+    let opts = resolve_options(py, options, kwargs)?;
+    let result = convert_checked(py, &base_url, &opts, || {
+        convert_document(&html, &base_url, &opts, false, Variants::Deferred)
+    })?;
+    Ok(MarkdownResult::new(result, opts.citation_style))
+}
+
+/// Convert a list of `(html, base_url)` pairs in parallel with the GIL
+/// released. Results come back in input order; a document that fails to
+/// convert gets `{"error": message}` in its place. Takes the same settings
+/// as `generate_markdown`.
+#[pyfunction]
+#[pyo3(signature = (docs, options=None, **kwargs))]
+fn generate_markdown_batch(
+    py: Python<'_>,
+    docs: Vec<(String, String)>,
+    options: Option<Bound<'_, ConversionOptions>>,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<PyObject> {
+    let opts = resolve_options(py, options, kwargs)?;
+    batch_to_list(py, &docs, &opts)
+}
+
+/// Convert `docs` on the rayon pool with the GIL released, as the list
+/// `generate_markdown_batch` returns.
+fn batch_to_list(py: Python<'_>, docs: &[(String, String)], opts: &Options) -> PyResult<PyObject> {
+    let results = py.allow_threads(|| convert_batch(docs, opts));
+    let list = PyList::empty_bound(py);
+    for result in results {
+        match result {
+            Ok(result) => list.append(result_to_dict(py, &result)?)?,
+            Err(err) => {
+                let d = PyDict::new_bound(py);
+                d.set_item("error", err.to_string())?;
+                list.append(d)?;
+            }
+        }
+    }
+    Ok(list.into())
+}
+
+/// Convert an HTML snippet (an element's inner HTML, say) without the
+/// whole-page heuristics. Takes the same settings as `generate_markdown`.
+#[pyfunction]
+#[pyo3(signature = (html, base_url=String::new(), options=None, **kwargs))]
+fn generate_markdown_fragment(
+    py: Python<'_>,
+    html: String,
+    base_url: String,
+    options: Option<Bound<'_, ConversionOptions>>,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<PyObject> {
+    let opts = resolve_options(py, options, kwargs)?;
+    let result = convert_checked(py, &base_url, &opts, || {
+        run_fragment_pipeline(&html, &base_url, &opts)
+    })?;
+    result_to_dict(py, &result)
+}
+
+/// Convert every element matching a CSS selector separately, returning one
+/// result dict per outermost match (an empty list when nothing matches).
+#[pyfunction]
+#[pyo3(signature = (html, selector, base_url=String::new(), options=None, **kwargs))]
+fn extract_selection(
+    py: Python<'_>,
+    html: String,
+    selector: &str,
+    base_url: String,
+    options: Option<Bound<'_, ConversionOptions>>,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<PyObject> {
+    let opts = resolve_options(py, options, kwargs)?;
+    let selector = parse_selectors("selector", &[selector.to_string()])
+        .map_err(pyo3::exceptions::PyValueError::new_err)?
+        .remove(0)
+        .selector;
+    let results = convert_checked(py, &base_url, &opts, || {
+        run_selection_pipeline(&html, &selector, &base_url, &opts)
+    })?;
+    let list = PyList::empty_bound(py);
+    for result in results {
+        list.append(result_to_dict(py, &result)?)?;
+    }
+    Ok(list.into())
+}
+
+/// The outbound links of the content, as `generate_markdown` would list
+/// them, from one walk with no markdown cleanup, deduplication or sparse
+/// content fallback.
+#[pyfunction]
+#[pyo3(signature = (html, base_url=String::new(), same_host_only=false, options=None, **kwargs))]
+fn extract_links(
+    py: Python<'_>,
+    html: String,
+    base_url: String,
+    same_host_only: bool,
+    options: Option<Bound<'_, ConversionOptions>>,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<PyObject> {
+    let opts = resolve_options(py, options, kwargs)?;
+    let links = convert_checked(py, &base_url, &opts, || {
+        run_link_pipeline(&html, &base_url, &opts, same_host_only)
+    })?;
+    Ok(links_to_list(py, &links)?.into())
+}
+
+/// The `markdown_plain` output of `generate_markdown` alone, for indexing
+/// where links, references and metadata would go unused.
+#[pyfunction]
+#[pyo3(signature = (html, base_url=String::new(), options=None, **kwargs))]
+fn extract_text(
+    py: Python<'_>,
+    html: String,
+    base_url: String,
+    options: Option<Bound<'_, ConversionOptions>>,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<String> {
+    let opts = resolve_options(py, options, kwargs)?;
+    convert_checked(py, &base_url, &opts, || {
+        run_text_pipeline(&html, &base_url, &opts)
+    })
+}
+
+/// The data tables of a document, as `generate_markdown` would emit them,
+/// with plain-text cells instead of markdown rows. Layout tables are left
+/// out, as they are from the markdown.
+#[pyfunction]
+#[pyo3(signature = (html, base_url=String::new(), options=None, **kwargs))]
+fn extract_tables(
+    py: Python<'_>,
+    html: String,
+    base_url: String,
+    options: Option<Bound<'_, ConversionOptions>>,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<PyObject> {
+    let opts = resolve_options(py, options, kwargs)?;
+    let tables = convert_checked(py, &base_url, &opts, || {
+        run_table_pipeline(&html, &base_url, &opts)
+    })?;
+    let list = PyList::empty_bound(py);
+    for table in tables {
+        let d = PyDict::new_bound(py);
+        d.set_item("index", table.index)?;
+        d.set_item("caption", &table.caption)?;
+        d.set_item("header", &table.header)?;
+        d.set_item("rows", &table.rows)?;
+        list.append(d)?;
+    }
+    Ok(list.into())
+}
+
+fn links_to_list<'py>(py: Python<'py>, links: &[LinkInfo]) -> PyResult<Bound<'py, PyList>> {
+    let links_list = PyList::empty_bound(py);
+    for link in links {
+        let d = PyDict::new_bound(py);
+        d.set_item("text", &link.text)?;
+        d.set_item("url", &link.url)?;
+        d.set_item("title", &link.title)?;
+        d.set_item("citation_number", link.citation_number)?;
+        d.set_item("is_internal", link.is_internal)?;
+        d.set_item("host", &link.host)?;
+        d.set_item("rel", &link.rel)?;
+        d.set_item("target", &link.target)?;
+        d.set_item("aria_label", &link.aria_label)?;
+        links_list.append(d)?;
+    }
+    Ok(links_list)
+}
+
+/// Top-level keys of a conversion result, in the order the dict lists them.
+const RESULT_FIELDS: &[&str] = &[
+    "title",
+    "language",
+    "metadata",
+    "open_graph",
+    "json_ld",
+    "canonical_url",
+    "feeds",
+    "next_page",
+    "prev_page",
+    "raw_markdown",
+    "clean_markdown",
+    "markdown_with_citations",
+    "references_markdown",
+    "markdown_references",
+    "markdown_plain",
+    "toc_markdown",
+    "comments_markdown",
+    "html_comments",
+    "links",
+    "images",
+    "media",
+    "headings",
+    "chunks",
+    "stats",
+    "diagnostics",
+    "truncated",
+    "contacts",
+    "urls",
+];
+
+fn result_to_dict(py: Python<'_>, result: &PipelineResult) -> PyResult<PyObject> {
+    let dict = PyDict::new_bound(py);
+    for name in RESULT_FIELDS {
+        dict.set_item(name, result_field(py, result, name)?)?;
+    }
+    Ok(dict.into())
+}
+
+/// One field of a conversion result as a Python object; `name` must be one
+/// of `RESULT_FIELDS`.
+fn result_field(py: Python<'_>, result: &PipelineResult, name: &str) -> PyResult<PyObject> {
+    let rels = &result.link_relations;
+    let value = match name {
+        "title" => result.title.to_object(py),
+        "language" => result.language.to_object(py),
+        "metadata" => {
+            let meta = PyDict::new_bound(py);
+            meta.set_item("description", &result.metadata.description)?;
+            meta.set_item("author", &result.metadata.author)?;
+            meta.set_item("published_time", &result.metadata.published_time)?;
+            meta.set_item("modified_time", &result.metadata.modified_time)?;
+            meta.into()
+        }
+        "open_graph" => {
+            // Single-valued properties map to a string, repeated ones to a list
+            let og = PyDict::new_bound(py);
+            for (key, values) in &result.open_graph {
+                if let [value] = values.as_slice() {
+                    og.set_item(key, value)?;
+                } else {
+                    og.set_item(key, values)?;
+                }
+            }
+            og.into()
+        }
+        "json_ld" => result.json_ld.to_object(py),
+        "canonical_url" => rels.canonical_url.to_object(py),
+        "feeds" => {
+            let feeds_list = PyList::empty_bound(py);
+            for feed in &rels.feeds {
+                let d = PyDict::new_bound(py);
+                d.set_item("url", &feed.url)?;
+                d.set_item("type", &feed.kind)?;
+                d.set_item("title", &feed.title)?;
+                feeds_list.append(d)?;
+            }
+            feeds_list.into()
+        }
+        "next_page" => rels.next_page.to_object(py),
+        "prev_page" => rels.prev_page.to_object(py),
+        "raw_markdown" => result.raw_markdown.to_object(py),
+        "clean_markdown" => result.clean_markdown.to_object(py),
+        "markdown_with_citations" => result.markdown_with_citations.to_object(py),
+        "references_markdown" => result.references_markdown.to_object(py),
+        "markdown_references" => result.markdown_references.to_object(py),
+        "markdown_plain" => result.markdown_plain.to_object(py),
+        "toc_markdown" => result.toc_markdown.to_object(py),
+        "comments_markdown" => result.comments_markdown.to_object(py),
+        "html_comments" => result.html_comments.to_object(py),
+        "links" => links_to_list(py, &result.links)?.into(),
+        "images" => {
+            let images_list = PyList::empty_bound(py);
+            for img in &result.images {
+                let d = PyDict::new_bound(py);
+                d.set_item("alt", &img.alt)?;
+                d.set_item("url", &img.url)?;
+                d.set_item("title", &img.title)?;
+                d.set_item("caption", &img.caption)?;
+                d.set_item("candidates", &img.candidates)?;
+                d.set_item("width", img.width)?;
+                d.set_item("height", img.height)?;
+                d.set_item("is_probably_icon", img.is_probably_icon)?;
+                d.set_item("format", &img.format)?;
+                images_list.append(d)?;
+            }
+            images_list.into()
+        }
+        "media" => {
+            let media_list = PyList::empty_bound(py);
+            for m in &result.media {
+                let d = PyDict::new_bound(py);
+                d.set_item("url", &m.url)?;
+                d.set_item("kind", &m.kind)?;
+                d.set_item("poster", &m.poster)?;
+                media_list.append(d)?;
+            }
+            media_list.into()
+        }
+        "headings" => {
+            let headings_list = PyList::empty_bound(py);
+            for h in &result.headings {
+                let d = PyDict::new_bound(py);
+                d.set_item("level", h.level)?;
+                d.set_item("text", &h.text)?;
+                d.set_item("index", h.index)?;
+                d.set_item("anchor", &h.anchor)?;
+                headings_list.append(d)?;
+            }
+            headings_list.into()
+        }
+        "chunks" => {
+            let chunks_list = PyList::empty_bound(py);
+            for c in &result.chunks {
+                let d = PyDict::new_bound(py);
+                d.set_item("text", &c.text)?;
+                d.set_item("heading_path", &c.heading_path)?;
+                d.set_item("start", c.start)?;
+                d.set_item("end", c.end)?;
+                chunks_list.append(d)?;
+            }
+            chunks_list.into()
+        }
+        "stats" => {
+            let stats = PyDict::new_bound(py);
+            stats.set_item("word_count", result.stats.word_count)?;
+            stats.set_item("char_count", result.stats.char_count)?;
+            stats.set_item("link_count", result.stats.link_count)?;
+            stats.set_item("image_count", result.stats.image_count)?;
+            stats.set_item("table_count", result.stats.table_count)?;
+            stats.set_item("code_block_count", result.stats.code_block_count)?;
+            let by_level = PyDict::new_bound(py);
+            for (i, n) in result.stats.headings_by_level.iter().enumerate() {
+                by_level.set_item(format!("h{}", i + 1), n)?;
+            }
+            stats.set_item("headings_by_level", by_level)?;
+            stats.set_item("reading_time_minutes", result.stats.reading_time_minutes)?;
+            stats.set_item("content_ratio", result.stats.content_ratio)?;
+            stats.into()
+        }
+        "diagnostics" => {
+            let diag = PyDict::new_bound(py);
+            let d = &result.diagnostics;
+            diag.set_item("base_url_usable", d.base_url_usable)?;
+            diag.set_item("main_selector", &d.main_selector)?;
+            diag.set_item("main_node", &d.main_node)?;
+            diag.set_item("fallback", d.fallback_reason.is_some())?;
+            diag.set_item("stage", d.stage.as_str())?;
+            diag.set_item(
+                "fallback_reason",
+                d.fallback_reason.map(FallbackReason::as_str),
+            )?;
+            match &d.site_rule {
+                Some(rule) => {
+                    let rule_dict = PyDict::new_bound(py);
+                    rule_dict.set_item("host", &rule.host)?;
+                    rule_dict.set_item("required", &rule.required)?;
+                    diag.set_item("site_rule", rule_dict)?;
+                }
+                None => diag.set_item("site_rule", py.None())?,
+            }
+            diag.set_item("clutter_nodes", d.clutter_nodes)?;
+            diag.set_item("hidden_nodes", d.hidden_nodes)?;
+            diag.set_item("overlay_nodes", d.overlay_nodes)?;
+            diag.into()
+        }
+        "truncated" => result.truncated.to_object(py),
+        "contacts" => {
+            let contacts_list = PyList::empty_bound(py);
+            for c in &result.contacts {
+                let d = PyDict::new_bound(py);
+                d.set_item("kind", &c.kind)?;
+                d.set_item("value", &c.value)?;
+                d.set_item("text", &c.text)?;
+                contacts_list.append(d)?;
+            }
+            contacts_list.into()
+        }
+        "urls" => PyList::new_bound(py, &result.urls).into(),
+        _ => unreachable!("unknown result field {name}"),
+    };
+    Ok(value)
+}
+
+/// A conversion result with attribute access. Each field becomes a Python
+/// object only when first read, and the same object is returned after.
+#[pyclass(frozen, module = "grub_md")]
+struct MarkdownResult {
+    /// Converted with `Variants::Deferred`.
+    result: PipelineResult,
+    citation_style: CitationStyle,
+    /// One slot per entry of `RESULT_FIELDS`.
+    fields: Vec<GILOnceCell<PyObject>>,
+    /// Shared by `references_markdown` and `markdown_references`.
+    references: GILOnceCell<String>,
+}
+
+impl MarkdownResult {
+    fn new(result: PipelineResult, citation_style: CitationStyle) -> Self {
+        MarkdownResult {
+            result,
+            citation_style,
+            fields: RESULT_FIELDS.iter().map(|_| GILOnceCell::new()).collect(),
+            references: GILOnceCell::new(),
+        }
+    }
+
+    /// `result_field`, building the variants the conversion deferred.
+    fn field(&self, py: Python<'_>, name: &str) -> PyResult<PyObject> {
+        let result = &self.result;
+        let references = || {
+            self.references.get_or_init(py, || {
+                references_markdown(&result.raw_markdown, &result.links, self.citation_style)
+            })
+        };
+        let value = match name {
+            // Language detection may have built it already
+            "markdown_plain" if result.markdown_plain.is_empty() => {
+                strip_links(&result.raw_markdown).to_object(py)
+            }
+            "references_markdown" => references().to_object(py),
+            "markdown_references" => {
+                markdown_references(&result.markdown_with_citations, references()).to_object(py)
+            }
+            _ => return result_field(py, result, name),
+        };
+        Ok(value)
+    }
+}
+
+#[pymethods]
+impl MarkdownResult {
+    fn __getattr__(&self, py: Python<'_>, name: &str) -> PyResult<PyObject> {
+        let Some(i) = RESULT_FIELDS.iter().position(|f| *f == name) else {
+            return Err(pyo3::exceptions::PyAttributeError::new_err(format!(
+                "'MarkdownResult' object has no attribute '{name}'"
+            )));
+        };
+        let value = self.fields[i].get_or_try_init(py, || self.field(py, RESULT_FIELDS[i]))?;
+        Ok(value.clone_ref(py))
+    }
+
+    fn __dir__(&self) -> Vec<&'static str> {
+        let mut names = RESULT_FIELDS.to_vec();
+        names.push("to_dict");
+        names
+    }
+
+    /// The same dict `generate_markdown` returns.
+    fn to_dict(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let dict = PyDict::new_bound(py);
+        for name in RESULT_FIELDS {
+            dict.set_item(name, self.__getattr__(py, name)?)?;
+        }
+        Ok(dict.into())
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "MarkdownResult(title={:?}, words={})",
+            self.result.title, self.result.stats.word_count
+        )
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Logging bridge
+// ---------------------------------------------------------------------------
+
+#[cfg(feature = "tracing")]
+mod logging {
+    use std::fmt::{self, Write};
+    use std::sync::atomic::{AtomicU8, Ordering};
+
+    use pyo3::prelude::*;
+    use pyo3::sync::GILOnceCell;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id};
+    use tracing::{Event, Level, Metadata, Subscriber};
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+
+    /// Most verbose level forwarded, as ranked by `rank`; 0 forwards nothing.
+    static MAX_RANK: AtomicU8 = AtomicU8::new(0);
+    static INSTALLED: GILOnceCell<()> = GILOnceCell::new();
+    static LOGGER: GILOnceCell<PyObject> = GILOnceCell::new();
+
+    fn rank(level: &Level) -> u8 {
+        match *level {
+            Level::ERROR => 1,
+            Level::WARN => 2,
+            Level::INFO => 3,
+            Level::DEBUG => 4,
+            Level::TRACE => 5,
+        }
+    }
+
+    /// The `logging` level number for a tracing level (TRACE maps below
+    /// DEBUG, to 5).
+    fn python_level(level: &Level) -> u8 {
+        match *level {
+            Level::ERROR => 40,
+            Level::WARN => 30,
+            Level::INFO => 20,
+            Level::DEBUG => 10,
+            Level::TRACE => 5,
+        }
+    }
+
+    /// Fields as ` name=value`, with the `message` field kept apart.
+    #[derive(Default)]
+    struct Fields {
+        message: String,
+        rest: String,
+    }
+
+    impl Visit for Fields {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == "message" {
+                self.message.push_str(value);
+            } else {
+                let _ = write!(self.rest, " {}={}", field.name(), value);
+            }
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() == "message" {
+                let _ = write!(self.message, "{:?}", value);
+            } else {
+                let _ = write!(self.rest, " {}={:?}", field.name(), value);
+            }
+        }
+    }
+
+    /// A span's fields, formatted once when it opens.
+    struct SpanFields(String);
+
+    /// Forwards events to the `grub_md` logger as
+    /// `convert{html_bytes=…}: walk: walked content root markdown_bytes=…`.
+    struct PythonLogging;
+
+    impl<S> Layer<S> for PythonLogging
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn enabled(&self, metadata: &Metadata<'_>, _: Context<'_, S>) -> bool {
+            rank(metadata.level()) <= MAX_RANK.load(Ordering::Relaxed)
+        }
+
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            if let Some(span) = ctx.span(id) {
+                span.extensions_mut().insert(SpanFields(fields.rest));
+            }
+        }
+
+        fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+            let mut line = String::new();
+            for span in ctx
+                .event_scope(event)
+                .into_iter()
+                .flat_map(|s| s.from_root())
+            {
+                line.push_str(span.name());
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    if !fields.is_empty() {
+                        let _ = write!(line, "{{{}}}", fields.trim_start());
+                    }
+                }
+                line.push_str(": ");
+            }
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            line.push_str(&fields.message);
+            line.push_str(&fields.rest);
+            let level = python_level(event.metadata().level());
+            // Conversions run with the GIL released, so taking it here
+            // can't deadlock; a failing handler mustn't fail the conversion
+            Python::with_gil(|py| {
+                let logger = LOGGER.get_or_try_init(py, || -> PyResult<PyObject> {
+                    let logging = py.import_bound("logging")?;
+                    Ok(logging.call_method1("getLogger", ("grub_md",))?.unbind())
+                });
+                if let Ok(logger) = logger {
+                    let _ = logger.call_method1(py, "log", (level, line));
+                }
+            });
+        }
+    }
+
+    /// Forward the converter's spans and events at `level` and above to the
+    /// Python `logging` logger named `grub_md`. `level` is one of `trace`,
+    /// `debug`, `info`, `warning`, `error` or `off` (any case); call again to
+    /// change it. The logger's own level and handlers still apply.
+    #[pyfunction]
+    #[pyo3(signature = (level="debug"))]
+    pub(super) fn enable_logging(py: Python<'_>, level: &str) -> PyResult<()> {
+        let rank = match level.to_ascii_lowercase().as_str() {
+            "off" => 0,
+            "warning" => rank(&Level::WARN),
+            name => rank(&name.parse::<Level>().map_err(|_| {
+                pyo3::exceptions::PyValueError::new_err(format!(
+                    "invalid logging level {:?} (expected one of: trace, debug, info, \
+                     warning, error, off)",
+                    level
+                ))
+            })?),
+        };
+        INSTALLED.get_or_try_init(py, || {
+            let subscriber = tracing_subscriber::registry().with(PythonLogging);
+            tracing::subscriber::set_global_default(subscriber)
+                .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+        })?;
+        MAX_RANK.store(rank, Ordering::Relaxed);
+        tracing::callsite::rebuild_interest_cache();
+        Ok(())
+    }
+}
+
+#[pymodule]
+fn grub_md(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(generate_markdown, m)?)?;
+    m.add_function(wrap_pyfunction!(generate_markdown_result, m)?)?;
+    m.add_function(wrap_pyfunction!(generate_markdown_json, m)?)?;
+    m.add_function(wrap_pyfunction!(generate_markdown_batch, m)?)?;
+    m.add_class::<MarkdownResult>()?;
+    m.add_class::<ConversionOptions>()?;
+    m.add_class::<MarkdownConverter>()?;
+    m.add("GrubMdError", m.py().get_type_bound::<GrubMdError>())?;
+    m.add_function(wrap_pyfunction!(generate_markdown_fragment, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_selection, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_links, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_tables, m)?)?;
+    m.add_function(wrap_pyfunction!(extract_text, m)?)?;
+    #[cfg(feature = "tracing")]
+    m.add_function(wrap_pyfunction!(logging::enable_logging, m)?)?;
+    Ok(())
+}
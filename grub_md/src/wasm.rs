@@ -0,0 +1,30 @@
+// ---------------------------------------------------------------------------
+// wasm-bindgen exports
+// ---------------------------------------------------------------------------
+//
+// For Workers and browsers: options come in as JSON named like the Python
+// keyword arguments, and the result goes out as a plain JS object keyed
+// like the Python dict. Built with the `wasm` feature.
+
+use wasm_bindgen::prelude::*;
+
+use crate::{try_run_pipeline, Options};
+
+/// Convert an HTML document. `options_json` is a JSON object of settings
+/// (see `Options::from_json`), or empty for the defaults; a bad setting,
+/// or a bad `base_url` with `"strict": true`, throws an `Error`.
+#[wasm_bindgen]
+pub fn generate_markdown(
+    html: &str,
+    base_url: &str,
+    options_json: &str,
+) -> Result<JsValue, JsError> {
+    let opts = if options_json.trim().is_empty() {
+        Options::default()
+    } else {
+        Options::from_json(options_json).map_err(|e| JsError::new(&e))?
+    };
+    let result = try_run_pipeline(html, base_url, &opts)?;
+    let json = serde_json::to_string(&result)?;
+    js_sys::JSON::parse(&json).map_err(|_| JsError::new("result is not valid JSON"))
+}
@@ -0,0 +1,109 @@
+// Drives the `grub-md` binary over the fixture files.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+fn grub_md(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_grub-md"))
+        .args(args)
+        .output()
+        .expect("run grub-md")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[test]
+fn raw_variant() {
+    let path = fixture("article.html");
+    let output = grub_md(&["--variant", "raw", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    let md = stdout(&output);
+    assert!(md.starts_with("# Tide Pools\n"));
+    assert!(md.contains("[rocky hollows](/rocks)"));
+    assert!(!md.contains("Copyright"));
+}
+
+#[test]
+fn base_url_and_citations() {
+    let path = fixture("article.html");
+    let output = grub_md(&[
+        "--base-url",
+        "https://coast.example/pools/",
+        "--variant",
+        "citations",
+        path.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let md = stdout(&output);
+    assert!(md.contains("rocky hollows[1]"));
+    assert!(md.contains("[1]: https://coast.example/rocks"));
+    assert!(md.contains("[2]: https://example.org/guide"));
+}
+
+#[test]
+fn plain_variant_from_stdin() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_grub-md"))
+        .args(["--variant", "plain"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("run grub-md");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(br#"<p>Read <a href="/docs">the docs</a>.</p>"#)
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "Read the docs.\n");
+}
+
+#[test]
+fn json_output() {
+    let path = fixture("article.html");
+    let output = grub_md(&["--json", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    let result: serde_json::Value = serde_json::from_str(&stdout(&output)).unwrap();
+    assert_eq!(result["title"], "Tide Pools | Coastal Notes");
+    assert_eq!(
+        result["metadata"]["description"],
+        "What lives in a tide pool."
+    );
+    assert_eq!(result["links"][1]["url"], "https://example.org/guide");
+    assert_eq!(result["stats"]["headings_by_level"]["h2"], 1);
+}
+
+#[test]
+fn empty_output_fails() {
+    let path = fixture("empty.html");
+    let output = grub_md(&[path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn help() {
+    let output = grub_md(&["--variant", "raw", "--help"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).starts_with("usage: grub-md "));
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn usage_errors() {
+    assert_eq!(grub_md(&["--variant", "fancy"]).status.code(), Some(2));
+    assert_eq!(grub_md(&["no/such/file.html"]).status.code(), Some(2));
+    let output = grub_md(&["--bogus"]);
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("grub-md: unknown option --bogus\nusage: grub-md "));
+}
@@ -0,0 +1,68 @@
+// The wasm-bindgen exports, run under Node by wasm-bindgen-test-runner:
+//
+//     cargo test --target wasm32-unknown-unknown --no-default-features \
+//         --features wasm --test wasm
+#![cfg(all(target_arch = "wasm32", feature = "wasm"))]
+
+use js_sys::Reflect;
+use wasm_bindgen::JsValue;
+use wasm_bindgen_test::wasm_bindgen_test;
+
+use grub_md::wasm::generate_markdown;
+
+fn field(value: &JsValue, key: &str) -> JsValue {
+    Reflect::get(value, &JsValue::from_str(key)).unwrap()
+}
+
+const ARTICLE: &str = r#"<html><head><title>Tide Pools</title></head><body>
+<nav><a href="/">Home</a></nav>
+<article><h1>Tide Pools</h1>
+<p>Pools form in <a href="/rocks">rocky hollows</a> at low tide.</p>
+<ul><li>Anemones</li><li>Sea stars</li></ul></article>
+</body></html>"#;
+
+#[wasm_bindgen_test]
+fn converts_with_defaults() {
+    let result = generate_markdown(ARTICLE, "https://coast.example/", "").unwrap();
+    assert_eq!(field(&result, "title").as_string().unwrap(), "Tide Pools");
+    let raw = field(&result, "raw_markdown").as_string().unwrap();
+    assert!(raw.starts_with("# Tide Pools"));
+    assert!(raw.contains("[rocky hollows](https://coast.example/rocks)"));
+    let links = field(&result, "links");
+    let first = Reflect::get_u32(&links, 0).unwrap();
+    assert_eq!(
+        field(&first, "url").as_string().unwrap(),
+        "https://coast.example/rocks"
+    );
+}
+
+#[wasm_bindgen_test]
+fn applies_options() {
+    let options = r#"{"bullet_marker": "*", "citation_style": "footnote"}"#;
+    let result = generate_markdown(ARTICLE, "", options).unwrap();
+    let raw = field(&result, "raw_markdown").as_string().unwrap();
+    assert!(raw.contains("* Anemones"));
+    let cited = field(&result, "markdown_with_citations")
+        .as_string()
+        .unwrap();
+    assert!(cited.contains("[^1]"));
+}
+
+#[wasm_bindgen_test]
+fn rejects_bad_options() {
+    assert!(generate_markdown(ARTICLE, "", r#"{"citation_style": "nope"}"#).is_err());
+    assert!(generate_markdown(ARTICLE, "", r#"{"no_such_option": true}"#).is_err());
+    assert!(generate_markdown(ARTICLE, "", "[1, 2]").is_err());
+    assert!(generate_markdown(ARTICLE, "not a url", r#"{"strict": true}"#).is_err());
+}
+
+#[wasm_bindgen_test]
+fn truncates_deep_nesting() {
+    let html = format!(
+        "<article><p>Shallow text.</p>{}Buried{}</article>",
+        "<table><tr><td>".repeat(150),
+        "</td></tr></table>".repeat(150)
+    );
+    let result = generate_markdown(&html, "", "").unwrap();
+    assert_eq!(field(&result, "truncated").as_bool(), Some(true));
+}
@@ -0,0 +1,35 @@
+"""app.markdown takes the grub_md fast path end to end."""
+
+import dataclasses
+import logging
+
+import pytest
+
+grub_md = pytest.importorskip("grub_md")
+pytest.importorskip("bs4")
+
+from app import markdown  # noqa: E402
+
+HTML = """<html><head><title>Tide Pools</title></head><body>
+<nav><a href="/">Home</a></nav>
+<article><h1>Tide Pools</h1>
+<p>Pools form in <a href="/rocks">rocky hollows</a> at low tide.</p></article>
+</body></html>"""
+
+
+def test_result_keys_are_dataclass_fields():
+    fields = {f.name for f in dataclasses.fields(markdown.MarkdownResult)}
+    assert set(grub_md.generate_markdown(HTML)) <= fields
+
+
+def test_generator_uses_rust(caplog):
+    assert markdown._HAS_RUST
+    with caplog.at_level(logging.WARNING, logger=markdown.__name__):
+        result = markdown.MarkdownGenerator().generate_markdown(
+            HTML, "https://coast.example/"
+        )
+    assert not caplog.records
+    # Only the Rust converter reports diagnostics and the truncation flag
+    assert result.diagnostics["main_selector"] == "article"
+    assert result.truncated is False
+    assert "[rocky hollows](https://coast.example/rocks)" in result.raw_markdown
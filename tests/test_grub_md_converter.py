@@ -0,0 +1,42 @@
+"""MarkdownConverter: one converter, many documents."""
+
+import pytest
+
+grub_md = pytest.importorskip("grub_md")
+
+
+def page(i):
+    return f"""<html><body>
+<div class="sidebar"><p>Sidebar {i} with enough words to look like content.</p></div>
+<div class="post-body"><p>Post {i}</p></div>
+</body></html>"""
+
+
+def test_converts_many_documents_with_custom_selectors():
+    converter = grub_md.MarkdownConverter(content_selectors=[".post-body"])
+    for i in range(5):
+        result = converter.convert(page(i), f"https://example.com/{i}")
+        assert result["raw_markdown"] == f"Post {i}"
+        assert result["diagnostics"]["main_selector"] == ".post-body"
+
+
+def test_matches_generate_markdown():
+    converter = grub_md.MarkdownConverter(content_selectors=[".post-body"])
+    assert converter.convert(page(1)) == grub_md.generate_markdown(
+        page(1), content_selectors=[".post-body"]
+    )
+
+
+def test_convert_batch():
+    converter = grub_md.MarkdownConverter(
+        grub_md.ConversionOptions(content_selectors=[".post-body"])
+    )
+    results = converter.convert_batch([(page(i), "") for i in range(50)])
+    assert [r["raw_markdown"] for r in results] == [f"Post {i}" for i in range(50)]
+
+
+def test_bad_configuration_raises_on_construction():
+    with pytest.raises(ValueError):
+        grub_md.MarkdownConverter(content_selectors=["div["])
+    with pytest.raises(ValueError):
+        grub_md.MarkdownConverter(boilerplate_patterns=["(unclosed"])
@@ -0,0 +1,45 @@
+"""GrubMdError and base_url checking."""
+
+import warnings
+
+import pytest
+
+grub_md = pytest.importorskip("grub_md")
+
+HTML = '<p>Read <a href="/docs">the docs</a>.</p>'
+
+
+def test_strict_rejects_garbage_base_url():
+    with pytest.raises(grub_md.GrubMdError) as excinfo:
+        grub_md.generate_markdown(HTML, "not a url", strict=True)
+    assert str(excinfo.value) == 'invalid base_url "not a url": relative URL without a base'
+    assert isinstance(excinfo.value, Exception)
+
+
+def test_strict_applies_to_every_entry_point():
+    options = grub_md.ConversionOptions(strict=True)
+    with pytest.raises(grub_md.GrubMdError):
+        grub_md.extract_links(HTML, "not a url", options=options)
+    with pytest.raises(grub_md.GrubMdError):
+        grub_md.MarkdownConverter(options).convert(HTML, "not a url")
+    batch = grub_md.generate_markdown_batch(
+        [(HTML, "not a url"), (HTML, "https://example.com/")], options=options
+    )
+    assert batch[0]["error"].startswith("invalid base_url")
+    assert batch[1]["links"][0]["url"] == "https://example.com/docs"
+
+
+def test_lenient_warns_and_converts_without_base():
+    with pytest.warns(UserWarning, match="invalid base_url"):
+        result = grub_md.generate_markdown(HTML, "not a url")
+    assert result["links"][0]["url"] == "/docs"
+    assert result["diagnostics"]["base_url_usable"] is False
+
+
+def test_good_and_empty_base_urls_are_quiet():
+    with warnings.catch_warnings():
+        warnings.simplefilter("error")
+        result = grub_md.generate_markdown(HTML, "https://example.com/", strict=True)
+        assert result["diagnostics"]["base_url_usable"] is True
+        result = grub_md.generate_markdown(HTML, strict=True)
+        assert result["diagnostics"]["base_url_usable"] is False
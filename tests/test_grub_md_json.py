@@ -0,0 +1,32 @@
+"""generate_markdown_json: the dict result as one JSON string."""
+
+import json
+
+import pytest
+
+grub_md = pytest.importorskip("grub_md")
+
+HTML = """<html><head><title>Tides</title>
+<meta property="og:image" content="/a.png">
+<meta property="og:image" content="/b.png">
+<link rel="alternate" type="application/atom+xml" href="/feed.atom">
+</head><body><article><h1>Tides</h1>
+<p>See <a href="/rocks">rocks</a> and <a href="mailto:tide@coast.example">write</a>.</p>
+<img src="/pool.jpg" alt="A pool"></article></body></html>"""
+
+
+def test_matches_dict():
+    as_json = grub_md.generate_markdown_json(HTML, "https://coast.example/")
+    assert json.loads(as_json) == grub_md.generate_markdown(HTML, "https://coast.example/")
+
+
+def test_key_order_matches_dict():
+    as_json = json.loads(grub_md.generate_markdown_json(HTML))
+    assert list(as_json) == list(grub_md.generate_markdown(HTML))
+
+
+def test_accepts_options():
+    options = grub_md.ConversionOptions(citation_style="footnote")
+    as_json = json.loads(grub_md.generate_markdown_json(HTML, "", options))
+    assert "[^1]" in as_json["markdown_with_citations"]
+    assert json.loads(grub_md.generate_markdown_json(HTML, citation_style="footnote")) == as_json
@@ -0,0 +1,34 @@
+"""Depth, node and output limits cut pathological pages short."""
+
+import pytest
+
+grub_md = pytest.importorskip("grub_md")
+
+
+def test_deep_nesting_is_truncated():
+    depth = 50_000
+    html = (
+        "<article><p>Shallow text.</p>"
+        + "<span>" * depth
+        + "Buried"
+        + "</span>" * depth
+        + "</article>"
+    )
+    result = grub_md.generate_markdown(html)
+    assert result["truncated"] is True
+    assert result["raw_markdown"] == "Shallow text."
+
+
+def test_output_cap():
+    html = "<article><p>" + "word " * 100_000 + "</p></article>"
+    result = grub_md.generate_markdown(html, max_output_bytes=1000)
+    assert result["truncated"] is True
+    assert len(result["raw_markdown"]) <= 1000
+    assert grub_md.generate_markdown(html)["truncated"] is False
+
+
+def test_node_cap():
+    html = "<article>" + "<p>para</p>" * 1000 + "</article>"
+    result = grub_md.generate_markdown_result(html, max_nodes=10)
+    assert result.truncated
+    assert result.raw_markdown.count("para") < 10
@@ -0,0 +1,55 @@
+"""enable_logging forwards the converter's tracing events to `logging`."""
+
+import logging
+from pathlib import Path
+
+import pytest
+
+grub_md = pytest.importorskip("grub_md")
+
+if not hasattr(grub_md, "enable_logging"):
+    pytest.skip("grub_md built without the tracing feature", allow_module_level=True)
+
+FIXTURE = Path(__file__).parent.parent / "grub_md" / "tests" / "fixtures" / "article.html"
+
+
+class Records(logging.Handler):
+    def __init__(self):
+        super().__init__()
+        self.records = []
+
+    def emit(self, record):
+        self.records.append(record)
+
+
+@pytest.fixture
+def records():
+    logger = logging.getLogger("grub_md")
+    handler = Records()
+    logger.addHandler(handler)
+    logger.setLevel(logging.DEBUG)
+    yield handler.records
+    grub_md.enable_logging("off")
+    logger.removeHandler(handler)
+
+
+def test_stages_are_logged(records):
+    grub_md.enable_logging("debug")
+    grub_md.generate_markdown(FIXTURE.read_text(), "https://coast.example/")
+    messages = [r.getMessage() for r in records]
+    assert all(r.levelno == logging.DEBUG for r in records)
+    assert messages[0].startswith("convert{html_bytes=")
+    assert any(": skip_set: built skip set skipped_subtrees=" in m for m in messages)
+    assert any(": main_content: found content root selector=article" in m for m in messages)
+    assert any(": walk: walked content root markdown_bytes=" in m for m in messages)
+    assert messages[-1].endswith("stage=content_root")
+
+
+def test_level_filters_and_off(records):
+    grub_md.enable_logging("info")
+    grub_md.generate_markdown(FIXTURE.read_text())
+    grub_md.enable_logging("off")
+    grub_md.generate_markdown(FIXTURE.read_text())
+    assert records == []
+    with pytest.raises(ValueError):
+        grub_md.enable_logging("loud")
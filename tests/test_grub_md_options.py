@@ -0,0 +1,87 @@
+"""ConversionOptions and keyword settings reach the Rust converter."""
+
+import pytest
+
+grub_md = pytest.importorskip("grub_md")
+
+HTML = """<html><body><article>
+<p>Read <a href="https://example.com/a">the docs</a>.</p>
+<ul><li>one</li><li>two</li></ul>
+</article></body></html>"""
+
+
+def convert(*args, **kwargs):
+    return grub_md.generate_markdown(HTML, "https://example.com/", *args, **kwargs)
+
+
+def test_defaults_match_no_options():
+    assert convert(grub_md.ConversionOptions()) == convert()
+
+
+def test_options_change_output():
+    options = grub_md.ConversionOptions(bullet_marker="*", citation_style="footnote")
+    result = convert(options)
+    assert "* one" in result["raw_markdown"]
+    assert "[^1]" in result["markdown_with_citations"]
+
+
+def test_options_and_kwargs_agree():
+    options = grub_md.ConversionOptions(bullet_marker="+")
+    assert convert(options=options) == convert(bullet_marker="+")
+
+
+def test_overrides_win():
+    options = grub_md.ConversionOptions(bullet_marker="*", citation_style="footnote")
+    result = convert(options, bullet_marker="+")
+    assert "+ one" in result["raw_markdown"]
+    # Settings that weren't overridden still apply
+    assert "[^1]" in result["markdown_with_citations"]
+    # The options object itself is unchanged
+    assert options.to_dict() == {"bullet_marker": "*", "citation_style": "footnote"}
+
+
+def test_replace():
+    options = grub_md.ConversionOptions(bullet_marker="*").replace(bullet_marker="+")
+    assert options.to_dict() == {"bullet_marker": "+"}
+    assert "ConversionOptions(bullet_marker='+')" == repr(options)
+
+
+def test_other_entry_points_accept_options():
+    options = grub_md.ConversionOptions(bullet_marker="*")
+    assert "* one" in grub_md.extract_text(HTML, options=options)
+    assert "* one" in grub_md.generate_markdown_result(HTML, "", options).raw_markdown
+    batch = grub_md.generate_markdown_batch([(HTML, "")], options=options)
+    assert "* one" in batch[0]["raw_markdown"]
+
+
+def test_invalid_settings_raise():
+    with pytest.raises(ValueError):
+        grub_md.ConversionOptions(citation_style="nope")
+    with pytest.raises(TypeError):
+        grub_md.ConversionOptions(no_such_setting=True)
+    with pytest.raises(TypeError):
+        convert(options={"bullet_marker": "*"})
+    with pytest.raises(ValueError):
+        grub_md.ConversionOptions(min_md_len=-1)
+    with pytest.raises(ValueError):
+        grub_md.ConversionOptions(content_selectors="article")
+
+
+def test_kwarg_value_types():
+    options = grub_md.ConversionOptions(
+        site_rules=[("example.com", "/post/")],
+        content_selectors=("article",),
+        min_ratio=0,
+        lazy_image_attrs=None,
+    )
+    assert "the docs" in convert(options)["raw_markdown"]
+
+
+def test_svg_placeholders_setting():
+    html = '<p><svg aria-label="Logo"></svg> Brand</p>'
+    assert "![Logo]()" not in grub_md.generate_markdown(html)["raw_markdown"]
+    options = grub_md.ConversionOptions(svg_placeholders=True)
+    result = grub_md.generate_markdown(html, options=options)
+    assert "![Logo]()" in result["raw_markdown"]
+    result = grub_md.generate_markdown(html, svg_placeholders=True)
+    assert "![Logo]()" in result["raw_markdown"]
@@ -0,0 +1,51 @@
+"""MarkdownResult: attribute access to a conversion result."""
+
+import pytest
+
+grub_md = pytest.importorskip("grub_md")
+
+HTML = """<html><head><title>Doc</title></head><body><article>
+<h1>Heading</h1>
+<p>Text with <a href="https://example.com/a">a link</a> and an
+<img src="/pic.png" alt="picture">.</p>
+</article></body></html>"""
+
+
+def test_fields_match_dict():
+    result = grub_md.generate_markdown_result(HTML, "https://example.com/")
+    expected = grub_md.generate_markdown(HTML, "https://example.com/")
+    assert result.to_dict() == expected
+    assert result.raw_markdown == expected["raw_markdown"]
+    assert result.markdown_plain == expected["markdown_plain"]
+    assert result.links[0]["url"] == "https://example.com/a"
+    assert result.stats["link_count"] == 1
+
+
+def test_fields_are_built_once():
+    result = grub_md.generate_markdown_result(HTML, "https://example.com/")
+    assert result.markdown_with_citations is result.markdown_with_citations
+    assert result.links is result.links
+
+
+def test_unknown_field_raises():
+    result = grub_md.generate_markdown_result(HTML)
+    with pytest.raises(AttributeError):
+        result.raw_markdwon
+    assert "references_markdown" in dir(result)
+
+
+def test_accepts_options():
+    result = grub_md.generate_markdown_result(HTML, citation_style="footnote")
+    assert "[^1]" in result.markdown_with_citations
+
+
+def test_deferred_variants_match_dict():
+    # With a declared language, markdown_plain isn't needed for detection
+    html = HTML.replace("<html>", '<html lang="en">')
+    for style in ["bracket", "footnote"]:
+        args = (html, "https://example.com/")
+        result = grub_md.generate_markdown_result(*args, citation_style=style)
+        expected = grub_md.generate_markdown(*args, citation_style=style)
+        assert result.references_markdown == expected["references_markdown"] != ""
+        assert result.markdown_references == expected["markdown_references"]
+        assert result.markdown_plain == expected["markdown_plain"] != ""
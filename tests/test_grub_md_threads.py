@@ -0,0 +1,68 @@
+"""grub_md releases the GIL while converting.
+
+Conversions started from several Python threads must overlap instead of
+running one after another.
+"""
+
+import os
+import time
+from concurrent.futures import ThreadPoolExecutor
+
+import pytest
+
+grub_md = pytest.importorskip("grub_md")
+
+THREADS = 4
+
+PARAGRAPH = '<p>Paragraph {0} with <a href="/p{0}">a link</a> and some text to walk.</p>'
+HTML = (
+    "<html><body><article>"
+    + "".join(PARAGRAPH.format(i) for i in range(3000))
+    + "</article></body></html>"
+)
+
+
+def _convert(_):
+    return grub_md.generate_markdown(HTML, "https://example.com/")["raw_markdown"]
+
+
+@pytest.mark.slow
+@pytest.mark.skipif((os.cpu_count() or 1) < 2, reason="needs more than one CPU")
+def test_threaded_conversions_overlap():
+    _convert(0)  # warm up
+
+    start = time.perf_counter()
+    serial = [_convert(i) for i in range(THREADS)]
+    serial_time = time.perf_counter() - start
+
+    with ThreadPoolExecutor(max_workers=THREADS) as pool:
+        start = time.perf_counter()
+        threaded = list(pool.map(_convert, range(THREADS)))
+        threaded_time = time.perf_counter() - start
+
+    assert threaded == serial
+    # With the GIL held the threaded run would take as long as the serial one
+    assert threaded_time < serial_time * 0.75, (serial_time, threaded_time)
+
+
+@pytest.mark.slow
+def test_python_runs_during_conversion():
+    """Another Python thread keeps running while a conversion is in progress,
+    which it can't while the GIL is held (even on a single CPU)."""
+    ticks = []
+    with ThreadPoolExecutor(max_workers=1) as pool:
+        start = time.perf_counter()
+        future = pool.submit(_convert, 0)
+        while not future.done():
+            ticks.append(time.perf_counter())
+        future.result()
+        elapsed = time.perf_counter() - start
+
+    gaps = [b - a for a, b in zip([start] + ticks, ticks)]
+    assert max(gaps) < elapsed / 2, (max(gaps), elapsed)
+
+
+def test_batch_keeps_order():
+    docs = [(f"<p>Document {i}</p>", "") for i in range(200)]
+    results = grub_md.generate_markdown_batch(docs)
+    assert [r["raw_markdown"] for r in results] == [f"Document {i}" for i in range(200)]